@@ -0,0 +1,392 @@
+//! Two-slot firmware updates with automatic rollback.
+//!
+//! `AbUpdate` keeps two firmware images in files of a `FileSystem`, one of
+//! which is active. A new image is written to the inactive slot through the
+//! usual `create`/`get_writer` path, verified, and then made active for a
+//! trial of a limited number of boots:
+//!
+//! ```ignore
+//! let fd = ab.begin_update(&mut fs)?;
+//! fs.get_writer(&fd)?.write_all(&image)?;
+//! fs.close(fd)?;
+//! ab.activate(&mut fs, |image| check_signature(image))?;
+//! // in the bootloader, on every boot
+//! let slot = ab.boot(&mut fs)?;
+//! // in the new firmware, once it is known to work
+//! ab.confirm(&mut fs)?;
+//! ```
+//!
+//! If the new image is not confirmed within `max_boots` boots, `boot` flips
+//! back to the previous slot. Slot state is kept in two small files written
+//! in turn, each record with a sequence number and a CRC, so that a write
+//! torn by power loss leaves the previous state in effect.
+
+use crc::crc32;
+use fs::{Fd, FileSystem};
+use io::{self, Read, ReadWriteSeek, Seek, Write};
+use path::Path;
+
+// sequence number (4), active slot (1), trial (1), boots left (1),
+// reserved (1), crc of the rest (4)
+const RECORD_SIZE: usize = 12;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Slot::A => 0,
+            Slot::B => 1,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SlotState {
+    seq: u32,
+    active: Slot,
+    trial: bool,
+    boots_left: u8,
+}
+
+impl SlotState {
+    /// Slot that should be booted.
+    pub fn active(&self) -> Slot {
+        self.active
+    }
+
+    /// Whether the active slot was activated and not yet confirmed.
+    pub fn is_trial(&self) -> bool {
+        self.trial
+    }
+
+    /// Boots the active slot gets before `boot` rolls back, while on trial.
+    pub fn boots_left(&self) -> u8 {
+        self.boots_left
+    }
+}
+
+/// State of a device that never had an update: slot A, confirmed.
+const INITIAL_STATE: SlotState = SlotState {
+    seq: 0,
+    active: Slot::A,
+    trial: false,
+    boots_left: 0,
+};
+
+pub struct AbUpdate {
+    slots: [Path; 2],
+    records: [Path; 2],
+    max_boots: u8,
+}
+
+impl AbUpdate {
+    /// Keeps images in files `slot_a` and `slot_b`, and slot state in files
+    /// `state_a` and `state_b`. A new image gets `max_boots` boots to be
+    /// confirmed.
+    pub fn new(slot_a: Path, slot_b: Path, state_a: Path, state_b: Path, max_boots: u8) -> Self {
+        AbUpdate {
+            slots: [slot_a, slot_b],
+            records: [state_a, state_b],
+            max_boots,
+        }
+    }
+
+    pub fn slot_path(&self, slot: Slot) -> Path {
+        self.slots[slot.index()]
+    }
+
+    /// Reads the newest valid slot state.
+    pub fn state<T: Read + Seek>(&self, fs: &mut FileSystem<T>) -> io::Result<SlotState> {
+        let mut state = INITIAL_STATE;
+        for &path in &self.records {
+            if let Some(record) = read_record(fs, path)? {
+                if record.seq >= state.seq {
+                    state = record;
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    /// Opens the inactive slot for writing a new image, truncating it.
+    pub fn begin_update<T: ReadWriteSeek>(&self, fs: &mut FileSystem<T>) -> io::Result<Fd> {
+        let state = self.state(fs)?;
+        if state.trial {
+            // the inactive slot is what a failed trial falls back to
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot update: active slot not confirmed",
+            ));
+        }
+        fs.create(self.slot_path(state.active.other()))
+    }
+
+    /// Checks the image in the inactive slot with `verify` and, if it
+    /// passes, makes that slot active on trial. The image must have been
+    /// closed after writing.
+    pub fn activate<T, F>(&self, fs: &mut FileSystem<T>, verify: F) -> io::Result<()>
+    where
+        T: ReadWriteSeek,
+        F: FnOnce(&mut dyn Read) -> bool,
+    {
+        let state = self.state(fs)?;
+        if state.trial {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot activate: active slot not confirmed",
+            ));
+        }
+        let target = state.active.other();
+        let fd = fs.open_read(self.slot_path(target))?;
+        let valid = fs.get_reader(&fd).map(|mut reader| verify(&mut reader));
+        fs.close(fd)?;
+        if !valid? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "image failed verification",
+            ));
+        }
+        // the image must be in storage before the state points at it
+        fs.flush_to_storage()?;
+        self.write_state(
+            fs,
+            SlotState {
+                seq: state.seq,
+                active: target,
+                trial: true,
+                boots_left: self.max_boots,
+            },
+        )
+    }
+
+    /// Counts a boot of a slot on trial, rolling back to the other slot once
+    /// it runs out of boots. Returns the slot to boot.
+    pub fn boot<T: ReadWriteSeek>(&self, fs: &mut FileSystem<T>) -> io::Result<Slot> {
+        let state = self.state(fs)?;
+        if !state.trial {
+            return Ok(state.active);
+        }
+        let next = if state.boots_left == 0 {
+            SlotState {
+                active: state.active.other(),
+                trial: false,
+                boots_left: 0,
+                ..state
+            }
+        } else {
+            SlotState {
+                boots_left: state.boots_left - 1,
+                ..state
+            }
+        };
+        self.write_state(fs, next)?;
+        Ok(next.active)
+    }
+
+    /// Marks the active slot as good, ending its trial.
+    pub fn confirm<T: ReadWriteSeek>(&self, fs: &mut FileSystem<T>) -> io::Result<()> {
+        let state = self.state(fs)?;
+        if !state.trial {
+            return Ok(());
+        }
+        self.write_state(
+            fs,
+            SlotState {
+                trial: false,
+                boots_left: 0,
+                ..state
+            },
+        )
+    }
+
+    /// Writes `state` with the next sequence number, over the older of the
+    /// two records.
+    fn write_state<T: ReadWriteSeek>(
+        &self,
+        fs: &mut FileSystem<T>,
+        state: SlotState,
+    ) -> io::Result<()> {
+        let seq = match state.seq.checked_add(1) {
+            Some(seq) => seq,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "slot state sequence exhausted",
+                ))
+            }
+        };
+        let record = encode_record(&SlotState { seq, ..state });
+        let fd = fs.create(self.records[(seq % 2) as usize])?;
+        let result = fs
+            .get_writer(&fd)
+            .and_then(|mut writer| writer.write_all(&record))
+            .and_then(|()| fs.sync(&fd));
+        fs.close(fd)?;
+        result
+    }
+}
+
+fn read_record<T: Read + Seek>(
+    fs: &mut FileSystem<T>,
+    path: Path,
+) -> io::Result<Option<SlotState>> {
+    let fd = match fs.open_read(path) {
+        Ok(fd) => fd,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut buf = [0; RECORD_SIZE];
+    let result = fs
+        .get_reader(&fd)
+        .and_then(|mut reader| io::read_full(&mut reader, &mut buf));
+    fs.close_read(fd)?;
+    // a torn write can leave the record short
+    Ok(if result? { decode_record(&buf) } else { None })
+}
+
+fn encode_record(state: &SlotState) -> [u8; RECORD_SIZE] {
+    let mut buf = [0; RECORD_SIZE];
+    buf[..4].copy_from_slice(&state.seq.to_le_bytes());
+    buf[4] = state.active.index() as u8;
+    buf[5] = state.trial as u8;
+    buf[6] = state.boots_left;
+    let crc = crc32(&buf[..8]);
+    buf[8..].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+fn decode_record(buf: &[u8; RECORD_SIZE]) -> Option<SlotState> {
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&buf[8..]);
+    if crc32(&buf[..8]) != u32::from_le_bytes(crc) {
+        return None;
+    }
+    let mut seq = [0; 4];
+    seq.copy_from_slice(&buf[..4]);
+    let active = match buf[4] {
+        0 => Slot::A,
+        1 => Slot::B,
+        _ => return None,
+    };
+    Some(SlotState {
+        seq: u32::from_le_bytes(seq),
+        active,
+        trial: buf[5] != 0,
+        boots_left: buf[6],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::Cursor;
+    use std::vec::Vec;
+    use FS_SIZE;
+
+    fn path(name: &[u8]) -> Path {
+        Path::from_ascii_str(name).unwrap()
+    }
+
+    fn ab() -> AbUpdate {
+        AbUpdate::new(
+            path(b"fw.a"),
+            path(b"fw.b"),
+            path(b"fw.state0"),
+            path(b"fw.state1"),
+            2,
+        )
+    }
+
+    fn install<T: ReadWriteSeek>(fs: &mut FileSystem<T>, image: &[u8]) -> io::Result<()> {
+        let ab = ab();
+        let fd = ab.begin_update(fs)?;
+        fs.get_writer(&fd)?.write_all(image)?;
+        fs.close(fd)?;
+        let expected = image.to_vec();
+        ab.activate(fs, |reader| {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).is_ok() && data == expected
+        })
+    }
+
+    #[test]
+    fn update_and_confirm() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let ab = ab();
+        assert_eq!(ab.boot(&mut fs).unwrap(), Slot::A);
+        install(&mut fs, b"firmware v2").expect("failed to install");
+        let state = ab.state(&mut fs).unwrap();
+        assert_eq!(state.active(), Slot::B);
+        assert!(state.is_trial());
+        assert!(
+            ab.begin_update(&mut fs).is_err(),
+            "trial slot must be confirmed first"
+        );
+        assert_eq!(ab.boot(&mut fs).unwrap(), Slot::B);
+        ab.confirm(&mut fs).expect("failed to confirm");
+        for _ in 0..5 {
+            assert_eq!(ab.boot(&mut fs).unwrap(), Slot::B);
+        }
+        install(&mut fs, b"firmware v3").expect("failed to install");
+        assert_eq!(ab.boot(&mut fs).unwrap(), Slot::A);
+    }
+
+    #[test]
+    fn unconfirmed_update_rolls_back() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let ab = ab();
+        install(&mut fs, b"broken firmware").expect("failed to install");
+        assert_eq!(ab.boot(&mut fs).unwrap(), Slot::B);
+        assert_eq!(ab.boot(&mut fs).unwrap(), Slot::B);
+        assert_eq!(ab.boot(&mut fs).unwrap(), Slot::A);
+        assert!(!ab.state(&mut fs).unwrap().is_trial());
+    }
+
+    #[test]
+    fn bad_image_is_not_activated() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let ab = ab();
+        let fd = ab.begin_update(&mut fs).unwrap();
+        fs.close(fd).unwrap();
+        let err = ab.activate(&mut fs, |_| false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(ab.state(&mut fs).unwrap(), INITIAL_STATE);
+    }
+
+    #[test]
+    fn torn_state_write_keeps_previous_state() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let ab = ab();
+        install(&mut fs, b"firmware v2").expect("failed to install");
+        ab.confirm(&mut fs).expect("failed to confirm");
+        let confirmed = ab.state(&mut fs).unwrap();
+        // the next record goes over the older one, leave it half written
+        let next = encode_record(&SlotState {
+            seq: confirmed.seq + 1,
+            active: Slot::A,
+            ..confirmed
+        });
+        let fd = fs
+            .create(ab.records[(confirmed.seq as usize + 1) % 2])
+            .unwrap();
+        fs.get_writer(&fd).unwrap().write_all(&next[..7]).unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(ab.state(&mut fs).unwrap(), confirmed);
+    }
+}
@@ -0,0 +1,319 @@
+//! ChaCha20-Poly1305 AEAD as specified in RFC 8439.
+//!
+//! Written for clarity and a small footprint rather than speed. Tags are
+//! compared in constant time, the rest makes no side channel promises beyond
+//! those of the algorithms themselves.
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+fn le32(buf: &[u8]) -> u32 {
+    u32::from(buf[0]) | u32::from(buf[1]) << 8 | u32::from(buf[2]) << 16 | u32::from(buf[3]) << 24
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; NONCE_SIZE]) -> [u8; 64] {
+    let mut init = [0u32; 16];
+    init[0] = 0x6170_7865;
+    init[1] = 0x3320_646e;
+    init[2] = 0x7962_2d32;
+    init[3] = 0x6b20_6574;
+    for i in 0..8 {
+        init[4 + i] = le32(&key[(4 * i)..]);
+    }
+    init[12] = counter;
+    for i in 0..3 {
+        init[13 + i] = le32(&nonce[(4 * i)..]);
+    }
+    let mut s = init;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut out = [0; 64];
+    for i in 0..16 {
+        let word = s[i].wrapping_add(init[i]);
+        for j in 0..4 {
+            out[4 * i + j] = (word >> (8 * j)) as u8;
+        }
+    }
+    out
+}
+
+/// XORs `buf` with the keystream, starting at block `counter`.
+fn chacha20_xor(key: &[u8; KEY_SIZE], mut counter: u32, nonce: &[u8; NONCE_SIZE], buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(64) {
+        let block = chacha20_block(key, counter, nonce);
+        for (byte, k) in chunk.iter_mut().zip(block.iter()) {
+            *byte ^= k;
+        }
+        counter = counter.wrapping_add(1);
+    }
+}
+
+/// Poly1305 with 26 bit limbs, after poly1305-donna.
+struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+}
+
+impl Poly1305 {
+    fn new(key: &[u8; 32]) -> Self {
+        Poly1305 {
+            r: [
+                le32(&key[0..]) & 0x03ff_ffff,
+                (le32(&key[3..]) >> 2) & 0x03ff_ff03,
+                (le32(&key[6..]) >> 4) & 0x03ff_c0ff,
+                (le32(&key[9..]) >> 6) & 0x03f0_3fff,
+                (le32(&key[12..]) >> 8) & 0x000f_ffff,
+            ],
+            h: [0; 5],
+            pad: [
+                le32(&key[16..]),
+                le32(&key[20..]),
+                le32(&key[24..]),
+                le32(&key[28..]),
+            ],
+        }
+    }
+
+    fn block(&mut self, m: &[u8; 16], hibit: u32) {
+        let r = &self.r;
+        let h = &mut self.h;
+        let s = [r[1] * 5, r[2] * 5, r[3] * 5, r[4] * 5];
+        h[0] += le32(&m[0..]) & 0x03ff_ffff;
+        h[1] += (le32(&m[3..]) >> 2) & 0x03ff_ffff;
+        h[2] += (le32(&m[6..]) >> 4) & 0x03ff_ffff;
+        h[3] += (le32(&m[9..]) >> 6) & 0x03ff_ffff;
+        h[4] += (le32(&m[12..]) >> 8) | hibit;
+
+        let m = |a: u32, b: u32| u64::from(a) * u64::from(b);
+        let d0 = m(h[0], r[0]) + m(h[1], s[3]) + m(h[2], s[2]) + m(h[3], s[1]) + m(h[4], s[0]);
+        let mut d1 = m(h[0], r[1]) + m(h[1], r[0]) + m(h[2], s[3]) + m(h[3], s[2]) + m(h[4], s[1]);
+        let mut d2 = m(h[0], r[2]) + m(h[1], r[1]) + m(h[2], r[0]) + m(h[3], s[3]) + m(h[4], s[2]);
+        let mut d3 = m(h[0], r[3]) + m(h[1], r[2]) + m(h[2], r[1]) + m(h[3], r[0]) + m(h[4], s[3]);
+        let mut d4 = m(h[0], r[4]) + m(h[1], r[3]) + m(h[2], r[2]) + m(h[3], r[1]) + m(h[4], r[0]);
+
+        h[0] = d0 as u32 & 0x03ff_ffff;
+        d1 += d0 >> 26;
+        h[1] = d1 as u32 & 0x03ff_ffff;
+        d2 += d1 >> 26;
+        h[2] = d2 as u32 & 0x03ff_ffff;
+        d3 += d2 >> 26;
+        h[3] = d3 as u32 & 0x03ff_ffff;
+        d4 += d3 >> 26;
+        h[4] = d4 as u32 & 0x03ff_ffff;
+        h[0] += (d4 >> 26) as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(16) {
+            let mut m = [0; 16];
+            m[..chunk.len()].copy_from_slice(chunk);
+            if chunk.len() == 16 {
+                self.block(&m, 1 << 24);
+            } else {
+                m[chunk.len()] = 1;
+                self.block(&m, 0);
+            }
+        }
+    }
+
+    /// Like `update`, but zero pads `data` to a whole block as the AEAD
+    /// construction requires.
+    fn update_padded(&mut self, data: &[u8]) {
+        for chunk in data.chunks(16) {
+            let mut m = [0; 16];
+            m[..chunk.len()].copy_from_slice(chunk);
+            self.block(&m, 1 << 24);
+        }
+    }
+
+    fn finish(self) -> [u8; TAG_SIZE] {
+        let mut h = self.h;
+        for i in 1..5 {
+            h[i] += h[i - 1] >> 26;
+            h[i - 1] &= 0x03ff_ffff;
+        }
+        h[0] += (h[4] >> 26) * 5;
+        h[4] &= 0x03ff_ffff;
+        h[1] += h[0] >> 26;
+        h[0] &= 0x03ff_ffff;
+
+        // compute h - p and keep it if it did not underflow
+        let mut g = [0u32; 5];
+        let mut carry = 5;
+        for i in 0..4 {
+            g[i] = h[i] + carry;
+            carry = g[i] >> 26;
+            g[i] &= 0x03ff_ffff;
+        }
+        g[4] = h[4].wrapping_add(carry).wrapping_sub(1 << 26);
+        let keep_g = (g[4] >> 31).wrapping_sub(1);
+        for i in 0..5 {
+            h[i] = (h[i] & !keep_g) | (g[i] & keep_g);
+        }
+
+        let words = [
+            h[0] | h[1] << 26,
+            h[1] >> 6 | h[2] << 20,
+            h[2] >> 12 | h[3] << 14,
+            h[3] >> 18 | h[4] << 8,
+        ];
+        let mut tag = [0; TAG_SIZE];
+        let mut f = 0u64;
+        for i in 0..4 {
+            f = u64::from(words[i]) + u64::from(self.pad[i]) + (f >> 32);
+            for j in 0..4 {
+                tag[4 * i + j] = (f >> (8 * j)) as u8;
+            }
+        }
+        tag
+    }
+}
+
+fn compute_tag(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> [u8; TAG_SIZE] {
+    let block = chacha20_block(key, 0, nonce);
+    let mut poly_key = [0; 32];
+    poly_key.copy_from_slice(&block[..32]);
+    let mut poly = Poly1305::new(&poly_key);
+    poly.update_padded(aad);
+    poly.update_padded(ciphertext);
+    let mut lengths = [0; 16];
+    for i in 0..8 {
+        lengths[i] = ((aad.len() as u64) >> (8 * i)) as u8;
+        lengths[8 + i] = ((ciphertext.len() as u64) >> (8 * i)) as u8;
+    }
+    poly.update(&lengths);
+    poly.finish()
+}
+
+/// Encrypts `buf` in place and returns the authentication tag.
+pub fn seal(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    buf: &mut [u8],
+) -> [u8; TAG_SIZE] {
+    chacha20_xor(key, 1, nonce, buf);
+    compute_tag(key, nonce, aad, buf)
+}
+
+/// Checks `tag` and decrypts `buf` in place. Returns `false` and leaves `buf`
+/// untouched if the data was not sealed with this key, nonce and `aad`.
+pub fn open(
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    buf: &mut [u8],
+    tag: &[u8; TAG_SIZE],
+) -> bool {
+    let expected = compute_tag(key, nonce, aad, buf);
+    let diff = expected
+        .iter()
+        .zip(tag.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    if diff != 0 {
+        return false;
+    }
+    chacha20_xor(key, 1, nonce, buf);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> std::vec::Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn poly1305_rfc8439() {
+        let mut key = [0; 32];
+        key.copy_from_slice(&unhex(
+            "85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b",
+        ));
+        let mut poly = Poly1305::new(&key);
+        poly.update(b"Cryptographic Forum Research Group");
+        assert_eq!(
+            &poly.finish()[..],
+            &unhex("a8061dc1305136c6c22b8baf0c0127a9")[..]
+        );
+    }
+
+    #[test]
+    fn seal_rfc8439() {
+        let mut key = [0; KEY_SIZE];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = 0x80 + i as u8;
+        }
+        let nonce = [7, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+            only one tip for the future, sunscreen would be it.";
+        let mut buf = plaintext.to_vec();
+        let tag = seal(&key, &nonce, &aad, &mut buf);
+        assert_eq!(
+            buf,
+            unhex(
+                "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+                 3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+                 92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+                 3ff4def08e4b7a9de576d26586cec64b6116"
+            )
+        );
+        assert_eq!(&tag[..], &unhex("1ae10b594f09e26a7e902ecbd0600691")[..]);
+
+        assert!(open(&key, &nonce, &aad, &mut buf, &tag));
+        assert_eq!(buf, plaintext);
+    }
+
+    #[test]
+    fn open_rejects_tampering() {
+        let key = [1; KEY_SIZE];
+        let nonce = [2; NONCE_SIZE];
+        let mut buf = *b"attack at dawn";
+        let tag = seal(&key, &nonce, b"", &mut buf);
+        let sealed = buf;
+
+        buf[3] ^= 1;
+        assert!(!open(&key, &nonce, b"", &mut buf, &tag));
+        assert_eq!(buf[3], sealed[3] ^ 1, "should not decrypt on failure");
+
+        buf = sealed;
+        assert!(!open(&key, &nonce, b"aad", &mut buf, &tag));
+        assert!(!open(&[3; KEY_SIZE], &nonce, b"", &mut buf, &tag));
+        assert!(!open(&key, &[4; NONCE_SIZE], b"", &mut buf, &tag));
+        assert!(open(&key, &nonce, b"", &mut buf, &tag));
+        assert_eq!(&buf, b"attack at dawn");
+    }
+}
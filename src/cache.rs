@@ -0,0 +1,526 @@
+//! Block cache between `FileSystem` and slow storage.
+//!
+//! `BlockCache` keeps recently used blocks of the storage in a caller provided
+//! buffer, so that small reads and writes, such as parsing a file a byte at a
+//! time, turn into a few whole-block transfers:
+//!
+//! ```ignore
+//! let mut buf = [0; 4 * 512];
+//! let mut cache = BlockCache::new(&mut flash, &mut buf, 512, CachePolicy::WriteBack);
+//! let mut fs = FileSystem::new(&mut cache)?;
+//! ```
+//!
+//! With `CachePolicy::WriteBack`, written blocks only reach the storage when
+//! they are evicted or on `flush`, which `FileSystem::flush_to_storage`,
+//! `sync` and `unmount` all do. Anything not flushed is lost on power loss, or
+//! when the cache is dropped: dropping cannot write, as the storage may only
+//! implement `Read`. `into_inner` writes back dirty blocks before handing the
+//! storage back, and returns the error if that fails.
+//!
+//! Storage that only implements `Read + Seek`, such as an image in ROM, can be
+//! cached too, for reading.
+
+use core::cmp;
+use io::{self, Read, Seek, SeekFrom, Write};
+
+/// Upper bound on the number of blocks a cache holds, whatever the size of its
+/// buffer.
+pub const MAX_CACHE_BLOCKS: usize = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Writes go to the storage immediately, the cache only serves reads.
+    WriteThrough,
+    /// Writes stay in the cache until the block is evicted or flushed.
+    WriteBack,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    valid: bool,
+    dirty: bool,
+    block: u64,
+    /// Bytes of the block that exist in storage, less than the block size
+    /// only for the last block.
+    len: usize,
+    /// Value of `clock` when last used, for LRU eviction.
+    used: u32,
+}
+
+const EMPTY_ENTRY: Entry = Entry {
+    valid: false,
+    dirty: false,
+    block: 0,
+    len: 0,
+    used: 0,
+};
+
+pub struct BlockCache<'b, T> {
+    inner: T,
+    buf: &'b mut [u8],
+    block_size: usize,
+    entries: [Entry; MAX_CACHE_BLOCKS],
+    count: usize,
+    policy: CachePolicy,
+    pos: u64,
+    clock: u32,
+}
+
+impl<'b, T> BlockCache<'b, T> {
+    /// Caches blocks of `block_size` bytes, as many as fit in `buf`.
+    ///
+    /// # Panics
+    ///
+    /// If `buf` cannot hold a single block.
+    pub fn new(inner: T, buf: &'b mut [u8], block_size: usize, policy: CachePolicy) -> Self {
+        assert!(
+            block_size > 0 && buf.len() >= block_size,
+            "cache buffer too small"
+        );
+        let count = cmp::min(buf.len() / block_size, MAX_CACHE_BLOCKS);
+        BlockCache {
+            inner,
+            buf,
+            block_size,
+            entries: [EMPTY_ENTRY; MAX_CACHE_BLOCKS],
+            count,
+            policy,
+            pos: 0,
+            clock: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Gives access to the storage behind the cache. Anything written to it
+    /// directly is not seen through the cache until `invalidate` is called.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn find(&self, block: u64) -> Option<usize> {
+        self.entries[..self.count]
+            .iter()
+            .position(|e| e.valid && e.block == block)
+    }
+
+    fn touch(&mut self, index: usize) {
+        self.clock = self.clock.wrapping_add(1);
+        self.entries[index].used = self.clock;
+    }
+
+    /// Picks the entry to reuse: an empty one, or else the least recently
+    /// used one, leaving out dirty ones if `clean` is set.
+    fn victim(&self, clean: bool) -> Option<usize> {
+        let entries = &self.entries[..self.count];
+        if let Some(index) = entries.iter().position(|e| !e.valid) {
+            return Some(index);
+        }
+        let clock = self.clock;
+        entries
+            .iter()
+            .enumerate()
+            .filter(|&(_, e)| !(clean && e.dirty))
+            .max_by_key(|&(_, e)| clock.wrapping_sub(e.used))
+            .map(|(index, _)| index)
+    }
+
+    fn slot(&mut self, index: usize) -> &mut [u8] {
+        let start = index * self.block_size;
+        &mut self.buf[start..(start + self.block_size)]
+    }
+
+    /// Copies `data` into the cached block at `offset`.
+    fn update(&mut self, index: usize, offset: usize, data: &[u8]) {
+        let len = self.entries[index].len;
+        let slot = self.slot(index);
+        if offset > len {
+            // a write past the end leaves a gap, as a file would
+            for byte in &mut slot[len..offset] {
+                *byte = 0;
+            }
+        }
+        slot[offset..(offset + data.len())].copy_from_slice(data);
+        let entry = &mut self.entries[index];
+        entry.len = cmp::max(entry.len, offset + data.len());
+    }
+}
+
+impl<'b, T: Read + Seek> BlockCache<'b, T> {
+    /// Reads `block` into entry `index`.
+    fn fill(&mut self, index: usize, block: u64) -> io::Result<()> {
+        self.entries[index].valid = false;
+        self.inner
+            .seek(SeekFrom::Start(block * self.block_size as u64))?;
+        let mut len = 0;
+        let block_size = self.block_size;
+        while len < block_size {
+            let start = index * block_size;
+            let read = self
+                .inner
+                .read(&mut self.buf[(start + len)..(start + block_size)])?;
+            if read == 0 {
+                break;
+            }
+            len += read;
+        }
+        self.entries[index] = Entry {
+            valid: true,
+            dirty: false,
+            block,
+            len,
+            used: 0,
+        };
+        Ok(())
+    }
+
+    /// Returns the entry holding `block`, loading it if a clean entry can
+    /// make room for it. Blocks that are not cached are up to date in storage,
+    /// so a read can always go there instead.
+    fn load_clean(&mut self, block: u64) -> io::Result<Option<usize>> {
+        let index = match self.find(block) {
+            Some(index) => index,
+            None => match self.victim(true) {
+                Some(index) => {
+                    self.fill(index, block)?;
+                    index
+                }
+                None => return Ok(None),
+            },
+        };
+        self.touch(index);
+        Ok(Some(index))
+    }
+}
+
+impl<'b, T: Read + Write + Seek> BlockCache<'b, T> {
+    /// Writes back dirty blocks and hands the storage back.
+    pub fn into_inner(mut self) -> io::Result<T> {
+        self.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Drops every cached block, writing back dirty ones first.
+    pub fn invalidate(&mut self) -> io::Result<()> {
+        self.write_back_all()?;
+        self.entries = [EMPTY_ENTRY; MAX_CACHE_BLOCKS];
+        Ok(())
+    }
+
+    fn write_back(&mut self, index: usize) -> io::Result<()> {
+        let entry = self.entries[index];
+        if entry.valid && entry.dirty {
+            let start = index * self.block_size;
+            self.inner
+                .seek(SeekFrom::Start(entry.block * self.block_size as u64))?;
+            self.inner
+                .write_all(&self.buf[start..(start + entry.len)])?;
+            self.entries[index].dirty = false;
+        }
+        Ok(())
+    }
+
+    fn write_back_all(&mut self) -> io::Result<()> {
+        for index in 0..self.count {
+            self.write_back(index)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the entry holding `block`, loading it if needed.
+    fn load(&mut self, block: u64) -> io::Result<usize> {
+        let index = match self.find(block) {
+            Some(index) => index,
+            None => {
+                let index = self.victim(false).expect("cache has no entries");
+                self.write_back(index)?;
+                self.fill(index, block)?;
+                index
+            }
+        };
+        self.touch(index);
+        Ok(index)
+    }
+}
+
+impl<'b, T: Read + Seek> Read for BlockCache<'b, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            let block = self.pos / self.block_size as u64;
+            let offset = (self.pos % self.block_size as u64) as usize;
+            let len = match self.load_clean(block)? {
+                Some(index) => {
+                    let available = self.entries[index].len.saturating_sub(offset);
+                    let len = cmp::min(buf.len() - read, available);
+                    let data = &self.slot(index)[offset..(offset + len)];
+                    buf[read..(read + len)].copy_from_slice(data);
+                    len
+                }
+                None => {
+                    let len = cmp::min(buf.len() - read, self.block_size - offset);
+                    self.inner.seek(SeekFrom::Start(self.pos))?;
+                    self.inner.read(&mut buf[read..(read + len)])?
+                }
+            };
+            if len == 0 {
+                break;
+            }
+            read += len;
+            self.pos += len as u64;
+        }
+        Ok(read)
+    }
+}
+
+impl<'b, T: Read + Write + Seek> Write for BlockCache<'b, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let block = self.pos / self.block_size as u64;
+            let offset = (self.pos % self.block_size as u64) as usize;
+            let len = cmp::min(buf.len() - written, self.block_size - offset);
+            let data = &buf[written..(written + len)];
+            if self.policy == CachePolicy::WriteThrough {
+                self.inner.seek(SeekFrom::Start(self.pos))?;
+                self.inner.write_all(data)?;
+                if let Some(index) = self.find(block) {
+                    self.update(index, offset, data);
+                }
+            } else {
+                let index = self.load(block)?;
+                self.update(index, offset, data);
+                self.entries[index].dirty = true;
+            }
+            written += len;
+            self.pos += len as u64;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_back_all()?;
+        self.inner.flush()
+    }
+}
+
+impl<'b, T: Seek> Seek for BlockCache<'b, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => {
+                // dirty blocks may extend past the end of the storage
+                let block_size = self.block_size as u64;
+                let cached_end = self.entries[..self.count]
+                    .iter()
+                    .filter(|e| e.valid)
+                    .map(|e| e.block * block_size + e.len as u64)
+                    .max()
+                    .unwrap_or(0);
+                (cmp::max(self.inner.seek(SeekFrom::End(0))?, cached_end), n)
+            }
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        let new_pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use testing::fault::{Fault, FaultyStorage};
+    use {FileSystem, Path, FS_SIZE};
+
+    fn storage(len: usize) -> FaultyStorage {
+        FaultyStorage::new((0..len).map(|i| i as u8).collect())
+    }
+
+    fn read_at<T: Read + Seek>(cache: &mut BlockCache<T>, pos: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        cache.seek(SeekFrom::Start(pos)).unwrap();
+        let read = cache.read(&mut buf).unwrap();
+        buf.truncate(read);
+        buf
+    }
+
+    #[test]
+    fn byte_at_a_time_file_reads() {
+        let mut inner = FaultyStorage::new(vec![0; FS_SIZE as usize]);
+        let mut buf = [0; 4 * 256];
+        let mut cache = BlockCache::new(&mut inner, &mut buf, 256, CachePolicy::WriteThrough);
+        let path = Path::from_ascii_str(b"data").unwrap();
+        let data = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        {
+            let mut fs = FileSystem::new(&mut cache).unwrap();
+            let fd = fs.create(path).unwrap();
+            fs.get_writer(&fd).unwrap().write_all(&data).unwrap();
+            fs.close(fd).unwrap();
+            fs.unmount().unwrap();
+        }
+        let reads_before = cache.get_ref().reads();
+        {
+            let mut fs = FileSystem::mount_read_only(&mut cache).unwrap();
+            let fd = fs.open_read(path).unwrap();
+            let mut read = Vec::new();
+            let mut byte = [0];
+            while fs.get_reader(&fd).unwrap().read(&mut byte).unwrap() == 1 {
+                read.push(byte[0]);
+            }
+            assert_eq!(read, data);
+        }
+        // headers of all slots and four blocks of data, not one per byte
+        assert!(cache.get_ref().reads() - reads_before < 30);
+    }
+
+    #[test]
+    fn write_back_defers_writes() {
+        let mut inner = storage(64);
+        let mut buf = [0; 32];
+        {
+            let mut cache = BlockCache::new(&mut inner, &mut buf, 16, CachePolicy::WriteBack);
+            cache.seek(SeekFrom::Start(20)).unwrap();
+            for i in 0..8 {
+                cache.write_all(&[100 + i]).unwrap();
+            }
+            assert_eq!(cache.get_ref().bytes_written(), 0);
+            assert_eq!(read_at(&mut cache, 18, 4), [18, 19, 100, 101]);
+            cache.flush().unwrap();
+            assert_eq!(cache.get_ref().bytes_written(), 16);
+            cache.flush().unwrap();
+            assert_eq!(cache.get_ref().bytes_written(), 16);
+        }
+        let mut image = inner.into_image();
+        let mut data = [0; 10];
+        image.seek(SeekFrom::Start(19)).unwrap();
+        image.read_exact(&mut data).unwrap();
+        assert_eq!(data, [19, 100, 101, 102, 103, 104, 105, 106, 107, 28]);
+    }
+
+    #[test]
+    fn write_back_on_eviction_and_into_inner() {
+        let mut inner = storage(64);
+        let mut buf = [0; 8];
+        {
+            let mut cache = BlockCache::new(&mut inner, &mut buf, 8, CachePolicy::WriteBack);
+            cache.write_all(&[0xAA; 4]).unwrap();
+            cache.seek(SeekFrom::Start(40)).unwrap();
+            cache.write_all(&[0xBB; 4]).unwrap();
+            // the first block had to make room for the second
+            assert_eq!(cache.get_ref().bytes_written(), 8);
+            cache.into_inner().unwrap();
+        }
+        let image = inner.into_image().into_inner();
+        assert_eq!(&image[..5], [0xAA, 0xAA, 0xAA, 0xAA, 4]);
+        assert_eq!(&image[39..45], [39, 0xBB, 0xBB, 0xBB, 0xBB, 44]);
+    }
+
+    #[test]
+    fn into_inner_returns_write_back_error() {
+        let mut inner = storage(64).with_fault(Fault::WriteError { write: 0 });
+        let mut buf = [0; 16];
+        let mut cache = BlockCache::new(&mut inner, &mut buf, 8, CachePolicy::WriteBack);
+        cache.write_all(&[0xAA; 4]).unwrap();
+        let err = cache.into_inner().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(&inner.into_image().into_inner()[..4], [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn reads_past_dirty_blocks() {
+        let mut inner = storage(64);
+        let mut buf = [0; 8];
+        let mut cache = BlockCache::new(&mut inner, &mut buf, 8, CachePolicy::WriteBack);
+        cache.write_all(&[0xAA; 2]).unwrap();
+        // no clean block to make room, so the read goes to storage
+        assert_eq!(read_at(&mut cache, 14, 4), [14, 15, 16, 17]);
+        assert_eq!(read_at(&mut cache, 0, 3), [0xAA, 0xAA, 2]);
+        assert_eq!(cache.get_ref().bytes_written(), 0);
+    }
+
+    #[test]
+    fn read_only_storage() {
+        let mut image = io::Cursor::new(vec![0; FS_SIZE as usize]);
+        let path = Path::from_ascii_str(b"data").unwrap();
+        {
+            let mut fs = FileSystem::new(&mut image).unwrap();
+            let fd = fs.create(path).unwrap();
+            fs.get_writer(&fd).unwrap().write_all(b"in rom").unwrap();
+            fs.close(fd).unwrap();
+            fs.unmount().unwrap();
+        }
+        let mut rom = io::Cursor::new(&image.get_ref()[..]);
+        let mut buf = [0; 4 * 256];
+        let mut cache = BlockCache::new(&mut rom, &mut buf, 256, CachePolicy::WriteThrough);
+        let mut fs = FileSystem::mount_read_only(&mut cache).unwrap();
+        let fd = fs.open_read(path).unwrap();
+        let mut data = Vec::new();
+        fs.get_reader(&fd).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"in rom");
+    }
+
+    #[test]
+    fn least_recently_used_block_is_evicted() {
+        let mut inner = storage(64);
+        let mut buf = [0; 8];
+        let mut cache = BlockCache::new(&mut inner, &mut buf, 4, CachePolicy::WriteBack);
+        read_at(&mut cache, 0, 1);
+        read_at(&mut cache, 4, 1);
+        read_at(&mut cache, 0, 1);
+        let reads = cache.get_ref().reads();
+        // evicts block 1, not block 0
+        read_at(&mut cache, 8, 1);
+        assert_eq!(read_at(&mut cache, 0, 4), [0, 1, 2, 3]);
+        assert_eq!(cache.get_ref().reads(), reads + 1);
+        assert_eq!(read_at(&mut cache, 4, 4), [4, 5, 6, 7]);
+        assert_eq!(cache.get_ref().reads(), reads + 2);
+    }
+
+    #[test]
+    fn write_through_updates_cached_blocks() {
+        let mut inner = storage(64);
+        let mut buf = [0; 64];
+        let mut cache = BlockCache::new(&mut inner, &mut buf, 16, CachePolicy::WriteThrough);
+        assert_eq!(read_at(&mut cache, 0, 4), [0, 1, 2, 3]);
+        cache.seek(SeekFrom::Start(1)).unwrap();
+        cache.write_all(&[9, 9]).unwrap();
+        assert_eq!(cache.get_ref().bytes_written(), 2);
+        assert_eq!(read_at(&mut cache, 0, 4), [0, 9, 9, 3]);
+    }
+
+    #[test]
+    fn short_last_block() {
+        let mut inner = storage(10);
+        let mut buf = [0; 16];
+        let mut cache = BlockCache::new(&mut inner, &mut buf, 4, CachePolicy::WriteBack);
+        assert_eq!(read_at(&mut cache, 6, 10), [6, 7, 8, 9]);
+        assert_eq!(read_at(&mut cache, 10, 1), []);
+        assert_eq!(cache.seek(SeekFrom::End(0)).unwrap(), 10);
+        let mut all = Vec::new();
+        cache.seek(SeekFrom::Start(0)).unwrap();
+        let mut byte = [0];
+        while cache.read(&mut byte).unwrap() == 1 {
+            all.push(byte[0]);
+        }
+        assert_eq!(all, (0..10).collect::<Vec<_>>());
+    }
+}
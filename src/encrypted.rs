@@ -0,0 +1,413 @@
+//! Encryption at rest for any `ReadWriteSeek` storage.
+//!
+//! `EncryptedStorage` splits the storage into sectors of `SECTOR_SIZE` bytes,
+//! each sealed with ChaCha20-Poly1305 under a caller supplied key. It sits
+//! between `FileSystem` and the real storage, so file names, lengths and
+//! contents are all encrypted:
+//!
+//! ```ignore
+//! let mut storage = EncryptedStorage::new(flash, &key);
+//! let mut fs = FileSystem::new(&mut storage)?;
+//! ```
+//!
+//! Each sector is stored twice, and a write replaces the older copy, so a
+//! write cut short by power loss leaves the previous contents of the sector
+//! readable. Reading a sector neither copy of which authenticates, because it
+//! was modified, moved to another position or written with a different key,
+//! fails with `ErrorKind::InvalidData`. Sectors that were never written read
+//! as zeros, so freshly erased storage holds an empty filesystem.
+//! Authentication does not protect against rolling a sector back to an older
+//! version, including the never written one, and a newer copy that fails to
+//! authenticate is taken for a torn write.
+
+use aead;
+use core::cmp;
+use io::{self, Read, Seek, SeekFrom, Write};
+
+pub use aead::KEY_SIZE;
+
+/// Bytes of plaintext in one sector.
+pub const SECTOR_SIZE: usize = 256;
+const COUNTER_SIZE: usize = 8;
+/// Bytes of storage taken by one copy of a sector: write counter, ciphertext
+/// and tag.
+const COPY_SIZE: usize = COUNTER_SIZE + SECTOR_SIZE + aead::TAG_SIZE;
+/// Bytes of storage taken by one sector, kept in two copies.
+pub const STORED_SECTOR_SIZE: usize = 2 * COPY_SIZE;
+
+/// Size of the storage needed to hold `len` bytes of plaintext.
+pub fn stored_size(len: u64) -> u64 {
+    let sectors = len.div_ceil(SECTOR_SIZE as u64);
+    sectors * STORED_SECTOR_SIZE as u64
+}
+
+pub struct EncryptedStorage<T> {
+    inner: T,
+    key: [u8; KEY_SIZE],
+    pos: u64,
+    /// Sector held in `buf`, which always matches what is in storage.
+    cached: Option<u64>,
+    /// Highest write counter found in either copy of the cached sector.
+    counter: u64,
+    /// Copy of the cached sector `buf` was read from.
+    copy: usize,
+    buf: [u8; SECTOR_SIZE],
+}
+
+impl<T> EncryptedStorage<T> {
+    pub fn new(inner: T, key: &[u8; KEY_SIZE]) -> Self {
+        EncryptedStorage {
+            inner,
+            key: *key,
+            pos: 0,
+            cached: None,
+            counter: 0,
+            copy: 0,
+            buf: [0; SECTOR_SIZE],
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+// The nonce is made of the sector index and a counter bumped on every write of
+// the sector, so it is never reused under the same key, and sectors cannot be
+// swapped. The counter is stored big endian ahead of the data: a write torn in
+// the middle of it leaves a value at least as large as the old one, and no
+// ciphertext for the new value. The next write counts on from the highest
+// value in either copy, authenticated or not, so no nonce ends up used for two
+// plaintexts.
+fn nonce(sector: u64, counter: u64) -> [u8; aead::NONCE_SIZE] {
+    let mut nonce = [0; aead::NONCE_SIZE];
+    nonce[..4].copy_from_slice(&(sector as u32).to_le_bytes());
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn stored_position(sector: u64, copy: usize) -> u64 {
+    sector * STORED_SECTOR_SIZE as u64 + (copy * COPY_SIZE) as u64
+}
+
+fn stored_counter(raw: &[u8]) -> u64 {
+    let mut counter = [0; COUNTER_SIZE];
+    counter.copy_from_slice(&raw[..COUNTER_SIZE]);
+    u64::from_be_bytes(counter)
+}
+
+impl<T: Read + Seek> EncryptedStorage<T> {
+    /// Makes `sector` the cached one, reading the newest copy that
+    /// authenticates. Returns `false` if it lies past the end of the storage.
+    fn load(&mut self, sector: u64) -> io::Result<bool> {
+        if self.cached == Some(sector) {
+            return Ok(true);
+        }
+        if sector > u64::from(u32::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encrypted storage too large",
+            ));
+        }
+        let mut raw = [0; STORED_SECTOR_SIZE];
+        self.inner.seek(SeekFrom::Start(stored_position(sector, 0)))?;
+        match self.inner.read_exact(&mut raw) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        self.cached = None;
+        let mut newest = None;
+        for (copy, raw) in raw.chunks_mut(COPY_SIZE).enumerate() {
+            let counter = stored_counter(raw);
+            if newest.is_some_and(|(_, newest)| newest >= counter) {
+                continue;
+            }
+            if raw.iter().all(|&byte| byte == 0) {
+                newest = Some((copy, counter));
+                continue;
+            }
+            let (data, tag) = raw[COUNTER_SIZE..].split_at_mut(SECTOR_SIZE);
+            let mut expected = [0; aead::TAG_SIZE];
+            expected.copy_from_slice(tag);
+            if aead::open(&self.key, &nonce(sector, counter), &[], data, &expected) {
+                newest = Some((copy, counter));
+            }
+        }
+        let copy = match newest {
+            Some((copy, _)) => copy,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "sector authentication failed",
+                ))
+            }
+        };
+        let start = copy * COPY_SIZE + COUNTER_SIZE;
+        self.buf.copy_from_slice(&raw[start..(start + SECTOR_SIZE)]);
+        self.cached = Some(sector);
+        self.copy = copy;
+        self.counter = cmp::max(stored_counter(&raw), stored_counter(&raw[COPY_SIZE..]));
+        Ok(true)
+    }
+}
+
+impl<T: Read + Seek> Read for EncryptedStorage<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            let sector = self.pos / SECTOR_SIZE as u64;
+            let offset = (self.pos % SECTOR_SIZE as u64) as usize;
+            if !self.load(sector)? {
+                break;
+            }
+            let len = cmp::min(buf.len() - read, SECTOR_SIZE - offset);
+            buf[read..(read + len)].copy_from_slice(&self.buf[offset..(offset + len)]);
+            read += len;
+            self.pos += len as u64;
+        }
+        Ok(read)
+    }
+}
+
+impl<T: Read + Write + Seek> Write for EncryptedStorage<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let sector = self.pos / SECTOR_SIZE as u64;
+            let offset = (self.pos % SECTOR_SIZE as u64) as usize;
+            if !self.load(sector)? {
+                // extending the storage, as far as the inner one allows
+                self.buf = [0; SECTOR_SIZE];
+                self.counter = 0;
+                self.copy = 0;
+            }
+            // the cached plaintext is about to diverge from storage
+            self.cached = None;
+            let counter = match self.counter.checked_add(1) {
+                Some(counter) => counter,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "sector write counter exhausted",
+                    ))
+                }
+            };
+            let len = cmp::min(buf.len() - written, SECTOR_SIZE - offset);
+            self.buf[offset..(offset + len)].copy_from_slice(&buf[written..(written + len)]);
+
+            // the copy read stays intact until this one is written in full
+            let copy = 1 - self.copy;
+            let mut raw = [0; COPY_SIZE];
+            raw[..COUNTER_SIZE].copy_from_slice(&counter.to_be_bytes());
+            let tag = {
+                let data = &mut raw[COUNTER_SIZE..(COUNTER_SIZE + SECTOR_SIZE)];
+                data.copy_from_slice(&self.buf);
+                aead::seal(&self.key, &nonce(sector, counter), &[], data)
+            };
+            raw[(COUNTER_SIZE + SECTOR_SIZE)..].copy_from_slice(&tag);
+            self.inner.seek(SeekFrom::Start(stored_position(sector, copy)))?;
+            self.inner.write_all(&raw)?;
+            self.cached = Some(sector);
+            self.counter = counter;
+            self.copy = copy;
+            written += len;
+            self.pos += len as u64;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek> Seek for EncryptedStorage<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => {
+                let stored = self.inner.seek(SeekFrom::End(0))?;
+                let len = stored / STORED_SECTOR_SIZE as u64 * SECTOR_SIZE as u64;
+                (len, n)
+            }
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        let new_pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::Cursor;
+    use std::vec::Vec;
+    use testing::fault::{Fault, FaultyStorage};
+    use fs::file_position;
+    use {FileSystem, Path, FS_SIZE};
+
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+    const NAME: &[u8] = b"secret.txt";
+    const DATA: &[u8] = b"the launch codes are 0000";
+
+    fn encrypted_image() -> Vec<u8> {
+        let backing = Cursor::new(vec![0; stored_size(FS_SIZE) as usize]);
+        let mut storage = EncryptedStorage::new(backing, &KEY);
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            let fd = fs
+                .create(Path::from_ascii_str(NAME).unwrap())
+                .expect("failed to create");
+            {
+                // in two parts, so that both copies of the sector are written
+                let mut writer = fs.get_writer(&fd).expect("failed to get writer");
+                writer.write_all(&DATA[..10]).expect("failed to write");
+                writer.write_all(&DATA[10..]).expect("failed to write");
+            }
+            fs.close(fd).expect("failed to close");
+            fs.flush_to_storage().expect("failed to flush");
+        }
+        storage.into_inner().into_inner()
+    }
+
+    fn read_secret(image: Vec<u8>, key: &[u8; KEY_SIZE]) -> io::Result<Vec<u8>> {
+        read_secret_from(&mut EncryptedStorage::new(Cursor::new(image), key))
+    }
+
+    fn read_secret_from<T: Read + Write + Seek>(
+        storage: &mut EncryptedStorage<T>,
+    ) -> io::Result<Vec<u8>> {
+        let mut fs = FileSystem::mount_read_only(storage)?;
+        let fd = fs.open_read(Path::from_ascii_str(NAME).unwrap())?;
+        let mut buf = [0; 64];
+        let len = fs.get_reader(&fd)?.read(&mut buf)?;
+        Ok(buf[..len].to_vec())
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let image = encrypted_image();
+        assert!(!contains(&image, NAME), "name stored in plaintext");
+        assert!(!contains(&image, DATA), "data stored in plaintext");
+        assert_eq!(read_secret(image, &KEY).expect("failed to read"), DATA);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let err = read_secret(encrypted_image(), &[8; KEY_SIZE]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let image = encrypted_image();
+        let written = image
+            .chunks(STORED_SECTOR_SIZE)
+            .enumerate()
+            .filter(|&(_, s)| s.iter().any(|&b| b != 0))
+            .map(|(i, _)| i * STORED_SECTOR_SIZE)
+            .collect::<Vec<_>>();
+        assert!(!written.is_empty());
+        for start in written {
+            // counter, ciphertext and tag, in both copies
+            for &offset in &[3, COUNTER_SIZE + 100, COPY_SIZE - 1] {
+                let mut tampered = image.clone();
+                tampered[start + offset] ^= 0x10;
+                tampered[start + COPY_SIZE + offset] ^= 0x10;
+                match read_secret(tampered, &KEY) {
+                    Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {}
+                    other => panic!("{}: tampering not detected: {:?}", start + offset, other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn swapped_sectors_are_detected() {
+        let mut image = encrypted_image();
+        // the superblock with the first header, and the sector holding the data
+        let data_sector = image
+            .chunks(STORED_SECTOR_SIZE)
+            .rposition(|s| s.chunks(COPY_SIZE).all(|c| c.iter().any(|&b| b != 0)))
+            .unwrap();
+        assert!(data_sector > 0);
+        let (head, tail) = image.split_at_mut(data_sector * STORED_SECTOR_SIZE);
+        head[..STORED_SECTOR_SIZE].swap_with_slice(&mut tail[..STORED_SECTOR_SIZE]);
+        let err = read_secret(image, &KEY).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rewrites_use_fresh_nonces() {
+        let mut storage = EncryptedStorage::new(Cursor::new(vec![0; STORED_SECTOR_SIZE]), &KEY);
+        storage.write_all(&[1; 10]).unwrap();
+        storage.seek(SeekFrom::Start(0)).unwrap();
+        storage.write_all(&[1; 10]).unwrap();
+        // both copies hold the same plaintext
+        let (first, second) = storage.get_ref().get_ref().split_at(COPY_SIZE);
+        assert_eq!(stored_counter(second), 1);
+        assert_eq!(stored_counter(first), 2);
+        assert_ne!(first[COUNTER_SIZE..], second[COUNTER_SIZE..]);
+        assert_eq!(storage.seek(SeekFrom::End(0)).unwrap(), SECTOR_SIZE as u64);
+    }
+
+    #[test]
+    fn power_loss_keeps_sectors_readable() {
+        let mut image = encrypted_image();
+        // only the first two slots are used
+        image.truncate(stored_size(file_position(2)) as usize);
+        // rewrites the superblock, which shares a sector with the header of
+        // the secret, and adds a file next to it
+        let update = |storage: FaultyStorage| {
+            let mut storage = EncryptedStorage::new(storage, &KEY);
+            {
+                let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+                let fd = fs
+                    .create(Path::from_ascii_str(b"other").unwrap())
+                    .expect("failed to create");
+                fs.get_writer(&fd)
+                    .expect("failed to get writer")
+                    .write_all(DATA)
+                    .expect("failed to write");
+                fs.close(fd).expect("failed to close");
+                fs.unmount().expect("failed to unmount");
+            }
+            storage.into_inner()
+        };
+        let total = update(FaultyStorage::new(image.clone())).bytes_written();
+        for cut in 0..total {
+            let fault = Fault::PowerLoss { after_bytes: cut };
+            let storage = update(FaultyStorage::new(image.clone()).with_fault(fault));
+            let mut storage = EncryptedStorage::new(storage.into_image(), &KEY);
+            match read_secret_from(&mut storage) {
+                Ok(ref data) if data == DATA => {}
+                other => panic!("cut at {}: {:?}", cut, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,845 @@
+//! `FileSystem` on top of the async io traits.
+//!
+//! `AsyncFileSystem` keeps the same bookkeeping as `FileSystem` and writes the
+//! same layout, only storage access goes through `AsyncRead`, `AsyncWrite`
+//! and `AsyncSeek`, so that long transfers do not block the executor:
+//!
+//! ```ignore
+//! let mut fs = AsyncFileSystem::new(&mut flash).await?;
+//! let fd = fs.create(path).await?;
+//! fs.get_writer(&fd)?.write_all(data).await?;
+//! fs.close(fd)?;
+//! fs.unmount().await?;
+//! ```
+//!
+//! Operations that only touch in-memory state, such as `open_read` or
+//! `close`, stay synchronous.
+
+use super::*;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{ready, Context, Poll};
+use io::{AsyncRead, AsyncReadWriteSeek, AsyncSeek, AsyncWrite};
+
+pub struct AsyncFileSystem<'a, T: 'a> {
+    storage: &'a mut T,
+    headers: [FileHeader; MAX_FILES],
+    descriptors: [OpenFile; MAX_DESCRIPTORS],
+    /// Whether the previous mount ended with `unmount`.
+    was_clean: bool,
+    /// Whether the superblock has been marked as mounted by this mount.
+    marked: bool,
+    /// See `FileSystem::moved`.
+    moved: Option<(usize, usize)>,
+}
+
+impl<'a, T: AsyncRead + AsyncSeek + 'a> AsyncFileSystem<'a, T> {
+    pub fn new(storage: &'a mut T) -> impl Future<Output = io::Result<Self>> + 'a {
+        Mount {
+            fs: Some(AsyncFileSystem {
+                storage,
+                headers: [NON_EXISTING_FILE; MAX_FILES],
+                descriptors: [UNUSED_FD; MAX_DESCRIPTORS],
+                was_clean: false,
+                marked: false,
+                moved: None,
+            }),
+            slot: None,
+            legacy: None,
+            target: false,
+            transfer: Transfer::new(0),
+            buf: [0; HEADER_SIZE],
+        }
+    }
+
+    pub fn open_read(&mut self, path: Path) -> io::Result<Fd> {
+        open_file(&mut self.headers, &mut self.descriptors, path)
+    }
+
+    pub fn metadata(&self, path: Path) -> io::Result<Metadata> {
+        file_metadata(&self.headers, path)
+    }
+
+    pub fn close(&mut self, fd: Fd) -> io::Result<()> {
+        close_file(&mut self.headers, &mut self.descriptors, fd);
+        Ok(())
+    }
+
+    pub fn get_reader<'b>(&'b mut self, fd: &Fd) -> io::Result<impl AsyncRead + 'b> {
+        let desc = &mut self.descriptors[fd.index];
+        debug_assert!(!desc.writing && desc.used, "invalid descriptor");
+        let header = self.headers[desc.index];
+        Ok(AsyncFsReader {
+            desc,
+            header,
+            reader: self.storage,
+            seeked: false,
+            chunk: None,
+        })
+    }
+
+    pub fn list_files<'b>(&'b mut self) -> impl Iterator<Item = Path> + 'b {
+        FileIterator {
+            headers: &self.headers,
+        }
+    }
+
+    /// See `FileSystem::find`.
+    pub fn find<'b>(&'b self, pattern: &'b [u8]) -> impl Iterator<Item = Path> + 'b {
+        find_files(&self.headers, pattern)
+    }
+
+    /// See `FileSystem::find_sorted`.
+    pub fn find_sorted(&self, pattern: &[u8]) -> impl Iterator<Item = Path> {
+        sorted(find_files(&self.headers, pattern))
+    }
+
+    /// See `FileSystem::was_unmounted_cleanly`.
+    pub fn was_unmounted_cleanly(&self) -> bool {
+        self.was_clean
+    }
+}
+
+impl<'a, T: AsyncReadWriteSeek + 'a> AsyncFileSystem<'a, T> {
+    pub fn create<'b>(
+        &'b mut self,
+        path: Path,
+    ) -> impl Future<Output = io::Result<Fd>> + use<'a, 'b, T> {
+        self.create_with(path, &CreateOptions::new())
+    }
+
+    pub fn create_with<'b>(
+        &'b mut self,
+        path: Path,
+        options: &CreateOptions,
+    ) -> impl Future<Output = io::Result<Fd>> + use<'a, 'b, T> {
+        Create {
+            fs: self,
+            path,
+            options: *options,
+            marker: StateWrite::new(),
+        }
+    }
+
+    pub fn remove(&mut self, path: Path) -> io::Result<()> {
+        remove_file(&mut self.headers, path)
+    }
+
+    /// See `FileSystem::set_flags`.
+    pub fn set_flags(&mut self, path: Path, flags: FileFlags) -> io::Result<()> {
+        set_file_flags(&mut self.headers, path, flags)
+    }
+
+    pub fn get_writer<'b>(&'b mut self, fd: &Fd) -> io::Result<impl AsyncWrite + 'b> {
+        let desc = &mut self.descriptors[fd.index];
+        debug_assert!(desc.writing && desc.used, "invalid descriptor");
+        let index = desc.index;
+        Ok(AsyncFsWriter {
+            desc,
+            header: &mut self.headers[index],
+            writer: self.storage,
+            seeked: false,
+            pending: None,
+        })
+    }
+
+    /// Writes all headers changed since they were last written.
+    pub fn flush_to_storage<'b>(
+        &'b mut self,
+    ) -> impl Future<Output = io::Result<()>> + use<'a, 'b, T> {
+        FlushToStorage {
+            fs: self,
+            flush: Flush::new(),
+        }
+    }
+
+    /// See `FileSystem::unmount`.
+    pub fn unmount(self) -> impl Future<Output = io::Result<&'a mut T>> + 'a {
+        let open = self.descriptors.iter().any(|desc| desc.used);
+        Unmount {
+            fs: Some(self),
+            open,
+            flush: Some(Flush::new()),
+            marker: StateWrite::new(),
+        }
+    }
+
+    /// Records in the superblock that the storage is being modified, before
+    /// the first modification.
+    fn poll_mark_mounted(
+        &mut self,
+        cx: &mut Context,
+        marker: &mut StateWrite,
+    ) -> Poll<io::Result<()>> {
+        if !self.marked {
+            if let Some((from, _)) = self.moved {
+                let free = !self.headers[from].exists;
+                ready!(marker.poll_clear_move(self.storage, cx, from, free))?;
+                self.moved = None;
+            }
+            ready!(marker.poll(self.storage, cx, STATE_MOUNTED))?;
+            self.marked = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Seeking to `pos`, then reading or writing a whole buffer, across polls.
+struct Transfer {
+    pos: u64,
+    seeked: bool,
+    done: usize,
+}
+
+impl Transfer {
+    fn new(pos: u64) -> Self {
+        Transfer {
+            pos,
+            seeked: false,
+            done: 0,
+        }
+    }
+
+    fn poll_read<T>(
+        &mut self,
+        storage: &mut T,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<()>>
+    where
+        T: AsyncRead + AsyncSeek,
+    {
+        if !self.seeked {
+            ready!(storage.poll_seek(cx, SeekFrom::Start(self.pos)))?;
+            self.seeked = true;
+        }
+        while self.done < buf.len() {
+            match ready!(storage.poll_read(cx, &mut buf[self.done..]))? {
+                0 => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to read exact",
+                    )))
+                }
+                n => self.done += n,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write<T>(
+        &mut self,
+        storage: &mut T,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<()>>
+    where
+        T: AsyncWrite + AsyncSeek,
+    {
+        if !self.seeked {
+            ready!(storage.poll_seek(cx, SeekFrom::Start(self.pos)))?;
+            self.seeked = true;
+        }
+        while self.done < buf.len() {
+            match ready!(storage.poll_write(cx, &buf[self.done..]))? {
+                0 => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    )))
+                }
+                n => self.done += n,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Writing and flushing the superblock state, and the layout version along
+/// with the mounted state, as `FileSystem::mark_mounted` does.
+struct StateWrite {
+    /// Frees the slot `finish_move` dropped.
+    dropped: Option<Transfer>,
+    /// Whether the freed slot has been flushed.
+    freed: bool,
+    moved: Transfer,
+    version: Transfer,
+    transfer: Transfer,
+    written: bool,
+}
+
+impl StateWrite {
+    fn new() -> Self {
+        StateWrite {
+            dropped: None,
+            freed: false,
+            moved: Transfer::new(MOVE_OFFSET),
+            version: Transfer::new(VERSION_OFFSET),
+            transfer: Transfer::new(0),
+            written: false,
+        }
+    }
+
+    fn poll<T: AsyncWrite + AsyncSeek>(
+        &mut self,
+        storage: &mut T,
+        cx: &mut Context,
+        state: u8,
+    ) -> Poll<io::Result<()>> {
+        if !self.written {
+            if state == STATE_MOUNTED {
+                ready!(self.version.poll_write(storage, cx, &[LAYOUT_VERSION]))?;
+            }
+            ready!(self.transfer.poll_write(storage, cx, &[state]))?;
+            self.written = true;
+        }
+        storage.poll_flush(cx)
+    }
+
+    /// Clears the move record in the superblock, after freeing slot `from`
+    /// on storage if `free`.
+    fn poll_clear_move<T: AsyncWrite + AsyncSeek>(
+        &mut self,
+        storage: &mut T,
+        cx: &mut Context,
+        from: usize,
+        free: bool,
+    ) -> Poll<io::Result<()>> {
+        if free && !self.freed {
+            let pos = file_position(from as u64);
+            let dropped = self.dropped.get_or_insert_with(|| Transfer::new(pos));
+            ready!(dropped.poll_write(storage, cx, &[0]))?;
+            ready!(storage.poll_flush(cx))?;
+            self.freed = true;
+        }
+        self.moved.poll_write(storage, cx, &[0; MOVE_SIZE])
+    }
+}
+
+struct Mount<'a, T: 'a> {
+    /// Taken once mounted.
+    fs: Option<AsyncFileSystem<'a, T>>,
+    /// Slot whose header is being read, `None` while reading the superblock.
+    slot: Option<usize>,
+    /// Slot of the layouts without a version whose header is being read, see
+    /// `check_layout`.
+    legacy: Option<usize>,
+    /// Whether the target of the symbolic link in `slot` is being read
+    /// rather than its header.
+    target: bool,
+    transfer: Transfer,
+    buf: [u8; HEADER_SIZE],
+}
+
+impl<'a, T: AsyncRead + AsyncSeek + 'a> Future for Mount<'a, T> {
+    type Output = io::Result<AsyncFileSystem<'a, T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let fs = this.fs.as_mut().expect("polled after completion");
+            let len = match this.slot {
+                _ if this.legacy.is_some() => LEGACY_HEADER_SIZE,
+                Some(slot) if this.target => fs.headers[slot].len as usize,
+                Some(_) => HEADER_SIZE,
+                // state, move record and layout version
+                None => 5,
+            };
+            let result = ready!(this
+                .transfer
+                .poll_read(fs.storage, cx, &mut this.buf[..len]));
+            match result {
+                Ok(()) => {}
+                // storage that ends early counts as clean and holds no files,
+                // as with `FileSystem`
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    this.buf = [0; HEADER_SIZE];
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            if let Some(legacy) = this.legacy {
+                if is_legacy_file(legacy, &this.buf) {
+                    return Poll::Ready(Err(legacy_layout()));
+                }
+                if legacy + 1 < LEGACY_SLOTS {
+                    this.legacy = Some(legacy + 1);
+                    this.transfer = Transfer::new(legacy_position(legacy + 1));
+                } else {
+                    this.legacy = None;
+                    this.slot = Some(0);
+                    this.transfer = Transfer::new(file_position(0));
+                }
+                continue;
+            }
+            let next = match this.slot {
+                None => {
+                    fs.was_clean = this.buf[0] == STATE_CLEAN;
+                    fs.moved = parse_move(&this.buf[1..4]);
+                    if check_version(this.buf[4])? {
+                        this.legacy = Some(0);
+                        this.transfer = Transfer::new(legacy_position(0));
+                        continue;
+                    }
+                    0
+                }
+                Some(slot) if this.target => {
+                    fs.headers[slot].target = parse_target(&this.buf[..len]);
+                    this.target = false;
+                    slot + 1
+                }
+                Some(slot) => {
+                    let header = parse_header(slot as u64, &this.buf)?;
+                    fs.headers[slot] = header;
+                    if header.exists && header.is_symlink() {
+                        this.target = true;
+                        this.transfer = Transfer::new(header.data);
+                        continue;
+                    }
+                    slot + 1
+                }
+            };
+            if next == MAX_FILES {
+                if let Some((from, to)) = fs.moved {
+                    finish_move(&mut fs.headers, from, to);
+                }
+                count_links(&mut fs.headers);
+                return Poll::Ready(Ok(this.fs.take().unwrap()));
+            }
+            this.slot = Some(next);
+            this.transfer = Transfer::new(file_position(next as u64));
+        }
+    }
+}
+
+struct Create<'b, 'a: 'b, T: 'a> {
+    fs: &'b mut AsyncFileSystem<'a, T>,
+    path: Path,
+    options: CreateOptions,
+    marker: StateWrite,
+}
+
+impl<'b, 'a: 'b, T: AsyncReadWriteSeek + 'a> Future for Create<'b, 'a, T> {
+    type Output = io::Result<Fd>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<Fd>> {
+        let this = self.get_mut();
+        ready!(this.fs.poll_mark_mounted(cx, &mut this.marker))?;
+        let fs = &mut *this.fs;
+        Poll::Ready(create_file(
+            &mut fs.headers,
+            &mut fs.descriptors,
+            this.path,
+            &this.options,
+        ))
+    }
+}
+
+/// Progress of writing out dirty headers.
+struct Flush {
+    marker: StateWrite,
+    slot: usize,
+    transfer: Option<Transfer>,
+    buf: [u8; HEADER_SIZE],
+}
+
+impl Flush {
+    fn new() -> Self {
+        Flush {
+            marker: StateWrite::new(),
+            slot: 0,
+            transfer: None,
+            buf: [0; HEADER_SIZE],
+        }
+    }
+
+    fn poll<'a, T: AsyncReadWriteSeek + 'a>(
+        &mut self,
+        fs: &mut AsyncFileSystem<'a, T>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        ready!(fs.poll_mark_mounted(cx, &mut self.marker))?;
+        while self.slot < MAX_FILES {
+            let header = &mut fs.headers[self.slot];
+            if header.dirty {
+                if self.transfer.is_none() {
+                    self.buf = encode_header(header);
+                    self.transfer = Some(Transfer::new(file_position(self.slot as u64)));
+                }
+                let transfer = self.transfer.as_mut().unwrap();
+                ready!(transfer.poll_write(fs.storage, cx, &self.buf))?;
+                self.transfer = None;
+                header.dirty = false;
+            }
+            self.slot += 1;
+        }
+        fs.storage.poll_flush(cx)
+    }
+}
+
+struct FlushToStorage<'b, 'a: 'b, T: 'a> {
+    fs: &'b mut AsyncFileSystem<'a, T>,
+    flush: Flush,
+}
+
+impl<'b, 'a: 'b, T: AsyncReadWriteSeek + 'a> Future for FlushToStorage<'b, 'a, T> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.flush.poll(this.fs, cx)
+    }
+}
+
+struct Unmount<'a, T: 'a> {
+    /// Taken once unmounted.
+    fs: Option<AsyncFileSystem<'a, T>>,
+    open: bool,
+    /// `None` once flushed.
+    flush: Option<Flush>,
+    /// Writes the clean state, after flushing.
+    marker: StateWrite,
+}
+
+impl<'a, T: AsyncReadWriteSeek + 'a> Future for Unmount<'a, T> {
+    type Output = io::Result<&'a mut T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&'a mut T>> {
+        let this = self.get_mut();
+        if this.open {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot unmount: files open",
+            )));
+        }
+        let fs = this.fs.as_mut().expect("polled after completion");
+        if let Some(ref mut flush) = this.flush {
+            ready!(flush.poll(fs, cx))?;
+        }
+        this.flush = None;
+        ready!(this.marker.poll(fs.storage, cx, STATE_CLEAN))?;
+        Poll::Ready(Ok(this.fs.take().unwrap().storage))
+    }
+}
+
+struct AsyncFsReader<'b, T: 'b> {
+    desc: &'b mut OpenFile,
+    header: FileHeader,
+    reader: &'b mut T,
+    seeked: bool,
+    /// Compressed chunk being read, with its payload length once known.
+    chunk: Option<(
+        Transfer,
+        Option<usize>,
+        [u8; CHUNK_HEADER_SIZE + CHUNK_SIZE],
+    )>,
+}
+
+impl<'b, T: AsyncRead + AsyncSeek + 'b> AsyncFsReader<'b, T> {
+    fn poll_read_chunk(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let pos = self.header.data + self.desc.stored_pos;
+        let chunk = self.chunk.get_or_insert_with(|| {
+            (
+                Transfer::new(pos),
+                None,
+                [0; CHUNK_HEADER_SIZE + CHUNK_SIZE],
+            )
+        });
+        let (ref mut transfer, ref mut len, ref mut stored) = *chunk;
+        let result = loop {
+            // the payload follows the header, so reading it just continues
+            // the same transfer with a longer buffer
+            let end = CHUNK_HEADER_SIZE + len.unwrap_or(0);
+            if let Err(e) = ready!(transfer.poll_read(self.reader, cx, &mut stored[..end])) {
+                break Err(e);
+            }
+            if len.is_some() {
+                break load_chunk(self.desc, &stored[..end]);
+            }
+            match stored_chunk_len(&stored[..]) {
+                Ok(payload) => *len = Some(payload),
+                Err(e) => break Err(e),
+            }
+        };
+        self.chunk = None;
+        Poll::Ready(result)
+    }
+
+    fn poll_read_compressed(
+        &mut self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.desc.pos >= self.header.len {
+            return Poll::Ready(Ok(0));
+        }
+        if self.desc.chunk_pos == self.desc.chunk_len {
+            if self.desc.stored_pos >= self.header.stored_len {
+                return Poll::Ready(Ok(0));
+            }
+            ready!(self.poll_read_chunk(cx))?;
+        }
+        Poll::Ready(Ok(read_from_chunk(self.desc, &self.header, buf)))
+    }
+}
+
+impl<'b, T: AsyncRead + AsyncSeek + 'b> AsyncRead for AsyncFsReader<'b, T> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.header.is_compressed() {
+            return self.poll_read_compressed(cx, buf);
+        }
+        if !self.seeked {
+            let pos = self.header.data + self.desc.pos;
+            ready!(self.reader.poll_seek(cx, SeekFrom::Start(pos)))?;
+            self.seeked = true;
+        }
+        let remaining_data = self.header.len - self.desc.pos;
+        let max_read = cmp::min(buf.len(), remaining_data as usize);
+        let read = ready!(self.reader.poll_read(cx, &mut buf[..max_read]))?;
+        self.desc.pos += read as u64;
+        Poll::Ready(Ok(read))
+    }
+}
+
+struct AsyncFsWriter<'b, T: 'b> {
+    desc: &'b mut OpenFile,
+    header: &'b mut FileHeader,
+    writer: &'b mut T,
+    seeked: bool,
+    /// Compressed chunk being written, and how much of the data passed to
+    /// `poll_write` it takes.
+    pending: Option<(Transfer, StoredChunk, usize)>,
+}
+
+impl<'b, T: AsyncWrite + AsyncSeek + 'b> AsyncWrite for AsyncFsWriter<'b, T> {
+    /// Compressed files have their current chunk stored on every write, as
+    /// `AsyncFileSystem::close` cannot store it later.
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.header.is_compressed() {
+            if self.pending.is_none() {
+                match buffer_chunk(self.desc, self.header, buf) {
+                    0 => return Poll::Ready(Ok(0)),
+                    written => {
+                        let chunk = seal_chunk(self.desc, self.header);
+                        self.pending = Some((Transfer::new(chunk.pos), chunk, written));
+                    }
+                }
+            }
+            let result = {
+                let (ref mut transfer, ref chunk, _) = *self.pending.as_mut().unwrap();
+                ready!(transfer.poll_write(self.writer, cx, chunk.stored()))
+            };
+            let (_, chunk, written) = self.pending.take().unwrap();
+            if let Err(e) = result {
+                // the data was not taken after all
+                self.desc.chunk_len -= written;
+                self.desc.unstored = false;
+                return Poll::Ready(Err(e));
+            }
+            chunk.commit(self.desc, self.header);
+            self.desc.pos += written as u64;
+            self.header.len += written as u64;
+            return Poll::Ready(Ok(written));
+        }
+        if !self.seeked {
+            let pos = self.header.data + self.desc.pos;
+            ready!(self.writer.poll_seek(cx, SeekFrom::Start(pos)))?;
+            self.seeked = true;
+        }
+        let remaining_space = MAX_FILE_SIZE - self.header.len;
+        let max_write = cmp::min(buf.len(), remaining_space as usize);
+        let written = ready!(self.writer.poll_write(cx, &buf[..max_write]))?;
+        self.desc.pos += written as u64;
+        self.header.len += written as u64;
+        self.header.dirty = true;
+        self.header.stored_len = self.header.len;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.writer.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs::tests::{moved_file_image, FIRST_LAYOUT_IMAGE};
+    use io::{AsyncRead, AsyncWrite, Cursor};
+    use std::vec::Vec;
+    use testing::asynch::{block_on, Stuttering};
+
+    fn path(name: &str) -> Path {
+        Path::from_ascii_str(name.as_bytes()).unwrap()
+    }
+
+    fn storage() -> Stuttering<Cursor<Vec<u8>>> {
+        Stuttering::new(Cursor::new(vec![0; FS_SIZE as usize]))
+    }
+
+    fn read_all<R: AsyncRead>(mut reader: R) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 100];
+        loop {
+            match block_on(reader.read(&mut buf)).unwrap() {
+                0 => return data,
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let data = (0..3000).map(|i| (i / 10) as u8).collect::<Vec<_>>();
+        let mut storage = storage();
+        {
+            let mut fs = block_on(AsyncFileSystem::new(&mut storage)).unwrap();
+            assert!(fs.was_unmounted_cleanly());
+            let plain = block_on(fs.create(path("plain"))).unwrap();
+            block_on(fs.get_writer(&plain).unwrap().write_all(&data)).unwrap();
+            fs.close(plain).unwrap();
+            let options = CreateOptions::new().compressed(true);
+            let packed = block_on(fs.create_with(path("packed"), &options)).unwrap();
+            block_on(fs.get_writer(&packed).unwrap().write_all(&data)).unwrap();
+            fs.close(packed).unwrap();
+
+            let fd = fs.open_read(path("packed")).unwrap();
+            assert_eq!(read_all(fs.get_reader(&fd).unwrap()), data);
+            fs.close(fd).unwrap();
+            let metadata = fs.metadata(path("packed")).unwrap();
+            assert!(metadata.stored_len() < metadata.len());
+            block_on(fs.unmount()).unwrap();
+        }
+
+        // the layout is the same as the blocking file system's
+        let mut fs = FileSystem::new(storage.get_mut()).unwrap();
+        assert!(fs.was_unmounted_cleanly());
+        for name in &["plain", "packed"] {
+            let fd = fs.open_read(path(name)).unwrap();
+            let mut read = Vec::new();
+            fs.get_reader(&fd).unwrap().read_to_end(&mut read).unwrap();
+            assert_eq!(read, data, "{}", name);
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn remove_and_set_flags_check_the_file() {
+        let mut storage = storage();
+        {
+            let mut fs = FileSystem::new(storage.get_mut()).unwrap();
+            let fd = fs.create(path("locked")).unwrap();
+            fs.get_writer(&fd).unwrap().write_all(b"kept").unwrap();
+            fs.close(fd).unwrap();
+            fs.set_flags(path("locked"), FileFlags::new().read_only(true))
+                .unwrap();
+            fs.unmount().unwrap();
+        }
+        let mut fs = block_on(AsyncFileSystem::new(&mut storage)).unwrap();
+        let err = fs.remove(path("locked")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        fs.set_flags(path("locked"), FileFlags::new()).unwrap();
+        fs.remove(path("locked")).unwrap();
+        let err = fs.set_flags(path("locked"), FileFlags::new()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn old_layout_is_rejected() {
+        let mut storage = storage();
+        {
+            let mut fs = block_on(AsyncFileSystem::new(&mut storage)).unwrap();
+            let fd = block_on(fs.create(path("file"))).unwrap();
+            fs.close(fd).unwrap();
+            block_on(fs.unmount()).unwrap();
+        }
+        assert_eq!(storage.get_ref().get_ref()[VERSION_OFFSET as usize], LAYOUT_VERSION);
+        assert!(block_on(AsyncFileSystem::new(&mut storage)).is_ok());
+
+        let mut storage = self::storage();
+        storage.get_mut().get_mut()[legacy_position(MAX_FILES - 1) as usize] = 1;
+        let err = block_on(AsyncFileSystem::new(&mut storage)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut storage = self::storage();
+        for &(pos, bytes) in FIRST_LAYOUT_IMAGE {
+            storage.get_mut().get_mut()[pos..(pos + bytes.len())].copy_from_slice(bytes);
+        }
+        let err = block_on(AsyncFileSystem::new(&mut storage)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn move_record_is_cleared_on_mount() {
+        let mut storage = Stuttering::new(Cursor::new(moved_file_image()));
+        {
+            let mut fs = block_on(AsyncFileSystem::new(&mut storage)).unwrap();
+            assert_eq!(fs.list_files().collect::<Vec<_>>(), [path("moved.txt")]);
+            // power is lost before the header of the new file is written
+            let fd = block_on(fs.create(path("new"))).unwrap();
+            fs.close(fd).unwrap();
+        }
+        let image = storage.get_ref().get_ref();
+        assert_eq!(image[1..4], [0; MOVE_SIZE]);
+        assert_eq!(image[file_position(0) as usize], 0);
+        let mut fs = block_on(AsyncFileSystem::new(&mut storage)).unwrap();
+        assert_eq!(fs.list_files().collect::<Vec<_>>(), [path("moved.txt")]);
+        let fd = fs.open_read(path("moved.txt")).unwrap();
+        assert_eq!(read_all(fs.get_reader(&fd).unwrap()), b"contents");
+    }
+
+    #[test]
+    fn flush_marks_mounted() {
+        let mut storage = storage();
+        let mut fs = block_on(AsyncFileSystem::new(&mut storage)).unwrap();
+        let fd = block_on(fs.create(path("file"))).unwrap();
+        block_on(fs.get_writer(&fd).unwrap().write_all(b"data")).unwrap();
+        block_on(fs.flush_to_storage()).unwrap();
+        assert!(block_on(fs.unmount()).is_err());
+
+        let mut storage = storage.get_ref().clone();
+        let fs = FileSystem::mount_read_only(&mut storage).unwrap();
+        assert!(!fs.was_unmounted_cleanly());
+        assert_eq!(fs.metadata(path("file")).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn mount_follows_links() {
+        let mut storage = storage();
+        {
+            let mut fs = FileSystem::new(storage.get_mut()).unwrap();
+            let fd = fs.create(path("data")).unwrap();
+            fs.get_writer(&fd).unwrap().write_all(b"abc").unwrap();
+            fs.close(fd).unwrap();
+            fs.symlink(path("data"), path("soft")).unwrap();
+            fs.hard_link(path("data"), path("hard")).unwrap();
+            fs.remove(path("data")).unwrap();
+            fs.unmount().unwrap();
+        }
+        let mut fs = block_on(AsyncFileSystem::new(&mut storage)).unwrap();
+        assert!(fs.open_read(path("soft")).is_err());
+        let fd = fs.open_read(path("hard")).unwrap();
+        assert_eq!(read_all(fs.get_reader(&fd).unwrap()), b"abc");
+        fs.close(fd).unwrap();
+        let fd = block_on(fs.create(path("data"))).unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(fs.metadata(path("soft")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn remove_and_list() {
+        let mut storage = storage();
+        let mut fs = block_on(AsyncFileSystem::new(&mut storage)).unwrap();
+        for name in &["a", "b", "c"] {
+            let fd = block_on(fs.create(path(name))).unwrap();
+            fs.close(fd).unwrap();
+        }
+        fs.remove(path("b")).unwrap();
+        let names = fs.list_files().collect::<Vec<_>>();
+        assert_eq!(names, [path("a"), path("c")]);
+        block_on(fs.unmount()).unwrap();
+
+        let fs = block_on(AsyncFileSystem::new(&mut storage)).unwrap();
+        assert!(fs.was_unmounted_cleanly());
+        assert_eq!(fs.headers.iter().filter(|h| h.exists).count(), 2);
+    }
+}
@@ -0,0 +1,485 @@
+//! File flags and extended attributes.
+//!
+//! Attributes of a file live in an area between its header and its data,
+//! holding two copies that are written in turn, so that an update cut short
+//! by power loss leaves the previous one intact. A header flag tells whether
+//! the area belongs to the file in the slot, so that a new file does not pick
+//! up the attributes of a removed one.
+
+use super::*;
+use crc::Crc32;
+use record::seq_newer;
+
+/// Slot has extended attributes.
+pub(super) const FLAG_XATTRS: u16 = 16;
+pub(super) const FLAG_READ_ONLY: u16 = 32;
+const FLAG_HIDDEN: u16 = 64;
+const FLAG_SYSTEM: u16 = 128;
+/// Flags set through `FileFlags`.
+pub(super) const FLAG_USER: u16 = FLAG_READ_ONLY | FLAG_HIDDEN | FLAG_SYSTEM;
+
+pub(super) const XATTR_SIZE: usize = 2 * COPY_SIZE;
+const COPY_SIZE: usize = 256;
+// seq (4), used (2), crc of everything else (4)
+const COPY_HEADER_SIZE: usize = 10;
+/// Space for all attributes of a file, each taking 2 bytes on top of its name
+/// and value.
+const XATTR_CAPACITY: usize = COPY_SIZE - COPY_HEADER_SIZE;
+const MAX_XATTR_NAME_LENGTH: usize = 32;
+
+/// Flags of a file, see `FileSystem::set_flags`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct FileFlags {
+    flags: u16,
+}
+
+impl FileFlags {
+    pub fn new() -> Self {
+        FileFlags::default()
+    }
+
+    /// Makes `create`, `open_append`, `truncate` and `remove` of the file
+    /// fail with `ErrorKind::PermissionDenied`.
+    pub fn read_only(self, read_only: bool) -> Self {
+        self.with(FLAG_READ_ONLY, read_only)
+    }
+
+    /// Only stored for the application, the file system does not hide
+    /// anything.
+    pub fn hidden(self, hidden: bool) -> Self {
+        self.with(FLAG_HIDDEN, hidden)
+    }
+
+    /// Only stored for the application.
+    pub fn system(self, system: bool) -> Self {
+        self.with(FLAG_SYSTEM, system)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.flags & FLAG_READ_ONLY != 0
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.flags & FLAG_HIDDEN != 0
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.flags & FLAG_SYSTEM != 0
+    }
+
+    pub(super) fn from_header(flags: u16) -> Self {
+        FileFlags {
+            flags: flags & FLAG_USER,
+        }
+    }
+
+    pub(super) fn to_header(self) -> u16 {
+        self.flags
+    }
+
+    fn with(mut self, flag: u16, enabled: bool) -> Self {
+        if enabled {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+}
+
+/// Extended attributes of a file, as returned by `FileSystem::list_xattrs`.
+#[derive(Copy, Clone)]
+pub struct Xattrs {
+    // name len (1), value len (1), name, value, repeated
+    buf: [u8; XATTR_CAPACITY],
+    len: usize,
+}
+
+impl Xattrs {
+    fn new() -> Self {
+        Xattrs {
+            buf: [0; XATTR_CAPACITY],
+            len: 0,
+        }
+    }
+
+    pub fn get(&self, name: &[u8]) -> Option<&[u8]> {
+        self.iter().find(|&(n, _)| n == name).map(|(_, value)| value)
+    }
+
+    /// Returns names and values of all attributes, in the order they were
+    /// first set.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        XattrIter {
+            buf: &self.buf[..self.len],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn set(&mut self, name: &[u8], value: &[u8]) -> io::Result<()> {
+        if name.is_empty()
+            || name.len() > MAX_XATTR_NAME_LENGTH
+            || value.len() > u8::MAX as usize
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad attribute"));
+        }
+        let mut new = Xattrs::new();
+        let mut replaced = false;
+        for (n, v) in self.iter() {
+            if n == name {
+                new.push(n, value)?;
+                replaced = true;
+            } else {
+                new.push(n, v)?;
+            }
+        }
+        if !replaced {
+            new.push(name, value)?;
+        }
+        *self = new;
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &[u8]) -> bool {
+        let mut new = Xattrs::new();
+        for (n, v) in self.iter().filter(|&(n, _)| n != name) {
+            new.push(n, v).expect("attributes got larger");
+        }
+        let removed = new.len != self.len;
+        *self = new;
+        removed
+    }
+
+    fn push(&mut self, name: &[u8], value: &[u8]) -> io::Result<()> {
+        let end = self.len + 2 + name.len() + value.len();
+        if end > XATTR_CAPACITY {
+            return Err(io::Error::new(io::ErrorKind::Other, "no space for attribute"));
+        }
+        self.buf[self.len] = name.len() as u8;
+        self.buf[self.len + 1] = value.len() as u8;
+        self.buf[(self.len + 2)..(self.len + 2 + name.len())].copy_from_slice(name);
+        self.buf[(end - value.len())..end].copy_from_slice(value);
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Xattrs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+struct XattrIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for XattrIter<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.len() < 2 {
+            return None;
+        }
+        let name_len = self.buf[0] as usize;
+        let end = 2 + name_len + self.buf[1] as usize;
+        if end > self.buf.len() {
+            // the crc matched, so this is only reached with a bad encoder
+            self.buf = &[];
+            return None;
+        }
+        let entry = (&self.buf[2..(2 + name_len)], &self.buf[(2 + name_len)..end]);
+        self.buf = &self.buf[end..];
+        Some(entry)
+    }
+}
+
+/// One copy of the attributes area, as read from storage.
+struct AreaCopy {
+    seq: u32,
+    xattrs: Option<Xattrs>,
+}
+
+fn parse_copy(buf: &[u8; COPY_SIZE]) -> AreaCopy {
+    let seq = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let used = u16::from(buf[4]) as usize | (buf[5] as usize) << 8;
+    let mut crc = Crc32::new();
+    crc.update(&buf[..6]);
+    if used <= XATTR_CAPACITY {
+        crc.update(&buf[COPY_HEADER_SIZE..(COPY_HEADER_SIZE + used)]);
+    }
+    if used > XATTR_CAPACITY || crc.finish().to_le_bytes() != buf[6..10] {
+        return AreaCopy {
+            seq: 0,
+            xattrs: None,
+        };
+    }
+    let mut xattrs = Xattrs::new();
+    xattrs.buf[..used].copy_from_slice(&buf[COPY_HEADER_SIZE..(COPY_HEADER_SIZE + used)]);
+    xattrs.len = used;
+    AreaCopy {
+        seq,
+        xattrs: Some(xattrs),
+    }
+}
+
+fn encode_copy(seq: u32, xattrs: &Xattrs) -> [u8; COPY_SIZE] {
+    let mut buf = [0; COPY_SIZE];
+    buf[..4].copy_from_slice(&seq.to_le_bytes());
+    buf[4] = xattrs.len as u8;
+    buf[5] = (xattrs.len >> 8) as u8;
+    buf[COPY_HEADER_SIZE..(COPY_HEADER_SIZE + xattrs.len)]
+        .copy_from_slice(&xattrs.buf[..xattrs.len]);
+    let mut crc = Crc32::new();
+    crc.update(&buf[..6]);
+    crc.update(&xattrs.buf[..xattrs.len]);
+    buf[6..10].copy_from_slice(&crc.finish().to_le_bytes());
+    buf
+}
+
+/// Current attributes of a slot, and where to write the next copy.
+struct Area {
+    xattrs: Xattrs,
+    next_copy: usize,
+    next_seq: u32,
+}
+
+fn read_area<T: Read + Seek>(storage: &mut T, header: &FileHeader) -> io::Result<Area> {
+    let start = header.data - XATTR_SIZE as u64;
+    const MISSING: AreaCopy = AreaCopy {
+        seq: 0,
+        xattrs: None,
+    };
+    let mut copies = [MISSING, MISSING];
+    for (i, copy) in copies.iter_mut().enumerate() {
+        let mut buf = [0; COPY_SIZE];
+        storage.seek(SeekFrom::Start(start + (i * COPY_SIZE) as u64))?;
+        match storage.read_exact(&mut buf) {
+            Ok(()) => *copy = parse_copy(&buf),
+            // trimmed images end before the data of files without attributes
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let newer = copies[0].xattrs.is_none() || seq_newer(copies[1].seq, copies[0].seq);
+    let current = if copies[1].xattrs.is_some() && newer {
+        1
+    } else {
+        0
+    };
+    let xattrs = match copies[current].xattrs {
+        // the area may be left over from a removed file
+        Some(xattrs) if header.flags & FLAG_XATTRS != 0 => xattrs,
+        _ => Xattrs::new(),
+    };
+    Ok(Area {
+        xattrs,
+        next_copy: 1 - current,
+        next_seq: copies[current].seq.wrapping_add(1),
+    })
+}
+
+impl<'a, T: Read + Seek + 'a> FileSystem<'a, T> {
+    /// Returns all extended attributes of the file at `path`.
+    pub fn list_xattrs(&mut self, path: Path) -> io::Result<Xattrs> {
+        let index = self.resolve_for_xattrs(path)?;
+        Ok(read_area(self.storage, &self.headers[index])?.xattrs)
+    }
+
+    /// Copies the value of attribute `name` of the file at `path` into `buf`,
+    /// returning its length, or `None` if the file has no such attribute.
+    /// Fails if the value does not fit in `buf`.
+    pub fn get_xattr(
+        &mut self,
+        path: Path,
+        name: &[u8],
+        buf: &mut [u8],
+    ) -> io::Result<Option<usize>> {
+        let xattrs = self.list_xattrs(path)?;
+        match xattrs.get(name) {
+            Some(value) if value.len() > buf.len() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "attribute larger than buffer",
+            )),
+            Some(value) => {
+                buf[..value.len()].copy_from_slice(value);
+                Ok(Some(value.len()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn resolve_for_xattrs(&self, path: Path) -> io::Result<usize> {
+        match resolve(&self.headers, path)? {
+            Resolved::Slot(index) => Ok(index),
+            Resolved::Missing(_) => Err(io::Error::new(io::ErrorKind::NotFound, "no file")),
+        }
+    }
+}
+
+impl<'a, T: ReadWriteSeek + 'a> FileSystem<'a, T> {
+    /// Sets attribute `name` of the file at `path` to `value`. Names are at
+    /// most 32 bytes long and values at most 255, and all attributes of a
+    /// file together take at most 246 bytes, counting 2 bytes for each on
+    /// top of its name and value. Attributes are kept when the file is
+    /// recreated with `create`.
+    pub fn set_xattr(&mut self, path: Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        let index = self.resolve_for_xattrs(path)?;
+        let mut area = read_area(self.storage, &self.headers[index])?;
+        area.xattrs.set(name, value)?;
+        self.write_area(index, &area)
+    }
+
+    /// Removes attribute `name` of the file at `path`, failing if it is not
+    /// set.
+    pub fn remove_xattr(&mut self, path: Path, name: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        let index = self.resolve_for_xattrs(path)?;
+        let mut area = read_area(self.storage, &self.headers[index])?;
+        if !area.xattrs.remove(name) {
+            return Err(io::Error::new(io::ErrorKind::Other, "no attribute"));
+        }
+        self.write_area(index, &area)
+    }
+
+    /// Replaces the flags of the file at `path`. Takes effect right away,
+    /// and is stored with the header.
+    pub fn set_flags(&mut self, path: Path, flags: FileFlags) -> io::Result<()> {
+        self.check_writable()?;
+        set_file_flags(&mut self.headers, path, flags)
+    }
+
+    fn write_area(&mut self, index: usize, area: &Area) -> io::Result<()> {
+        self.mark_mounted()?;
+        let header = &mut self.headers[index];
+        let start = header.data - XATTR_SIZE as u64;
+        self.storage
+            .seek(SeekFrom::Start(start + (area.next_copy * COPY_SIZE) as u64))?;
+        self.storage
+            .write_all(&encode_copy(area.next_seq, &area.xattrs))?;
+        if header.flags & FLAG_XATTRS == 0 {
+            header.flags |= FLAG_XATTRS;
+            header.dirty = true;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use testing::fault::{Fault, FaultyStorage};
+
+    fn path(name: &str) -> Path {
+        Path::from_ascii_str(name.as_bytes()).unwrap()
+    }
+
+    fn storage() -> io::Cursor<Vec<u8>> {
+        io::Cursor::new(vec![0; FS_SIZE as usize])
+    }
+
+    fn create(fs: &mut FileSystem<io::Cursor<Vec<u8>>>, name: &str) {
+        let fd = fs.create(path(name)).expect("failed to create");
+        fs.close(fd).expect("failed to close");
+    }
+
+    #[test]
+    fn set_get_list() {
+        let mut storage = storage();
+        {
+            let mut fs = FileSystem::new(&mut storage).unwrap();
+            create(&mut fs, "fw.bin");
+            fs.set_xattr(path("fw.bin"), b"type", b"firmware").unwrap();
+            fs.set_xattr(path("fw.bin"), b"version", b"1.2").unwrap();
+            fs.set_xattr(path("fw.bin"), b"type", b"image").unwrap();
+            fs.set_xattr(path("fw.bin"), b"hash", &[7; 32]).unwrap();
+            fs.remove_xattr(path("fw.bin"), b"version").unwrap();
+            assert!(fs.remove_xattr(path("fw.bin"), b"version").is_err());
+            // recreating the file keeps attributes
+            create(&mut fs, "fw.bin");
+            fs.unmount().unwrap();
+        }
+        let mut fs = FileSystem::new(&mut storage).unwrap();
+        let xattrs = fs.list_xattrs(path("fw.bin")).unwrap();
+        let all = xattrs.iter().collect::<Vec<_>>();
+        assert_eq!(all, [(&b"type"[..], &b"image"[..]), (b"hash", &[7; 32])]);
+        let mut buf = [0; 8];
+        assert_eq!(fs.get_xattr(path("fw.bin"), b"type", &mut buf).unwrap(), Some(5));
+        assert_eq!(&buf[..5], b"image");
+        assert!(fs.get_xattr(path("fw.bin"), b"hash", &mut buf).is_err());
+        assert_eq!(fs.get_xattr(path("fw.bin"), b"missing", &mut buf).unwrap(), None);
+
+        let big = [0; 200];
+        let err = fs.set_xattr(path("fw.bin"), b"big", &big).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(fs.set_xattr(path("fw.bin"), b"", b"x").is_err());
+    }
+
+    #[test]
+    fn new_file_does_not_inherit_attributes() {
+        let mut storage = storage();
+        let mut fs = FileSystem::new(&mut storage).unwrap();
+        create(&mut fs, "old");
+        fs.set_xattr(path("old"), b"tag", b"x").unwrap();
+        fs.remove(path("old")).unwrap();
+        create(&mut fs, "new");
+        assert!(fs.list_xattrs(path("new")).unwrap().is_empty());
+        fs.set_xattr(path("new"), b"other", b"y").unwrap();
+        let xattrs = fs.list_xattrs(path("new")).unwrap();
+        assert_eq!(xattrs.get(b"other"), Some(&b"y"[..]));
+        assert_eq!(xattrs.get(b"tag"), None);
+    }
+
+    #[test]
+    fn read_only_flag() {
+        let mut storage = storage();
+        let mut fs = FileSystem::new(&mut storage).unwrap();
+        create(&mut fs, "cfg");
+        let flags = FileFlags::new().read_only(true).hidden(true);
+        fs.set_flags(path("cfg"), flags).unwrap();
+        assert_eq!(fs.metadata(path("cfg")).unwrap().flags(), flags);
+        for result in &[
+            fs.create(path("cfg")).map(|_| ()),
+            fs.open_append(path("cfg")).map(|_| ()),
+            fs.truncate(path("cfg"), 0),
+            fs.remove(path("cfg")),
+        ] {
+            let kind = result.as_ref().unwrap_err().kind();
+            assert_eq!(kind, io::ErrorKind::PermissionDenied);
+        }
+        fs.open_read(path("cfg")).map(|fd| fs.close(fd)).unwrap().unwrap();
+
+        fs.set_flags(path("cfg"), FileFlags::new()).unwrap();
+        fs.remove(path("cfg")).unwrap();
+    }
+
+    #[test]
+    fn power_loss_keeps_previous_attributes() {
+        let image = {
+            let mut storage = storage();
+            let mut fs = FileSystem::new(&mut storage).unwrap();
+            create(&mut fs, "file");
+            fs.set_xattr(path("file"), b"version", b"1").unwrap();
+            fs.unmount().unwrap();
+            storage.into_inner()
+        };
+        for cut in 0..COPY_SIZE as u64 {
+            let mut storage = FaultyStorage::new(image.clone())
+                .with_fault(Fault::PowerLoss { after_bytes: cut });
+            {
+                let mut fs = FileSystem::new(&mut storage).unwrap();
+                let _ = fs.set_xattr(path("file"), b"version", b"2");
+            }
+            let mut image = storage.into_image();
+            let mut fs = FileSystem::new(&mut image).unwrap();
+            let xattrs = fs.list_xattrs(path("file")).unwrap();
+            let version = xattrs.get(b"version");
+            assert!(version == Some(b"1") || version == Some(b"2"), "cut at {}", cut);
+        }
+    }
+}
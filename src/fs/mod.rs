@@ -0,0 +1,2229 @@
+use core::{array, cmp, fmt, iter};
+use io::{self, Read, ReadWriteSeek, Seek, SeekFrom, Write};
+use lz4;
+use path::{self, Path};
+
+mod asynch;
+mod attr;
+mod snapshot;
+
+pub use self::asynch::AsyncFileSystem;
+pub use self::attr::{FileFlags, Xattrs};
+use self::attr::{FLAG_READ_ONLY, FLAG_USER, FLAG_XATTRS, XATTR_SIZE};
+
+pub const MAX_FILES: usize = 16;
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024;
+// exists (1), flags (2), len (8), stored len (8), name
+pub(crate) const HEADER_SIZE: usize = 19 + path::MAX_PATH_LENGTH;
+// header, extended attributes, data
+const FILE_RAW_SIZE: u64 = (HEADER_SIZE + XATTR_SIZE) as u64 + MAX_FILE_SIZE;
+// state (1), move record: pending (1), from (1), to (1), layout version
+// (1), reserved
+const SUPERBLOCK_SIZE: u64 = 16;
+pub const FS_SIZE: u64 = SUPERBLOCK_SIZE + MAX_FILES as u64 * FILE_RAW_SIZE;
+pub(crate) const MAX_DESCRIPTORS: usize = 16;
+
+/// Superblock state, zero so that erased storage counts as clean.
+const STATE_CLEAN: u8 = 0;
+const STATE_MOUNTED: u8 = 1;
+
+/// Layout of slots and headers, stored in the superblock when it is first
+/// marked as mounted. Storage without a version is blank, or was written by
+/// a layout without room for extended attributes and is only accepted if it
+/// holds no files.
+const LAYOUT_VERSION: u8 = 1;
+const VERSION_OFFSET: u64 = 4;
+/// Superblock bytes recording a file being moved to another slot: pending
+/// (1), from (1), to (1). Nothing starts moves any more, but storage can
+/// still hold one cut short by a crash, see `finish_move`.
+const MOVE_OFFSET: u64 = 1;
+const MOVE_SIZE: usize = 3;
+const MOVE_PENDING: u8 = 1;
+/// Slots checked for files in the layouts without a version, see
+/// `legacy_position`.
+const LEGACY_SLOTS: usize = 2 * MAX_FILES;
+// exists (1), and in the first layout locks (1), len (8), name
+const LEGACY_HEADER_SIZE: usize = 11;
+
+/// File data is a sequence of chunks, each compressed on its own.
+const FLAG_COMPRESSED: u16 = 1;
+/// Maximum amount of file data in one compressed chunk.
+const CHUNK_SIZE: usize = 256;
+// stored len (2, top bit set if stored uncompressed), data len (2)
+const CHUNK_HEADER_SIZE: usize = 4;
+const CHUNK_RAW: u16 = 0x8000;
+
+/// Symbolic link, with the path it points to as its data.
+const FLAG_SYMLINK: u16 = 2;
+/// Hard link, sharing the data of the slot stored in place of its length.
+const FLAG_HARD_LINK: u16 = 4;
+/// File whose name was removed while hard links to it remain.
+const FLAG_UNNAMED: u16 = 8;
+/// Symbolic links followed while looking up a path before giving up.
+const MAX_LINK_DEPTH: usize = 8;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Fd {
+    index: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct OpenFile {
+    used: bool,
+    index: usize,
+    pos: u64,
+    writing: bool,
+    // for compressed files: where the current chunk is stored, and its
+    // uncompressed contents
+    stored_pos: u64,
+    chunk: [u8; CHUNK_SIZE],
+    chunk_len: usize,
+    chunk_pos: usize,
+    /// Whether `chunk` holds data written but not stored yet.
+    unstored: bool,
+}
+
+const UNUSED_FD: OpenFile = OpenFile {
+    used: false,
+    index: 0,
+    pos: 0,
+    writing: false,
+    stored_pos: 0,
+    chunk: [0; CHUNK_SIZE],
+    chunk_len: 0,
+    chunk_pos: 0,
+    unstored: false,
+};
+
+#[derive(Debug, Copy, Clone)]
+struct FileHeader {
+    exists: bool,
+    locks: u8,
+    flags: u16,
+    len: u64,
+    /// Bytes of the slot taken by file data, equal to `len` unless the file
+    /// is compressed.
+    stored_len: u64,
+    name: Path,
+    data: u64,
+    /// Changed since it was last written to storage.
+    dirty: bool,
+    /// Path a symbolic link points to.
+    target: Path,
+    /// Slot a hard link points to.
+    linked: usize,
+    /// Number of hard links pointing to this slot, counted on mount.
+    links: u8,
+}
+
+impl FileHeader {
+    fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.flags & FLAG_SYMLINK != 0
+    }
+
+    fn is_hard_link(&self) -> bool {
+        self.flags & FLAG_HARD_LINK != 0
+    }
+
+    fn is_named(&self) -> bool {
+        self.flags & FLAG_UNNAMED == 0
+    }
+
+    fn check_not_read_only(&self) -> io::Result<()> {
+        if self.flags & FLAG_READ_ONLY != 0 {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only file"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn can_write(&self) -> bool {
+        self.locks == 0
+    }
+
+    fn can_read(&self) -> bool {
+        self.locks < u8::MAX
+    }
+
+    fn lock_write(&mut self) {
+        debug_assert!(self.can_write(), "cannot lock for write");
+        self.locks = u8::MAX;
+    }
+
+    fn lock_read(&mut self) {
+        debug_assert!(self.can_read(), "cannot lock for read");
+        self.locks += 1;
+    }
+
+    fn unlock_write(&mut self) {
+        debug_assert!(self.locks == u8::MAX, "cannot unlock write");
+        self.locks = 0;
+    }
+
+    fn unlock_read(&mut self) {
+        debug_assert!(self.locks > 0, "cannot unlock read");
+        self.locks -= 1;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    len: u64,
+    stored_len: u64,
+    compressed: bool,
+    flags: FileFlags,
+}
+
+impl Metadata {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bytes the file takes up in storage.
+    pub fn stored_len(&self) -> u64 {
+        self.stored_len
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    pub fn flags(&self) -> FileFlags {
+        self.flags
+    }
+}
+
+/// Options for `FileSystem::create_with`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CreateOptions {
+    flags: u16,
+}
+
+impl CreateOptions {
+    pub fn new() -> Self {
+        CreateOptions::default()
+    }
+
+    /// Compress file contents on the fly. Compressed files can hold more than
+    /// `MAX_FILE_SIZE` bytes if their contents compress well.
+    pub fn compressed(mut self, compressed: bool) -> Self {
+        if compressed {
+            self.flags |= FLAG_COMPRESSED;
+        } else {
+            self.flags &= !FLAG_COMPRESSED;
+        }
+        self
+    }
+}
+
+const NON_EXISTING_FILE: FileHeader = FileHeader {
+    exists: false,
+    locks: 0,
+    flags: 0,
+    len: 0,
+    stored_len: 0,
+    name: path::EMPTY,
+    data: 0,
+    dirty: false,
+    target: path::EMPTY,
+    linked: 0,
+    links: 0,
+};
+
+pub struct FileSystem<'a, T: 'a> {
+    storage: &'a mut T,
+    headers: [FileHeader; MAX_FILES],
+    descriptors: [OpenFile; MAX_DESCRIPTORS],
+    read_only: bool,
+    /// Whether the previous mount ended with `unmount`.
+    was_clean: bool,
+    /// Whether the superblock has been marked as mounted by this mount.
+    marked: bool,
+    flush_on_close: bool,
+    /// Move recorded in the superblock, cleared when it is first marked as
+    /// mounted.
+    moved: Option<(usize, usize)>,
+}
+
+impl<'a, T: Read + Seek + 'a> FileSystem<'a, T> {
+    pub fn new(storage: &'a mut T) -> io::Result<Self> {
+        Self::mount(storage, false)
+    }
+
+    /// Mounts `storage` without ever writing to it. `create`, `remove` and
+    /// `flush_to_storage` fail with `ErrorKind::ReadOnly`, and storage that
+    /// only implements `Read + Seek` can be mounted this way too.
+    pub fn mount_read_only(storage: &'a mut T) -> io::Result<Self> {
+        Self::mount(storage, true)
+    }
+
+    fn mount(storage: &'a mut T, read_only: bool) -> io::Result<Self> {
+        let mut fs = FileSystem {
+            storage,
+            headers: [NON_EXISTING_FILE; MAX_FILES],
+            descriptors: [UNUSED_FD; MAX_DESCRIPTORS],
+            read_only,
+            was_clean: false,
+            marked: false,
+            flush_on_close: false,
+            moved: None,
+        };
+        fs.was_clean = read_state(fs.storage)? == STATE_CLEAN;
+        check_layout(fs.storage)?;
+        fs.load_headers()?;
+        Ok(fs)
+    }
+
+    /// Reads all headers and the targets of symbolic links, finishes a move
+    /// cut short by a crash, and counts hard links.
+    fn load_headers(&mut self) -> io::Result<()> {
+        self.moved = read_move(self.storage)?;
+        for i in 0..MAX_FILES {
+            let buf = read_raw_header(self.storage, i as u64)?;
+            let mut header = parse_header(i as u64, &buf)?;
+            if header.exists && header.is_symlink() {
+                let mut target = [0; path::MAX_PATH_LENGTH];
+                let target = &mut target[..header.len as usize];
+                self.storage.seek(SeekFrom::Start(header.data))?;
+                match self.storage.read_exact(target) {
+                    Ok(()) => header.target = parse_target(target),
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.headers[i] = header;
+        }
+        if let Some((from, to)) = self.moved {
+            finish_move(&mut self.headers, from, to);
+        }
+        count_links(&mut self.headers);
+        Ok(())
+    }
+
+    pub fn open_read(&mut self, path: Path) -> io::Result<Fd> {
+        open_file(&mut self.headers, &mut self.descriptors, path)
+    }
+
+    pub fn metadata(&self, path: Path) -> io::Result<Metadata> {
+        file_metadata(&self.headers, path)
+    }
+
+    /// Returns the path the symbolic link `path` points to.
+    pub fn read_link(&self, path: Path) -> io::Result<Path> {
+        match find_file(&self.headers, path) {
+            Some(index) if self.headers[index].is_symlink() => Ok(self.headers[index].target),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "not a symlink")),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "cannot read link: no file")),
+        }
+    }
+
+    /// Closes `fd` opened by `open_read`. This works on storage that cannot
+    /// be written to, files opened for writing are closed with `close`.
+    pub fn close_read(&mut self, fd: Fd) -> io::Result<()> {
+        if self.descriptors[fd.index].writing {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot close: open for writing",
+            ));
+        }
+        close_file(&mut self.headers, &mut self.descriptors, fd);
+        Ok(())
+    }
+
+    pub fn get_reader<'b>(&'b mut self, fd: &Fd) -> io::Result<impl io::Read + 'b> {
+        let desc = &mut self.descriptors[fd.index];
+        debug_assert!(!desc.writing && desc.used, "invalid descriptor");
+        let header = self.headers[desc.index];
+        if !header.is_compressed() {
+            self.storage.seek(SeekFrom::Start(header.data + desc.pos))?;
+        }
+        Ok(FsReader {
+            desc,
+            header,
+            reader: self.storage,
+        })
+    }
+
+    pub fn list_files<'b>(&'b mut self) -> impl Iterator<Item = Path> + 'b {
+        FileIterator {
+            headers: &self.headers,
+        }
+    }
+
+    /// Returns paths of files that match glob `pattern`, see `Path::matches`,
+    /// in slot order.
+    pub fn find<'b>(&'b self, pattern: &'b [u8]) -> impl Iterator<Item = Path> + 'b {
+        find_files(&self.headers, pattern)
+    }
+
+    /// Like `find`, but returns paths in byte order.
+    pub fn find_sorted(&self, pattern: &[u8]) -> impl Iterator<Item = Path> {
+        sorted(find_files(&self.headers, pattern))
+    }
+
+    /// Returns the length of the storage prefix that holds every existing
+    /// file. Storage can be cut to that length and still be mounted for
+    /// reading.
+    pub fn storage_used(&self) -> u64 {
+        self.headers
+            .iter()
+            .filter(|header| header.exists)
+            .map(|header| header.data + header.stored_len)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        self.storage
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns `false` if the storage was last mounted for writing and never
+    /// unmounted, in which case `check_storage` should be run on it.
+    pub fn was_unmounted_cleanly(&self) -> bool {
+        self.was_clean
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            Err(io::Error::new(io::ErrorKind::ReadOnly, "read-only filesystem"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'a, T: ReadWriteSeek + 'a> FileSystem<'a, T> {
+    /// Makes `close` of a file opened for writing also write its header, as
+    /// `sync` does, so that the file is kept by a power loss once `close`
+    /// returns.
+    pub fn set_flush_on_close(&mut self, enabled: bool) {
+        self.flush_on_close = enabled;
+    }
+
+    /// Closes `fd`. A file opened for writing has the data still buffered for
+    /// it stored first, and stays open if that fails. See also
+    /// `set_flush_on_close`.
+    pub fn close(&mut self, fd: Fd) -> io::Result<()> {
+        if self.descriptors[fd.index].writing {
+            self.mark_mounted()?;
+            let desc = &mut self.descriptors[fd.index];
+            store_chunk(self.storage, desc, &mut self.headers[desc.index])?;
+        }
+        let index = match close_file(&mut self.headers, &mut self.descriptors, fd) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        if self.flush_on_close && self.headers[index].dirty {
+            sync_header(self.storage, index as u64, &self.headers[index])?;
+            self.headers[index].dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Records in the superblock that the storage is being modified, before
+    /// the first modification.
+    fn mark_mounted(&mut self) -> io::Result<()> {
+        if !self.marked {
+            if let Some((from, _)) = self.moved {
+                // the slot dropped by `finish_move` is freed on storage first,
+                // so that the file is not found twice once the record is gone
+                if !self.headers[from].exists {
+                    write_exists(self.storage, from, false)?;
+                    self.storage.flush()?;
+                }
+                write_move(self.storage, None)?;
+                self.moved = None;
+            }
+            write_version(self.storage)?;
+            write_state(self.storage, STATE_MOUNTED)?;
+            self.storage.flush()?;
+            self.marked = true;
+        }
+        Ok(())
+    }
+
+    /// Writes all headers changed since they were last written.
+    pub fn flush_to_storage(&mut self) -> io::Result<()> {
+        self.check_writable()?;
+        self.mark_mounted()?;
+        for desc in self.descriptors.iter_mut() {
+            store_chunk(self.storage, desc, &mut self.headers[desc.index])?;
+        }
+        for i in 0..MAX_FILES {
+            if self.headers[i].dirty {
+                write_header(self.storage, i as u64, &self.headers[i])?;
+                self.headers[i].dirty = false;
+            }
+        }
+        self.storage.flush()
+    }
+
+    /// Persists a single open file: its header and everything written to it
+    /// so far.
+    pub fn sync(&mut self, fd: &Fd) -> io::Result<()> {
+        debug_assert!(self.descriptors[fd.index].used, "invalid descriptor");
+        self.check_writable()?;
+        self.mark_mounted()?;
+        let desc = &mut self.descriptors[fd.index];
+        let index = desc.index;
+        store_chunk(self.storage, desc, &mut self.headers[index])?;
+        if self.headers[index].dirty {
+            write_header(self.storage, index as u64, &self.headers[index])?;
+            self.headers[index].dirty = false;
+        }
+        self.storage.flush()
+    }
+
+    pub fn create(&mut self, path: Path) -> io::Result<Fd> {
+        self.create_with(path, &CreateOptions::new())
+    }
+
+    pub fn create_with(&mut self, path: Path, options: &CreateOptions) -> io::Result<Fd> {
+        self.check_writable()?;
+        self.mark_mounted()?;
+        create_file(&mut self.headers, &mut self.descriptors, path, options)
+    }
+
+    /// Opens an existing file for writing after its current end. Compressed
+    /// files cannot be appended to.
+    pub fn open_append(&mut self, path: Path) -> io::Result<Fd> {
+        self.check_writable()?;
+        self.mark_mounted()?;
+        append_file(&mut self.headers, &mut self.descriptors, path)
+    }
+
+    /// Shortens a file to `len` bytes. Does nothing if it is not longer than
+    /// that already.
+    pub fn truncate(&mut self, path: Path, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        truncate_file(&mut self.headers, path, len)
+    }
+
+    /// Removes `path`. Symbolic links are removed themselves rather than
+    /// what they point to, and file data stays around while hard links to
+    /// it remain.
+    pub fn remove(&mut self, path: Path) -> io::Result<()> {
+        self.check_writable()?;
+        remove_file(&mut self.headers, path)
+    }
+
+    /// Creates a symbolic link `link` pointing to `target`, which does not
+    /// have to exist. Opening `link` opens `target`, and `create` through it
+    /// creates `target`. The link takes a slot of its own.
+    pub fn symlink(&mut self, target: Path, link: Path) -> io::Result<()> {
+        self.check_writable()?;
+        if find_file(&self.headers, link).is_some() {
+            return Err(io::Error::new(io::ErrorKind::Other, "cannot link: file exists"));
+        }
+        self.mark_mounted()?;
+        let index = match find_empty_slot(&mut self.headers) {
+            Some((index, _)) => index,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "cannot link: no slots")),
+        };
+        let target_path = target.as_slice();
+        self.storage.seek(SeekFrom::Start(self.headers[index].data))?;
+        self.storage.write_all(target_path)?;
+        let header = &mut self.headers[index];
+        header.exists = true;
+        header.flags = FLAG_SYMLINK;
+        header.len = target_path.len() as u64;
+        header.stored_len = header.len;
+        header.name = link;
+        header.target = target;
+        header.dirty = true;
+        Ok(())
+    }
+
+    /// Gives the file at `existing` another name, `link`, sharing its data.
+    /// The data is freed once the last of its names is removed. The link
+    /// takes a slot of its own.
+    pub fn hard_link(&mut self, existing: Path, link: Path) -> io::Result<()> {
+        self.check_writable()?;
+        let data = match resolve(&self.headers, existing)? {
+            Resolved::Slot(index) => index,
+            Resolved::Missing(_) => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "cannot link: no file"))
+            }
+        };
+        if find_file(&self.headers, link).is_some() {
+            return Err(io::Error::new(io::ErrorKind::Other, "cannot link: file exists"));
+        }
+        if self.headers[data].links == u8::MAX {
+            return Err(io::Error::new(io::ErrorKind::Other, "cannot link: too many links"));
+        }
+        let header = match find_empty_slot(&mut self.headers) {
+            Some((_, header)) => header,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "cannot link: no slots")),
+        };
+        header.exists = true;
+        header.flags = FLAG_HARD_LINK;
+        header.name = link;
+        header.linked = data;
+        header.dirty = true;
+        self.headers[data].links += 1;
+        Ok(())
+    }
+
+    /// Fails with `ErrorKind::PermissionDenied` if `fd` was opened for reading.
+    pub fn get_writer<'b>(&'b mut self, fd: &Fd) -> io::Result<impl io::Write + 'b> {
+        if !self.descriptors[fd.index].writing {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "cannot write: file opened for reading",
+            ));
+        }
+        self.mark_mounted()?;
+        let desc = &mut self.descriptors[fd.index];
+        debug_assert!(desc.used, "invalid descriptor");
+        let header = &mut self.headers[desc.index];
+        if !header.is_compressed() {
+            self.storage.seek(SeekFrom::Start(header.data + desc.pos))?;
+        }
+        Ok(FsWriter {
+            desc,
+            header,
+            writer: self.storage,
+        })
+    }
+
+    /// Flushes headers, marks the storage as cleanly unmounted and hands it
+    /// back. Fails if any file is still open.
+    pub fn unmount(self) -> io::Result<&'a mut T> {
+        if self.descriptors.iter().any(|desc| desc.used) {
+            return Err(io::Error::new(io::ErrorKind::Other, "cannot unmount: files open"));
+        }
+        self.force_unmount()
+    }
+
+    /// Like `unmount`, but closes any open files first, keeping what was
+    /// written to them.
+    pub fn force_unmount(mut self) -> io::Result<&'a mut T> {
+        for index in 0..MAX_DESCRIPTORS {
+            if self.descriptors[index].used {
+                self.close(Fd { index })?;
+            }
+        }
+        if !self.read_only {
+            self.flush_to_storage()?;
+            write_state(self.storage, STATE_CLEAN)?;
+            self.storage.flush()?;
+        }
+        Ok(self.storage)
+    }
+}
+
+struct FsWriter<'a, T: 'a> {
+    desc: &'a mut OpenFile,
+    header: &'a mut FileHeader,
+    writer: &'a mut T,
+}
+
+impl<'a, T: Write + Seek + 'a> FsWriter<'a, T> {
+    /// Buffers data into the current chunk. A full chunk is stored when the
+    /// next write starts a new one after it, and one that is not full yet on
+    /// `flush`, `sync` or `flush_to_storage`.
+    fn write_compressed(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.desc.chunk_len == CHUNK_SIZE {
+            store_chunk(self.writer, self.desc, self.header)?;
+        }
+        let written = buffer_chunk(self.desc, self.header, buf);
+        self.desc.pos += written as u64;
+        self.header.len += written as u64;
+        self.header.dirty = true;
+        Ok(written)
+    }
+}
+
+impl<'a, T: Write + Seek + 'a> io::Write for FsWriter<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.header.is_compressed() {
+            return self.write_compressed(buf);
+        }
+        let remaining_space = MAX_FILE_SIZE - self.header.len;
+        let max_write = cmp::min(buf.len(), remaining_space as usize);
+        let written = self.writer.write(&buf[..max_write])?;
+        self.desc.pos += written as u64;
+        self.header.len += written as u64;
+        self.header.dirty = true;
+        self.header.stored_len = self.header.len;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        store_chunk(self.writer, self.desc, self.header)?;
+        self.writer.flush()
+    }
+}
+
+struct FsReader<'a, T: 'a> {
+    desc: &'a mut OpenFile,
+    header: FileHeader,
+    reader: &'a mut T,
+}
+
+impl<'a, T: Read + Seek + 'a> FsReader<'a, T> {
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let mut stored = [0; CHUNK_HEADER_SIZE + CHUNK_SIZE];
+        self.reader
+            .seek(SeekFrom::Start(self.header.data + self.desc.stored_pos))?;
+        self.reader.read_exact(&mut stored[..CHUNK_HEADER_SIZE])?;
+        let len = stored_chunk_len(&stored)?;
+        self.reader
+            .read_exact(&mut stored[CHUNK_HEADER_SIZE..(CHUNK_HEADER_SIZE + len)])?;
+        load_chunk(self.desc, &stored[..(CHUNK_HEADER_SIZE + len)])
+    }
+
+    fn read_compressed(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.desc.pos >= self.header.len {
+            return Ok(0);
+        }
+        if self.desc.chunk_pos == self.desc.chunk_len {
+            if self.desc.stored_pos >= self.header.stored_len {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+        Ok(read_from_chunk(self.desc, &self.header, buf))
+    }
+}
+
+impl<'a, T: Read + Seek + 'a> io::Read for FsReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.header.is_compressed() {
+            return self.read_compressed(buf);
+        }
+        let remaining_data = self.header.len - self.desc.pos;
+        let max_read = cmp::min(buf.len(), remaining_data as usize);
+        let read = self.reader.read(&mut buf[..max_read])?;
+        self.desc.pos += read as u64;
+        Ok(read)
+    }
+}
+
+struct FileIterator<'a> {
+    headers: &'a [FileHeader],
+}
+
+impl<'a> Iterator for FileIterator<'a> {
+    type Item = Path;
+
+    fn next(&mut self) -> Option<Path> {
+        while let Some(header) = self.headers.first() {
+            self.headers = &self.headers[1..];
+            if header.exists && header.is_named() {
+                return Some(header.name);
+            }
+        }
+        None
+    }
+}
+
+fn find_files<'a>(
+    headers: &'a [FileHeader],
+    pattern: &'a [u8],
+) -> impl Iterator<Item = Path> + 'a {
+    FileIterator { headers }.filter(move |path| path.matches(pattern))
+}
+
+/// Collects `paths` in byte order, without keeping a borrow of them.
+fn sorted<I>(paths: I) -> iter::Take<array::IntoIter<Path, MAX_FILES>>
+where
+    I: Iterator<Item = Path>,
+{
+    let mut sorted = [path::EMPTY; MAX_FILES];
+    let mut len = 0;
+    for path in paths {
+        sorted[len] = path;
+        len += 1;
+    }
+    sorted[..len].sort_unstable_by(|a, b| a.as_slice().cmp(b.as_slice()));
+    IntoIterator::into_iter(sorted).take(len)
+}
+
+fn read_raw_header<T: Read + Seek>(storage: &mut T, index: u64) -> io::Result<[u8; HEADER_SIZE]> {
+    let mut buf = [0; HEADER_SIZE];
+    storage.seek(SeekFrom::Start(file_position(index)))?;
+    match storage.read_exact(&mut buf) {
+        Ok(()) => Ok(buf),
+        // slots past the end of a trimmed image hold no files
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok([0; HEADER_SIZE]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Decodes the header of slot `index`, as read from storage.
+fn parse_header(index: u64, buf: &[u8; HEADER_SIZE]) -> io::Result<FileHeader> {
+    let flags = u16::from_le_bytes([buf[1], buf[2]]);
+    // a header write torn by power loss can leave a mix of old and new
+    // length bytes, don't let that point outside of the slot
+    let stored_len = cmp::min(to_u64(&buf[11..19]), MAX_FILE_SIZE);
+    let len = if flags & FLAG_COMPRESSED != 0 {
+        to_u64(&buf[3..11])
+    } else {
+        cmp::min(to_u64(&buf[3..11]), stored_len)
+    };
+    // hard links keep the slot they point to in place of their length, a
+    // slot out of range is dropped by `count_links`
+    let (len, stored_len, linked) = if flags & FLAG_HARD_LINK != 0 {
+        let linked = cmp::min(to_u64(&buf[3..11]), MAX_FILES as u64) as usize;
+        (0, 0, linked)
+    } else if flags & FLAG_SYMLINK != 0 {
+        let len = cmp::min(len, path::MAX_PATH_LENGTH as u64);
+        (len, len, 0)
+    } else {
+        (len, stored_len, 0)
+    };
+    let name = Path::from_ascii_zero_padded(&buf[19..])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupt file name"))?;
+    Ok(FileHeader {
+        exists: buf[0] != 0,
+        // locks belong to descriptors of a previous mount, none of which
+        // are open anymore
+        locks: 0,
+        flags,
+        len,
+        stored_len,
+        name,
+        data: file_position(index) + (HEADER_SIZE + XATTR_SIZE) as u64,
+        dirty: false,
+        // read from file data once all headers are parsed
+        target: path::EMPTY,
+        linked,
+        links: 0,
+    })
+}
+
+/// Decodes the target of a symbolic link, as stored in its data. A corrupt
+/// target points nowhere.
+fn parse_target(buf: &[u8]) -> Path {
+    Path::from_ascii_str(buf).unwrap_or(path::EMPTY)
+}
+
+/// Counts hard links to every slot, dropping links to slots that hold no
+/// file data and files that lost their name along with all their links.
+fn count_links(headers: &mut [FileHeader]) {
+    for i in 0..headers.len() {
+        if !headers[i].exists || !headers[i].is_hard_link() {
+            continue;
+        }
+        let data = headers[i].linked;
+        let valid = data < headers.len()
+            && headers[data].exists
+            && !headers[data].is_hard_link()
+            && !headers[data].is_symlink()
+            && headers[data].links < u8::MAX;
+        if valid {
+            headers[data].links += 1;
+        } else {
+            headers[i].exists = false;
+            headers[i].dirty = true;
+        }
+    }
+    for header in headers.iter_mut() {
+        if header.exists && !header.is_named() && header.links == 0 {
+            header.exists = false;
+            header.dirty = true;
+        }
+    }
+}
+
+fn encode_header(header: &FileHeader) -> [u8; HEADER_SIZE] {
+    let mut buf = [0; HEADER_SIZE];
+    buf[0] = header.exists as u8;
+    buf[1..3].copy_from_slice(&header.flags.to_le_bytes());
+    if header.is_hard_link() {
+        from_u64(&mut buf[3..11], header.linked as u64);
+    } else {
+        from_u64(&mut buf[3..11], header.len);
+        from_u64(&mut buf[11..19], header.stored_len);
+    }
+    let path = header.name.as_slice();
+    buf[19..(19 + path.len())].copy_from_slice(path);
+    buf
+}
+
+// The functions below keep the bookkeeping of open files apart from storage
+// access, so that `AsyncFileSystem` shares it.
+
+/// Returns the slot named `name`, without following links.
+fn find_file(headers: &[FileHeader], name: Path) -> Option<usize> {
+    headers
+        .iter()
+        .position(|file| file.exists && file.is_named() && file.name == name)
+}
+
+enum Resolved {
+    /// Slot holding the file data.
+    Slot(usize),
+    /// No file, at the name the last symbolic link pointed to.
+    Missing(Path),
+}
+
+/// Follows symbolic and hard links from `path` to the slot holding the data.
+fn resolve(headers: &[FileHeader], mut path: Path) -> io::Result<Resolved> {
+    for _ in 0..=MAX_LINK_DEPTH {
+        let index = match find_file(headers, path) {
+            Some(index) => index,
+            None => return Ok(Resolved::Missing(path)),
+        };
+        if headers[index].is_symlink() {
+            path = headers[index].target;
+        } else {
+            return Ok(Resolved::Slot(data_slot(headers, index)));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::Other, "too many levels of links"))
+}
+
+/// Returns the slot holding the data of the file named in slot `index`.
+fn data_slot(headers: &[FileHeader], index: usize) -> usize {
+    if headers[index].is_hard_link() {
+        headers[index].linked
+    } else {
+        index
+    }
+}
+
+fn find_empty_slot(headers: &mut [FileHeader]) -> Option<(usize, &mut FileHeader)> {
+    // a removed hard link, or file data that one points to, stays reserved
+    // until the link is written to storage, so that a stale link can't come
+    // back pointing at a new file after a crash
+    let reserved = |index: usize| {
+        headers[index].is_hard_link() && headers[index].dirty
+            || headers.iter().any(|file| {
+                file.is_hard_link() && (file.exists || file.dirty) && file.linked == index
+            })
+    };
+    let index = (0..headers.len()).find(|&index| !headers[index].exists && !reserved(index))?;
+    let file = &mut headers[index];
+    *file = FileHeader {
+        data: file.data,
+        ..NON_EXISTING_FILE
+    };
+    Some((index, file))
+}
+
+fn alloc_descriptor(descriptors: &[OpenFile]) -> Option<usize> {
+    descriptors.iter().position(|desc| !desc.used)
+}
+
+fn open_file(
+    headers: &mut [FileHeader],
+    descriptors: &mut [OpenFile],
+    path: Path,
+) -> io::Result<Fd> {
+    let desc = match alloc_descriptor(descriptors) {
+        Some(index) => index,
+        None => return Err(io::Error::new(io::ErrorKind::Other, "cannot open: fd limit")),
+    };
+    if let Resolved::Slot(index) = resolve(headers, path)? {
+        let existing = &mut headers[index];
+        if existing.can_read() {
+            existing.lock_read();
+            descriptors[desc] = OpenFile {
+                used: true,
+                index,
+                writing: false,
+                ..UNUSED_FD
+            };
+            return Ok(Fd { index: desc });
+        } else {
+            return Err(io::Error::new(io::ErrorKind::Other, "cannot open: locked"));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "cannot open: no file"))
+}
+
+fn create_file(
+    headers: &mut [FileHeader],
+    descriptors: &mut [OpenFile],
+    path: Path,
+    options: &CreateOptions,
+) -> io::Result<Fd> {
+    let desc = match alloc_descriptor(descriptors) {
+        Some(index) => index,
+        None => return Err(io::Error::new(io::ErrorKind::Other, "cannot create")),
+    };
+    let path = match resolve(headers, path)? {
+        Resolved::Slot(index) => {
+            let existing = &mut headers[index];
+            if !existing.can_write() {
+                return Err(io::Error::new(io::ErrorKind::Other, "cannot create"));
+            }
+            existing.check_not_read_only()?;
+            existing.lock_write();
+            // a file recreated keeps its attributes and flags, and may only be
+            // reachable through hard links
+            let kept = FLAG_UNNAMED | FLAG_XATTRS | FLAG_USER;
+            existing.flags = options.flags | existing.flags & kept;
+            existing.len = 0;
+            existing.stored_len = 0;
+            existing.dirty = true;
+            descriptors[desc] = OpenFile {
+                used: true,
+                index,
+                writing: true,
+                ..UNUSED_FD
+            };
+            return Ok(Fd { index: desc });
+        }
+        Resolved::Missing(path) => path,
+    };
+    if let Some((index, existing)) = find_empty_slot(headers) {
+        existing.lock_write();
+        existing.exists = true;
+        existing.flags = options.flags;
+        existing.name = path;
+        existing.dirty = true;
+        descriptors[desc] = OpenFile {
+            used: true,
+            index,
+            writing: true,
+            ..UNUSED_FD
+        };
+        return Ok(Fd { index: desc });
+    }
+    Err(io::Error::new(io::ErrorKind::Other, "cannot create"))
+}
+
+fn append_file(
+    headers: &mut [FileHeader],
+    descriptors: &mut [OpenFile],
+    path: Path,
+) -> io::Result<Fd> {
+    let desc = match alloc_descriptor(descriptors) {
+        Some(index) => index,
+        None => return Err(io::Error::new(io::ErrorKind::Other, "cannot open: fd limit")),
+    };
+    let index = match resolve(headers, path)? {
+        Resolved::Slot(index) => index,
+        Resolved::Missing(_) => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "cannot open: no file"))
+        }
+    };
+    let existing = &mut headers[index];
+    existing.check_not_read_only()?;
+    if existing.is_compressed() {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot append to compressed file",
+        ))
+    } else if existing.can_write() {
+        existing.lock_write();
+        descriptors[desc] = OpenFile {
+            used: true,
+            index,
+            pos: existing.len,
+            writing: true,
+            ..UNUSED_FD
+        };
+        Ok(Fd { index: desc })
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, "cannot open: locked"))
+    }
+}
+
+fn truncate_file(headers: &mut [FileHeader], path: Path, len: u64) -> io::Result<()> {
+    let index = match resolve(headers, path)? {
+        Resolved::Slot(index) => index,
+        Resolved::Missing(_) => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "cannot truncate: no file"))
+        }
+    };
+    let existing = &mut headers[index];
+    existing.check_not_read_only()?;
+    if existing.is_compressed() {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot truncate compressed file",
+        ))
+    } else if !existing.can_write() {
+        Err(io::Error::new(io::ErrorKind::Other, "cannot truncate: locked"))
+    } else {
+        if len < existing.len {
+            existing.len = len;
+            existing.stored_len = len;
+            existing.dirty = true;
+        }
+        Ok(())
+    }
+}
+
+fn remove_file(headers: &mut [FileHeader], path: Path) -> io::Result<()> {
+    match find_file(headers, path) {
+        Some(index) => unlink(headers, index),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "cannot remove: no file")),
+    }
+}
+
+/// Removes the name in slot `index`, freeing the file data once nothing else
+/// refers to it.
+fn unlink(headers: &mut [FileHeader], index: usize) -> io::Result<()> {
+    let data = data_slot(headers, index);
+    if !headers[data].can_write() {
+        return Err(io::Error::new(io::ErrorKind::Other, "cannot remove: locked"));
+    }
+    headers[data].check_not_read_only()?;
+    if headers[index].is_hard_link() {
+        headers[data].links -= 1;
+        if !headers[data].is_named() && headers[data].links == 0 {
+            headers[data].exists = false;
+            headers[data].dirty = true;
+        }
+    } else if headers[index].links > 0 {
+        headers[index].flags |= FLAG_UNNAMED;
+        headers[index].dirty = true;
+        return Ok(());
+    }
+    headers[index].exists = false;
+    headers[index].dirty = true;
+    Ok(())
+}
+
+fn file_metadata(headers: &[FileHeader], path: Path) -> io::Result<Metadata> {
+    match resolve(headers, path)? {
+        Resolved::Slot(index) => Ok(Metadata {
+            len: headers[index].len,
+            stored_len: headers[index].stored_len,
+            compressed: headers[index].is_compressed(),
+            flags: FileFlags::from_header(headers[index].flags),
+        }),
+        Resolved::Missing(_) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "cannot stat: no file",
+        )),
+    }
+}
+
+fn set_file_flags(headers: &mut [FileHeader], path: Path, flags: FileFlags) -> io::Result<()> {
+    match resolve(headers, path)? {
+        Resolved::Slot(index) => {
+            let header = &mut headers[index];
+            header.flags = header.flags & !FLAG_USER | flags.to_header();
+            header.dirty = true;
+            Ok(())
+        }
+        Resolved::Missing(_) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "cannot set flags: no file",
+        )),
+    }
+}
+
+/// Releases `fd`, returning the slot of the file if it was open for writing.
+fn close_file(
+    headers: &mut [FileHeader],
+    descriptors: &mut [OpenFile],
+    fd: Fd,
+) -> Option<usize> {
+    let desc = &mut descriptors[fd.index];
+    debug_assert!(desc.used, "cannot close unused fd");
+    desc.used = false;
+    if desc.writing {
+        headers[desc.index].unlock_write();
+        Some(desc.index)
+    } else {
+        headers[desc.index].unlock_read();
+        None
+    }
+}
+
+/// A compressed chunk ready to be written to storage.
+struct StoredChunk {
+    /// Absolute storage position of the chunk.
+    pos: u64,
+    buf: [u8; CHUNK_HEADER_SIZE + CHUNK_SIZE],
+    len: usize,
+}
+
+impl StoredChunk {
+    fn stored(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Records the chunk as written.
+    fn commit(&self, desc: &mut OpenFile, header: &mut FileHeader) {
+        desc.unstored = false;
+        header.stored_len = desc.stored_pos + self.len as u64;
+        header.dirty = true;
+    }
+}
+
+/// Buffers data into the current chunk of a compressed file, starting a new
+/// chunk after the current one once it is full, which must have been stored
+/// by then. Returns how much of `buf` was taken, 0 if the slot is full.
+fn buffer_chunk(desc: &mut OpenFile, header: &FileHeader, buf: &[u8]) -> usize {
+    if desc.chunk_len == CHUNK_SIZE {
+        debug_assert!(!desc.unstored, "full chunk not stored");
+        desc.stored_pos = header.stored_len;
+        desc.chunk_len = 0;
+    }
+    // leave room for the chunk even if it ends up stored uncompressed
+    let used = desc.stored_pos + (CHUNK_HEADER_SIZE + desc.chunk_len) as u64;
+    let remaining_space = MAX_FILE_SIZE.saturating_sub(used);
+    let max_write = cmp::min(
+        cmp::min(buf.len(), CHUNK_SIZE - desc.chunk_len),
+        remaining_space as usize,
+    );
+    desc.chunk[desc.chunk_len..(desc.chunk_len + max_write)].copy_from_slice(&buf[..max_write]);
+    desc.chunk_len += max_write;
+    desc.unstored |= max_write > 0;
+    max_write
+}
+
+/// Compresses the current chunk of a compressed file.
+fn seal_chunk(desc: &OpenFile, header: &FileHeader) -> StoredChunk {
+    let data = &desc.chunk[..desc.chunk_len];
+    let mut stored = [0; CHUNK_HEADER_SIZE + CHUNK_SIZE];
+    // only keep compressed data if it is actually smaller
+    let limit = CHUNK_HEADER_SIZE + data.len().saturating_sub(1);
+    let (len, tag) = match lz4::compress(data, &mut stored[CHUNK_HEADER_SIZE..limit]) {
+        Some(len) => (len, len as u16),
+        None => {
+            stored[CHUNK_HEADER_SIZE..(CHUNK_HEADER_SIZE + data.len())].copy_from_slice(data);
+            (data.len(), data.len() as u16 | CHUNK_RAW)
+        }
+    };
+    stored[0] = tag as u8;
+    stored[1] = (tag >> 8) as u8;
+    stored[2] = data.len() as u8;
+    stored[3] = (data.len() >> 8) as u8;
+    StoredChunk {
+        pos: header.data + desc.stored_pos,
+        buf: stored,
+        len: CHUNK_HEADER_SIZE + len,
+    }
+}
+
+/// Writes the current chunk of `desc` to storage if it holds data not stored
+/// yet.
+fn store_chunk<T>(storage: &mut T, desc: &mut OpenFile, header: &mut FileHeader) -> io::Result<()>
+where
+    T: Write + Seek,
+{
+    if !desc.unstored {
+        return Ok(());
+    }
+    let chunk = seal_chunk(desc, header);
+    storage.seek(SeekFrom::Start(chunk.pos))?;
+    storage.write_all(chunk.stored())?;
+    chunk.commit(desc, header);
+    Ok(())
+}
+
+/// Returns the length of the chunk payload following `stored`, which starts
+/// with a chunk header.
+fn stored_chunk_len(stored: &[u8]) -> io::Result<usize> {
+    let tag = u16::from(stored[0]) | u16::from(stored[1]) << 8;
+    let data_len = u16::from(stored[2]) as usize | (stored[3] as usize) << 8;
+    let len = (tag & !CHUNK_RAW) as usize;
+    if len > CHUNK_SIZE || data_len > CHUNK_SIZE || data_len == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt chunk"));
+    }
+    Ok(len)
+}
+
+/// Decompresses a whole stored chunk, header included, into `desc`.
+fn load_chunk(desc: &mut OpenFile, stored: &[u8]) -> io::Result<()> {
+    let tag = u16::from(stored[0]) | u16::from(stored[1]) << 8;
+    let data_len = u16::from(stored[2]) as usize | (stored[3] as usize) << 8;
+    let payload = &stored[CHUNK_HEADER_SIZE..];
+    let decompressed = if tag & CHUNK_RAW != 0 {
+        desc.chunk[..payload.len()].copy_from_slice(payload);
+        payload.len()
+    } else {
+        lz4::decompress(payload, &mut desc.chunk)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupt chunk"))?
+    };
+    if decompressed != data_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt chunk"));
+    }
+    desc.stored_pos += stored.len() as u64;
+    desc.chunk_len = data_len;
+    desc.chunk_pos = 0;
+    Ok(())
+}
+
+/// Copies data out of the chunk loaded in `desc`.
+fn read_from_chunk(desc: &mut OpenFile, header: &FileHeader, buf: &mut [u8]) -> usize {
+    let remaining_data = cmp::min(
+        (desc.chunk_len - desc.chunk_pos) as u64,
+        header.len - desc.pos,
+    );
+    let max_read = cmp::min(buf.len(), remaining_data as usize);
+    buf[..max_read].copy_from_slice(&desc.chunk[desc.chunk_pos..(desc.chunk_pos + max_read)]);
+    desc.chunk_pos += max_read;
+    desc.pos += max_read as u64;
+    max_read
+}
+
+fn from_u64(buf: &mut [u8], value: u64) {
+    assert_eq!(buf.len(), 8);
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = (value >> (8 * i)) as u8;
+    }
+}
+
+fn to_u64(buf: &[u8]) -> u64 {
+    assert_eq!(buf.len(), 8);
+    let mut result = 0;
+    let mut mul = 1;
+    for &byte in buf {
+        result += mul * u64::from(byte);
+        mul = mul.wrapping_mul(256);
+    }
+    result
+}
+
+fn write_header<T>(storage: &mut T, index: u64, header: &FileHeader) -> io::Result<()>
+where
+    T: ReadWriteSeek,
+{
+    storage.seek(SeekFrom::Start(file_position(index)))?;
+    storage.write_all(&encode_header(header))
+}
+
+fn sync_header<T>(storage: &mut T, index: u64, header: &FileHeader) -> io::Result<()>
+where
+    T: ReadWriteSeek,
+{
+    write_header(storage, index, header)?;
+    storage.flush()
+}
+
+fn read_state<T: Read + Seek>(storage: &mut T) -> io::Result<u8> {
+    read_byte(storage, 0)
+}
+
+/// Reads the byte at `pos`, zero past the end of a trimmed image.
+fn read_byte<T: Read + Seek>(storage: &mut T, pos: u64) -> io::Result<u8> {
+    let mut byte = [0];
+    storage.seek(SeekFrom::Start(pos))?;
+    match storage.read_exact(&mut byte) {
+        Ok(()) => Ok(byte[0]),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Fails unless `storage` is laid out as `LAYOUT_VERSION` says, or is blank.
+fn check_layout<T: Read + Seek>(storage: &mut T) -> io::Result<()> {
+    if check_version(read_byte(storage, VERSION_OFFSET)?)? {
+        for slot in 0..LEGACY_SLOTS {
+            let mut buf = [0; LEGACY_HEADER_SIZE];
+            storage.seek(SeekFrom::Start(legacy_position(slot)))?;
+            io::read_full(storage, &mut buf)?;
+            if is_legacy_file(slot, &buf) {
+                return Err(legacy_layout());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the stored layout version, returning whether there is none, so
+/// that the storage must be checked for files in the layout without one.
+fn check_version(version: u8) -> io::Result<bool> {
+    match version {
+        LAYOUT_VERSION => Ok(false),
+        0 => Ok(true),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported layout version",
+        )),
+    }
+}
+
+/// Position of slot `slot` in the layouts without a version. The first
+/// `MAX_FILES` are those of the layout whose header took 18 bytes and the
+/// name, the rest those of the first layout, which had no superblock and a
+/// header of 10 bytes and the name.
+fn legacy_position(slot: usize) -> u64 {
+    let name = path::MAX_PATH_LENGTH as u64;
+    if slot < MAX_FILES {
+        SUPERBLOCK_SIZE + slot as u64 * (18 + name + MAX_FILE_SIZE)
+    } else {
+        (slot - MAX_FILES) as u64 * (10 + name + MAX_FILE_SIZE)
+    }
+}
+
+/// Returns whether the header read at `legacy_position(slot)` is that of an
+/// existing file. The first slot of the first layout overlaps the superblock,
+/// so its files are told apart by their name as well.
+fn is_legacy_file(slot: usize, buf: &[u8]) -> bool {
+    if slot < MAX_FILES {
+        buf[0] != 0
+    } else {
+        buf[0] == 1 && buf[10] != 0
+    }
+}
+
+fn legacy_layout() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "unsupported layout: files without extended attribute area",
+    )
+}
+
+fn parse_move(buf: &[u8]) -> Option<(usize, usize)> {
+    let (from, to) = (buf[1] as usize, buf[2] as usize);
+    if buf[0] == MOVE_PENDING && from < MAX_FILES && to < MAX_FILES && from != to {
+        Some((from, to))
+    } else {
+        None
+    }
+}
+
+fn read_move<T: Read + Seek>(storage: &mut T) -> io::Result<Option<(usize, usize)>> {
+    let mut buf = [0; MOVE_SIZE];
+    storage.seek(SeekFrom::Start(MOVE_OFFSET))?;
+    match storage.read_exact(&mut buf) {
+        Ok(()) => Ok(parse_move(&buf)),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_move<T: ReadWriteSeek>(storage: &mut T, moved: Option<(usize, usize)>) -> io::Result<()> {
+    let buf = match moved {
+        Some((from, to)) => [MOVE_PENDING, from as u8, to as u8],
+        None => [0; MOVE_SIZE],
+    };
+    storage.seek(SeekFrom::Start(MOVE_OFFSET))?;
+    storage.write_all(&buf)
+}
+
+/// Drops the old slot of a move cut short after the file was made to exist
+/// in the new one, which both slots then hold. Runs before links are counted.
+fn finish_move(headers: &mut [FileHeader], from: usize, to: usize) {
+    let (old, new) = (&headers[from], &headers[to]);
+    if old.exists && new.exists && old.name == new.name && old.flags == new.flags {
+        headers[from].exists = false;
+        headers[from].dirty = true;
+    }
+}
+
+fn write_exists<T: ReadWriteSeek>(storage: &mut T, index: usize, exists: bool) -> io::Result<()> {
+    storage.seek(SeekFrom::Start(file_position(index as u64)))?;
+    storage.write_all(&[exists as u8])
+}
+
+fn write_version<T: ReadWriteSeek>(storage: &mut T) -> io::Result<()> {
+    storage.seek(SeekFrom::Start(VERSION_OFFSET))?;
+    storage.write_all(&[LAYOUT_VERSION])
+}
+
+fn write_state<T: ReadWriteSeek>(storage: &mut T, state: u8) -> io::Result<()> {
+    storage.seek(SeekFrom::Start(0))?;
+    storage.write_all(&[state])
+}
+
+pub(crate) fn file_position(index: u64) -> u64 {
+    SUPERBLOCK_SIZE + index * FILE_RAW_SIZE
+}
+
+pub fn format_storage<T: ReadWriteSeek>(storage: &mut T, len: u64) -> io::Result<()> {
+    if len < FS_SIZE {
+        panic!(
+            "backing storage too small: is {}, should be at least {}",
+            len, FS_SIZE,
+        );
+    }
+    write_state(storage, STATE_CLEAN)?;
+    write_version(storage)?;
+    for file in 0..MAX_FILES {
+        storage.seek(SeekFrom::Start(file_position(file as u64)))?;
+        // just clear `exists` flag, leave everything else as-is
+        storage.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+/// Inconsistency found by `check_storage`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// Storage was modified and not unmounted afterwards, so writes may have
+    /// been cut short.
+    NotUnmounted,
+    /// Stored length does not fit in the slot, usually a torn header write.
+    /// Mounting clamps it to `MAX_FILE_SIZE`.
+    LengthTooLarge { slot: usize, len: u64 },
+    /// File has the same name as the one in `original` slot, so it can never
+    /// be opened.
+    DuplicateName { slot: usize, original: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::NotUnmounted => write!(f, "filesystem was not unmounted cleanly"),
+            Problem::LengthTooLarge { slot, len } => {
+                write!(f, "slot {}: length {} is too large", slot, len)
+            }
+            Problem::DuplicateName { slot, original } => {
+                write!(f, "slot {}: same name as slot {}", slot, original)
+            }
+        }
+    }
+}
+
+/// Checks file headers in `storage` and calls `report` for every problem
+/// found. Storage is not modified. Fails on storage of another layout, as
+/// mounting does.
+pub fn check_storage<T, F>(storage: &mut T, mut report: F) -> io::Result<()>
+where
+    T: Read + Seek,
+    F: FnMut(Problem),
+{
+    check_layout(storage)?;
+    if read_state(storage)? != STATE_CLEAN {
+        report(Problem::NotUnmounted);
+    }
+    let mut names = [None; MAX_FILES];
+    for slot in 0..MAX_FILES {
+        let buf = read_raw_header(storage, slot as u64)?;
+        if buf[0] == 0 {
+            continue;
+        }
+        let len = to_u64(&buf[11..19]);
+        if len > MAX_FILE_SIZE {
+            report(Problem::LengthTooLarge { slot, len });
+        }
+        // a file whose name was removed does not clash with anything
+        if u16::from_le_bytes([buf[1], buf[2]]) & FLAG_UNNAMED != 0 {
+            continue;
+        }
+        let name = Path::from_ascii_zero_padded(&buf[19..]);
+        if let Some(original) = names.iter().position(|n| name.is_some() && *n == name) {
+            report(Problem::DuplicateName { slot, original });
+        }
+        names[slot] = name;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use testing::fault::FaultyStorage;
+
+    #[test]
+    fn smoke() {
+        let mut storage = empty_backing_storage();
+        let _fs = FileSystem::new(&mut storage).expect("failed to create fs");
+    }
+
+    #[test]
+    fn create() {
+        let mut storage = empty_backing_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        fs.create(path).expect("failed to create");
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mut storage = empty_backing_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        {
+            let fd = {
+                let fd = fs.create(path).expect("failed to create");
+                let mut writer = fs.get_writer(&fd).expect("failed to get writer");
+                writer.write_all(&[1, 2, 3, 4]).expect("failed to write");
+                fd
+            };
+            fs.close(fd).expect("failed to close");
+        }
+        let fd = fs.open_read(path).expect("failed to open");
+        let mut reader = fs.get_reader(&fd).expect("failed to get reader");
+        let mut buf = [0; 5];
+        let bytes = reader.read(&mut buf).expect("failed to read");
+        assert_eq!(bytes, 4, "should have read 4 bytes");
+        assert_eq!(buf, [1, 2, 3, 4, 0], "should have read written bytes");
+    }
+
+    #[test]
+    fn list_files() {
+        let mut storage = empty_backing_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        let path1 = Path::from_ascii_str(b"foo.txt").unwrap();
+        fs.create(path1).expect("failed to create file");
+        let path2 = Path::from_ascii_str(b"bar.txt").unwrap();
+        fs.create(path2).expect("failed to create file");
+        let files = fs.list_files().collect::<Vec<_>>();
+        assert_eq!(files.len(), 2, "should be 2 files");
+        assert!(files.iter().any(|p| *p == path1));
+        assert!(files.iter().any(|p| *p == path2));
+    }
+
+    #[test]
+    fn find() {
+        let mut storage = empty_backing_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        for name in &["b.log", "cfg", "a.log", "c.log.1"] {
+            let fd = fs
+                .create(Path::from_ascii_str(name.as_bytes()).unwrap())
+                .expect("failed to create file");
+            fs.close(fd).expect("failed to close");
+        }
+        let names = |paths: &mut dyn Iterator<Item = Path>| {
+            paths.map(|p| p.as_slice().to_vec()).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&mut fs.find(b"*.log")), [&b"b.log"[..], b"a.log"]);
+        assert_eq!(names(&mut fs.find_sorted(b"*.log")), [&b"a.log"[..], b"b.log"]);
+        assert_eq!(
+            names(&mut fs.find_sorted(b"*")),
+            [&b"a.log"[..], b"b.log", b"c.log.1", b"cfg"]
+        );
+        assert_eq!(names(&mut fs.find(b"[ab].*")), [&b"b.log"[..], b"a.log"]);
+        assert_eq!(fs.find(b"*.txt").count(), 0);
+    }
+
+    #[test]
+    fn remove() {
+        let mut storage = empty_backing_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        let fd = fs.create(path).expect("failed to create file");
+        assert!(fs.remove(path).is_err(), "should not remove open file");
+        fs.close(fd).expect("failed to close");
+        fs.remove(path).expect("failed to remove");
+        assert_eq!(fs.list_files().count(), 0, "should be no files");
+        assert!(fs.open_read(path).is_err(), "should not open removed file");
+    }
+
+    #[test]
+    fn check_duplicates() {
+        let mut storage = empty_backing_storage();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let path1 = Path::from_ascii_str(b"foo.txt").unwrap();
+            let path2 = Path::from_ascii_str(b"bar.txt").unwrap();
+            fs.create(path1).expect("failed to create file");
+            fs.create(path2).expect("failed to create file");
+            fs.headers[1].name = path1;
+            fs.headers[1].stored_len = MAX_FILE_SIZE + 1;
+            fs.force_unmount().expect("failed to unmount");
+        }
+        let mut problems = Vec::new();
+        check_storage(&mut storage, |p| problems.push(p)).expect("failed to check");
+        assert_eq!(
+            problems,
+            [
+                Problem::LengthTooLarge {
+                    slot: 1,
+                    len: MAX_FILE_SIZE + 1,
+                },
+                Problem::DuplicateName {
+                    slot: 1,
+                    original: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mount_trimmed_slice() {
+        let mut storage = empty_backing_storage();
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        let used = {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let fd = fs.create(path).expect("failed to create file");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[1, 2, 3, 4])
+                .expect("failed to write");
+            fs.close(fd).expect("failed to close");
+            fs.flush_to_storage().expect("failed to flush");
+            fs.storage_used()
+        };
+        assert_eq!(used, SUPERBLOCK_SIZE + (HEADER_SIZE + XATTR_SIZE) as u64 + 4);
+        let mut image = io::Cursor::new(&storage.get_ref()[..used as usize]);
+        let mut fs = FileSystem::new(&mut image).expect("failed to mount");
+        assert_eq!(fs.list_files().collect::<Vec<_>>(), [path]);
+        let fd = fs.open_read(path).expect("failed to open");
+        let mut buf = [0; 5];
+        let bytes = fs
+            .get_reader(&fd)
+            .expect("failed to get reader")
+            .read(&mut buf)
+            .expect("failed to read");
+        assert_eq!(&buf[..bytes], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn read_only_mount() {
+        let mut storage = empty_backing_storage();
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let fd = fs.create(path).expect("failed to create file");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[1, 2, 3])
+                .expect("failed to write");
+            fs.close(fd).expect("failed to close");
+            fs.flush_to_storage().expect("failed to flush");
+        }
+        let before = storage.get_ref().clone();
+        {
+            let mut fs = FileSystem::mount_read_only(&mut storage).expect("failed to mount");
+            assert!(fs.is_read_only());
+            let other = Path::from_ascii_str(b"bar.txt").unwrap();
+            let kind = |r: io::Result<()>| r.unwrap_err().kind();
+            assert_eq!(kind(fs.create(other).map(|_| ())), io::ErrorKind::ReadOnly);
+            assert_eq!(kind(fs.create(path).map(|_| ())), io::ErrorKind::ReadOnly);
+            assert_eq!(kind(fs.remove(path)), io::ErrorKind::ReadOnly);
+            assert_eq!(kind(fs.flush_to_storage()), io::ErrorKind::ReadOnly);
+            assert_eq!(read_all(&mut fs, path), [1, 2, 3]);
+        }
+        assert!(storage.get_ref() == &before, "storage was modified");
+
+        // storage that cannot be written at all mounts the same way
+        let mut rom = io::Cursor::new(&before[..]);
+        let mut fs = FileSystem::mount_read_only(&mut rom).expect("failed to mount");
+        assert_eq!(read_all(&mut fs, path), [1, 2, 3]);
+    }
+
+    #[test]
+    fn unmount() {
+        let mut storage = empty_backing_storage();
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            assert!(fs.was_unmounted_cleanly(), "empty storage should be clean");
+            let fd = fs.create(path).expect("failed to create file");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[1, 2, 3])
+                .expect("failed to write");
+            // dropped without unmounting, as if power was lost
+        }
+        let mut problems = Vec::new();
+        check_storage(&mut storage, |p| problems.push(p)).expect("failed to check");
+        assert_eq!(problems, [Problem::NotUnmounted]);
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            assert!(!fs.was_unmounted_cleanly());
+            let fd = fs.create(path).expect("failed to create file");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[4, 5])
+                .expect("failed to write");
+            assert_eq!(
+                fs.unmount().err().map(|e| e.kind()),
+                Some(io::ErrorKind::Other),
+                "should not unmount with open files"
+            );
+        }
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            let fd = fs.create(path).expect("failed to create file");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[6, 7])
+                .expect("failed to write");
+            fs.close(fd).expect("failed to close");
+            let storage = fs.unmount().expect("failed to unmount");
+            storage.seek(SeekFrom::Start(0)).expect("storage still usable");
+        }
+        check_storage(&mut storage, |p| panic!("unexpected problem: {}", p))
+            .expect("failed to check");
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        assert!(fs.was_unmounted_cleanly());
+        assert_eq!(read_all(&mut fs, path), [6, 7]);
+    }
+
+    #[test]
+    fn force_unmount_closes_files() {
+        let mut storage = empty_backing_storage();
+        let path = Path::from_ascii_str(b"log.txt").unwrap();
+        let packed = Path::from_ascii_str(b"log.lz4").unwrap();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let fd = fs.create(path).expect("failed to create file");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[1, 2])
+                .expect("failed to write");
+            let options = CreateOptions::new().compressed(true);
+            let fd = fs.create_with(packed, &options).expect("failed to create");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[3, 4])
+                .expect("failed to write");
+            fs.open_read(path).expect_err("should be locked");
+            fs.force_unmount().expect("failed to unmount");
+        }
+        check_storage(&mut storage, |p| panic!("unexpected problem: {}", p))
+            .expect("failed to check");
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        assert!(fs.was_unmounted_cleanly());
+        assert_eq!(read_all(&mut fs, path), [1, 2]);
+        assert_eq!(read_all(&mut fs, packed), [3, 4]);
+    }
+
+    #[test]
+    fn read_only_mount_keeps_state() {
+        let mut storage = empty_backing_storage();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            fs.flush_to_storage().expect("failed to flush");
+        }
+        let fs = FileSystem::mount_read_only(&mut storage).expect("failed to mount");
+        assert!(!fs.was_unmounted_cleanly());
+        fs.force_unmount().expect("failed to unmount");
+        let fs = FileSystem::new(&mut storage).expect("failed to mount");
+        assert!(!fs.was_unmounted_cleanly(), "read-only unmount should not mark clean");
+    }
+
+    #[test]
+    fn flush_writes_only_changed_headers() {
+        let mut storage = FaultyStorage::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        let fd = fs.create(path).expect("failed to create file");
+        fs.close(fd).expect("failed to close");
+        fs.flush_to_storage().expect("failed to flush");
+        let written = fs.inner_mut().bytes_written();
+        // superblock state and layout version, and one header
+        assert_eq!(written, 2 + HEADER_SIZE as u64);
+        fs.flush_to_storage().expect("failed to flush");
+        assert_eq!(fs.inner_mut().bytes_written(), written);
+        fs.remove(path).expect("failed to remove");
+        fs.flush_to_storage().expect("failed to flush");
+        assert_eq!(fs.inner_mut().bytes_written(), written + HEADER_SIZE as u64);
+    }
+
+    #[test]
+    fn sync_single_file() {
+        let mut storage = empty_backing_storage();
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        let other = Path::from_ascii_str(b"bar.txt").unwrap();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let fd = fs.create(path).expect("failed to create file");
+            let unsynced = fs.create(other).expect("failed to create file");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[1, 2, 3])
+                .expect("failed to write");
+            fs.sync(&fd).expect("failed to sync");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[4])
+                .expect("failed to write");
+            fs.close(unsynced).expect("failed to close");
+        }
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        assert_eq!(fs.list_files().collect::<Vec<_>>(), [path]);
+        assert_eq!(read_all(&mut fs, path), [1, 2, 3]);
+    }
+
+    #[test]
+    fn flush_on_close() {
+        let mut storage = empty_backing_storage();
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            fs.set_flush_on_close(true);
+            let fd = fs.create(path).expect("failed to create file");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[1, 2, 3])
+                .expect("failed to write");
+            fs.close(fd).expect("failed to close");
+            // power is lost right after close returns
+        }
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        assert_eq!(fs.list_files().collect::<Vec<_>>(), [path]);
+        assert_eq!(read_all(&mut fs, path), [1, 2, 3]);
+    }
+
+    #[test]
+    fn append_and_truncate() {
+        let mut storage = empty_backing_storage();
+        let path = Path::from_ascii_str(b"log.txt").unwrap();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let fd = fs.create(path).expect("failed to create file");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[1, 2, 3])
+                .expect("failed to write");
+            fs.close(fd).expect("failed to close");
+            fs.unmount().expect("failed to unmount");
+        }
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let fd = fs.open_append(path).expect("failed to open");
+        assert!(fs.truncate(path, 0).is_err(), "should not truncate open file");
+        fs.get_writer(&fd)
+            .expect("failed to get writer")
+            .write_all(&[4, 5])
+            .expect("failed to write");
+        fs.close(fd).expect("failed to close");
+        assert_eq!(read_all(&mut fs, path), [1, 2, 3, 4, 5]);
+        fs.truncate(path, 4).expect("failed to truncate");
+        fs.truncate(path, 10).expect("failed to truncate");
+        assert_eq!(read_all(&mut fs, path), [1, 2, 3, 4]);
+
+        let compressed = Path::from_ascii_str(b"log.lz4").unwrap();
+        let options = CreateOptions::new().compressed(true);
+        let fd = fs.create_with(compressed, &options).expect("failed to create");
+        fs.close(fd).expect("failed to close");
+        let kind = fs.open_append(compressed).map(|_| ()).unwrap_err().kind();
+        assert_eq!(kind, io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn symlinks() {
+        let mut storage = empty_backing_storage();
+        let current = Path::from_ascii_str(b"current.cfg").unwrap();
+        let v1 = Path::from_ascii_str(b"cfg.v1").unwrap();
+        let v2 = Path::from_ascii_str(b"cfg.v2").unwrap();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            fs.symlink(v1, current).expect("failed to link");
+            // creating through a link creates its target
+            let fd = fs.create(current).expect("failed to create");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[1])
+                .expect("failed to write");
+            fs.close(fd).expect("failed to close");
+            assert_eq!(read_all(&mut fs, v1), [1]);
+            let fd = fs.create(v2).expect("failed to create");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[2, 2])
+                .expect("failed to write");
+            fs.close(fd).expect("failed to close");
+
+            fs.remove(current).expect("failed to remove link");
+            assert_eq!(read_all(&mut fs, v1), [1], "target should stay");
+            fs.symlink(v2, current).expect("failed to link");
+            fs.unmount().expect("failed to unmount");
+        }
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        assert_eq!(fs.read_link(current).expect("failed to read link"), v2);
+        assert_eq!(fs.metadata(current).expect("failed to stat").len(), 2);
+        assert_eq!(read_all(&mut fs, current), [2, 2]);
+        assert_eq!(fs.list_files().count(), 3);
+    }
+
+    #[test]
+    fn symlink_loop() {
+        let mut storage = empty_backing_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        let a = Path::from_ascii_str(b"a").unwrap();
+        let b = Path::from_ascii_str(b"b").unwrap();
+        fs.symlink(b, a).expect("failed to link");
+        fs.symlink(a, b).expect("failed to link");
+        assert!(fs.open_read(a).is_err(), "should not open a loop");
+        assert!(fs.create(a).is_err(), "should not create through a loop");
+        assert!(fs.symlink(b, a).is_err(), "should not replace a link");
+    }
+
+    #[test]
+    fn hard_links() {
+        let mut storage = empty_backing_storage();
+        let original = Path::from_ascii_str(b"original").unwrap();
+        let link = Path::from_ascii_str(b"link").unwrap();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let fd = fs.create(original).expect("failed to create");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(&[1, 2, 3])
+                .expect("failed to write");
+            fs.close(fd).expect("failed to close");
+            fs.hard_link(original, link).expect("failed to link");
+            fs.remove(original).expect("failed to remove");
+            assert!(fs.open_read(original).is_err(), "name should be gone");
+            assert_eq!(fs.list_files().collect::<Vec<_>>(), [link]);
+            // the old slot still holds the data and can't take a new file
+            let fd = fs.create(original).expect("failed to create");
+            fs.close(fd).expect("failed to close");
+            assert_eq!(read_all(&mut fs, link), [1, 2, 3]);
+            fs.unmount().expect("failed to unmount");
+        }
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            assert_eq!(read_all(&mut fs, link), [1, 2, 3]);
+            assert_eq!(read_all(&mut fs, original), []);
+            fs.remove(link).expect("failed to remove");
+            assert_eq!(fs.headers.iter().filter(|h| h.exists).count(), 1);
+            fs.unmount().expect("failed to unmount");
+        }
+        let mut problems = Vec::new();
+        check_storage(&mut storage, |p| problems.push(p)).expect("failed to check");
+        assert_eq!(problems, []);
+    }
+
+    fn read_all<T: Read + Seek>(fs: &mut FileSystem<T>, path: Path) -> Vec<u8> {
+        let fd = fs.open_read(path).expect("failed to open");
+        let mut data = Vec::new();
+        {
+            let mut reader = fs.get_reader(&fd).expect("failed to get reader");
+            let mut buf = [0; 100];
+            loop {
+                let read = reader.read(&mut buf).expect("failed to read");
+                if read == 0 {
+                    break;
+                }
+                data.extend_from_slice(&buf[..read]);
+            }
+        }
+        fs.close_read(fd).expect("failed to close");
+        data
+    }
+
+    #[test]
+    fn compressed_write_and_read() {
+        let mut storage = empty_backing_storage();
+        let path = Path::from_ascii_str(b"log.txt").unwrap();
+        let mut expected = Vec::new();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let options = CreateOptions::new().compressed(true);
+            let fd = fs.create_with(path, &options).expect("failed to create");
+            {
+                let mut writer = fs.get_writer(&fd).expect("failed to get writer");
+                // small writes that keep rewriting a partial chunk
+                for i in 0..200 {
+                    let line = if i % 7 == 0 { &b"[warn] tock\n"[..] } else { b"[info] tick\n" };
+                    writer.write_all(line).expect("failed to write");
+                    expected.extend_from_slice(line);
+                }
+                // and some incompressible data
+                let mut x = 1u32;
+                for _ in 0..1000 {
+                    x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    expected.push((x >> 16) as u8);
+                }
+                writer.write_all(&expected[2400..]).expect("failed to write");
+            }
+            fs.close(fd).expect("failed to close");
+            fs.flush_to_storage().expect("failed to flush");
+            assert_eq!(read_all(&mut fs, path), expected);
+        }
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let meta = fs.metadata(path).expect("failed to stat");
+        assert!(meta.is_compressed());
+        assert_eq!(meta.len(), expected.len() as u64);
+        assert!(meta.stored_len() < meta.len());
+        assert_eq!(read_all(&mut fs, path), expected);
+    }
+
+    #[test]
+    fn compressed_small_writes_are_buffered() {
+        let mut storage = FaultyStorage::new(vec![0; FS_SIZE as usize]);
+        let path = Path::from_ascii_str(b"log.txt").unwrap();
+        let expected = (0..300).map(|i| b"tick"[i % 4]).collect::<Vec<_>>();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let options = CreateOptions::new().compressed(true);
+            let fd = fs.create_with(path, &options).expect("failed to create");
+            for &byte in &expected {
+                fs.get_writer(&fd)
+                    .expect("failed to get writer")
+                    .write_all(&[byte])
+                    .expect("failed to write");
+            }
+            // the superblock and the first chunk, stored once it was full
+            let chunk = (CHUNK_HEADER_SIZE + CHUNK_SIZE) as u64;
+            let written = fs.inner_mut().bytes_written();
+            assert!(written < chunk + 2, "wrote {} bytes", written);
+            // the rest is stored on close
+            fs.close(fd).expect("failed to close");
+            assert!(fs.inner_mut().bytes_written() > written);
+            assert_eq!(read_all(&mut fs, path), expected);
+            fs.unmount().expect("failed to unmount");
+        }
+        let mut image = storage.into_image();
+        let mut fs = FileSystem::new(&mut image).expect("failed to mount");
+        assert_eq!(read_all(&mut fs, path), expected);
+    }
+
+    #[test]
+    fn closed_compressed_file_frees_descriptor() {
+        let mut storage = empty_backing_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        let path = Path::from_ascii_str(b"short").unwrap();
+        let options = CreateOptions::new().compressed(true);
+        let fd = fs.create_with(path, &options).expect("failed to create");
+        fs.get_writer(&fd)
+            .expect("failed to get writer")
+            .write_all(b"short")
+            .expect("failed to write");
+        fs.close(fd).expect("failed to close");
+        for _ in 0..MAX_DESCRIPTORS {
+            fs.open_read(path).expect("failed to open");
+        }
+    }
+
+    #[test]
+    fn compressed_file_larger_than_slot() {
+        let mut storage = empty_backing_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        let path = Path::from_ascii_str(b"zeros").unwrap();
+        let options = CreateOptions::new().compressed(true);
+        let fd = fs.create_with(path, &options).expect("failed to create");
+        let len = 2 * MAX_FILE_SIZE as usize;
+        {
+            let mut writer = fs.get_writer(&fd).expect("failed to get writer");
+            let buf = [0; 4096];
+            for _ in 0..(len / buf.len()) {
+                writer.write_all(&buf).expect("failed to write");
+            }
+        }
+        fs.close(fd).expect("failed to close");
+        let meta = fs.metadata(path).expect("failed to stat");
+        assert_eq!(meta.len(), len as u64);
+        assert!(meta.stored_len() <= MAX_FILE_SIZE);
+        let data = read_all(&mut fs, path);
+        assert_eq!(data.len(), len);
+        assert!(data.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn compressed_file_full() {
+        let mut storage = empty_backing_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+        let path = Path::from_ascii_str(b"noise").unwrap();
+        let options = CreateOptions::new().compressed(true);
+        let fd = fs.create_with(path, &options).expect("failed to create");
+        let mut writer = fs.get_writer(&fd).expect("failed to get writer");
+        let mut x = 1u32;
+        let mut written = 0u64;
+        loop {
+            let mut buf = [0; 100];
+            for byte in buf.iter_mut() {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                *byte = (x >> 16) as u8;
+            }
+            match writer.write(&buf).expect("failed to write") {
+                0 => break,
+                n => written += n as u64,
+            }
+        }
+        // incompressible chunks are stored as is, so only chunk headers are lost
+        let chunks = MAX_FILE_SIZE / (CHUNK_HEADER_SIZE + CHUNK_SIZE) as u64;
+        assert!(written < MAX_FILE_SIZE);
+        assert!(written >= (chunks - 1) * CHUNK_SIZE as u64);
+    }
+
+    #[test]
+    fn layout_version() {
+        let path = Path::from_ascii_str(b"foo.txt").unwrap();
+        // blank storage gets the version along with the first change
+        let mut storage = empty_backing_storage();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let fd = fs.create(path).expect("failed to create");
+            fs.close(fd).expect("failed to close");
+            fs.unmount().expect("failed to unmount");
+        }
+        assert_eq!(storage.get_ref()[VERSION_OFFSET as usize], LAYOUT_VERSION);
+        let fs = FileSystem::new(&mut storage).expect("failed to mount");
+        assert!(fs.metadata(path).is_ok());
+
+        // a file in a slot of the layout before the version
+        let mut legacy = empty_backing_storage();
+        legacy.get_mut()[legacy_position(3) as usize] = 1;
+        let err = FileSystem::new(&mut legacy).err().expect("mounted old layout");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(check_storage(&mut legacy, |_| ()).is_err());
+
+        // files written by the first layout, without a superblock
+        let mut first = empty_backing_storage();
+        for &(pos, bytes) in FIRST_LAYOUT_IMAGE {
+            first.get_mut()[pos..(pos + bytes.len())].copy_from_slice(bytes);
+        }
+        let err = FileSystem::new(&mut first).err().expect("mounted first layout");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(check_storage(&mut first, |_| ()).is_err());
+
+        storage.get_mut()[VERSION_OFFSET as usize] = LAYOUT_VERSION + 1;
+        let err = FileSystem::new(&mut storage).err().expect("mounted unknown layout");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn move_record_is_cleared_on_mount() {
+        let path = Path::from_ascii_str(b"moved.txt").unwrap();
+        let mut storage = io::Cursor::new(moved_file_image());
+        {
+            let mut fs = FileSystem::mount_read_only(&mut storage).expect("failed to mount");
+            assert_eq!(fs.list_files().collect::<Vec<_>>(), [path]);
+            assert_eq!(read_all(&mut fs, path), b"contents");
+        }
+        assert_eq!(storage.get_ref()[1..4], [MOVE_PENDING, 0, 2]);
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            assert_eq!(fs.list_files().count(), 1);
+            // the first change takes the slot the file was moved out of, and
+            // power is lost before its header is written
+            let new = Path::from_ascii_str(b"new").unwrap();
+            let fd = fs.create(new).expect("failed to create");
+            fs.close(fd).expect("failed to close");
+        }
+        assert_eq!(storage.get_ref()[1..4], [0; MOVE_SIZE]);
+        assert_eq!(storage.get_ref()[file_position(0) as usize], 0);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        assert_eq!(fs.list_files().collect::<Vec<_>>(), [path]);
+        assert_eq!(read_all(&mut fs, path), b"contents");
+    }
+
+    /// Image with `moved.txt` in slot 0 and slot 2, as left by a power loss
+    /// while the file was moved from one to the other.
+    pub(super) fn moved_file_image() -> Vec<u8> {
+        let mut storage = empty_backing_storage();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to create fs");
+            let fd = fs
+                .create(Path::from_ascii_str(b"moved.txt").unwrap())
+                .expect("failed to create");
+            fs.get_writer(&fd)
+                .expect("failed to get writer")
+                .write_all(b"contents")
+                .expect("failed to write");
+            fs.close(fd).expect("failed to close");
+            fs.unmount().expect("failed to unmount");
+        }
+        let mut image = storage.into_inner();
+        let (from, to) = (file_position(0) as usize, file_position(2) as usize);
+        let slot = image[from..(from + FILE_RAW_SIZE as usize)].to_vec();
+        image[to..(to + slot.len())].copy_from_slice(&slot);
+        image[0] = STATE_MOUNTED;
+        image[1..4].copy_from_slice(&[MOVE_PENDING, 0, 2]);
+        image
+    }
+
+    /// Non-zero bytes of an image written by the first layout, with files
+    /// `boot.cfg` and `log` created and flushed. Its writer lost their data.
+    pub(super) const FIRST_LAYOUT_IMAGE: &[(usize, &[u8])] = &[
+        (0, &[1, 0, 6]),
+        (10, b"boot.cfg"),
+        (1_048_606, &[1, 0, 2]),
+        (1_048_616, b"log"),
+    ];
+
+    fn empty_backing_storage() -> io::Cursor<Vec<u8>> {
+        io::Cursor::new(vec![0; FS_SIZE as usize])
+    }
+}
@@ -0,0 +1,40 @@
+//! `FileSystem` operations on snapshots kept by `SnapshotStorage`.
+
+use super::*;
+use snapshot::SnapshotStorage;
+
+impl<'a, S: ReadWriteSeek + 'a> FileSystem<'a, SnapshotStorage<S>> {
+    /// Flushes headers and takes a snapshot of the whole filesystem. Data
+    /// written to files still open is included as far as it got.
+    pub fn snapshot(&mut self, name: Path) -> io::Result<()> {
+        self.flush_to_storage()?;
+        self.storage.snapshot(name)
+    }
+
+    /// Returns names of all snapshots, oldest first.
+    pub fn list_snapshots<'b>(&'b self) -> impl Iterator<Item = Path> + 'b {
+        self.storage.snapshots()
+    }
+
+    /// Brings every file back to how it was when snapshot `name` was taken,
+    /// deleting all later snapshots. Fails if any file is open.
+    pub fn rollback(&mut self, name: Path) -> io::Result<()> {
+        self.check_writable()?;
+        if self.descriptors.iter().any(|desc| desc.used) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cannot rollback: files open",
+            ));
+        }
+        self.storage.rollback(name)?;
+        self.load_headers()?;
+        // the superblock was rolled back too
+        self.marked = false;
+        Ok(())
+    }
+
+    pub fn delete_snapshot(&mut self, name: Path) -> io::Result<()> {
+        self.check_writable()?;
+        self.storage.delete_snapshot(name)
+    }
+}
@@ -0,0 +1,302 @@
+//! Conversion between host directory trees and filesystem images.
+//!
+//! Files in nested directories are stored under their relative path with `/`
+//! as the separator, so `assets/logo.bmp` becomes a single file named
+//! `assets/logo.bmp`.
+//!
+//! To embed a directory in firmware, call `embed_dir` from a build script and
+//! mount the result read-only:
+//!
+//! ```ignore
+//! // build.rs
+//! spark_fs::image::embed_dir("assets", "assets.img").unwrap();
+//!
+//! // main.rs
+//! static ASSETS: &[u8] = include_image!("assets.img");
+//! let mut storage = spark_fs::io::Cursor::new(ASSETS);
+//! let mut fs = spark_fs::FileSystem::mount_read_only(&mut storage)?;
+//! ```
+
+use io::{self, Cursor, Read, Write};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path as HostPath, PathBuf};
+use std::string::String;
+use std::vec::Vec;
+use {format_storage, FileSystem, Path, FS_SIZE, MAX_FILES, MAX_FILE_SIZE, MAX_PATH_LENGTH};
+
+#[derive(Debug)]
+pub enum ImageError {
+    /// Accessing a host file or directory failed.
+    Host {
+        path: PathBuf,
+        err: ::std::io::Error,
+    },
+    /// Filesystem operation on the image failed.
+    Fs(io::Error),
+    /// Relative path of a host file does not fit in `MAX_PATH_LENGTH`.
+    NameTooLong { path: PathBuf },
+    /// Host file is larger than `MAX_FILE_SIZE`.
+    FileTooLarge { path: PathBuf, len: u64 },
+    /// Directory has more than `MAX_FILES` files.
+    TooManyFiles,
+    /// Name cannot be represented on the other side, for example a non-UTF-8
+    /// host name or an image name that would escape the target directory.
+    InvalidName { name: String },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageError::Host { ref path, ref err } => write!(f, "{}: {}", path.display(), err),
+            ImageError::Fs(ref err) => write!(f, "{}", err),
+            ImageError::NameTooLong { ref path } => write!(
+                f,
+                "{}: name is longer than {} bytes",
+                path.display(),
+                MAX_PATH_LENGTH,
+            ),
+            ImageError::FileTooLarge { ref path, len } => write!(
+                f,
+                "{}: file is {} bytes, at most {} are allowed",
+                path.display(),
+                len,
+                MAX_FILE_SIZE,
+            ),
+            ImageError::TooManyFiles => write!(f, "more than {} files", MAX_FILES),
+            ImageError::InvalidName { ref name } => write!(f, "invalid file name: {:?}", name),
+        }
+    }
+}
+
+impl ::std::error::Error for ImageError {}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> Self {
+        ImageError::Fs(err)
+    }
+}
+
+fn host_err(path: &HostPath) -> impl FnOnce(::std::io::Error) -> ImageError {
+    let path = path.to_path_buf();
+    move |err| ImageError::Host { path, err }
+}
+
+/// Collects files under `dir` as (relative name, host path) pairs, sorted by
+/// name so that images are reproducible.
+fn collect_files(
+    root: &HostPath,
+    dir: &HostPath,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), ImageError> {
+    for entry in fs::read_dir(dir).map_err(host_err(dir))? {
+        let path = entry.map_err(host_err(dir))?.path();
+        let file_type = fs::metadata(&path).map_err(host_err(&path))?.file_type();
+        if file_type.is_dir() {
+            collect_files(root, &path, files)?;
+            continue;
+        }
+        let relative = path.strip_prefix(root).expect("file outside of root");
+        let mut name = String::new();
+        for component in relative.components() {
+            let part = component
+                .as_os_str()
+                .to_str()
+                .ok_or_else(|| ImageError::InvalidName {
+                    name: relative.to_string_lossy().into_owned(),
+                })?;
+            if !name.is_empty() {
+                name.push('/');
+            }
+            name.push_str(part);
+        }
+        files.push((name, path));
+    }
+    files.sort();
+    Ok(())
+}
+
+/// Builds a freshly formatted image of `FS_SIZE` bytes containing every file
+/// under `dir`.
+pub fn pack(dir: &HostPath) -> Result<Vec<u8>, ImageError> {
+    let mut files = Vec::new();
+    collect_files(dir, dir, &mut files)?;
+    if files.len() > MAX_FILES {
+        return Err(ImageError::TooManyFiles);
+    }
+    let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+    format_storage(&mut storage, FS_SIZE)?;
+    {
+        let mut fs = FileSystem::new(&mut storage)?;
+        for (name, host_path) in files {
+            let path = match Path::from_ascii_str(name.as_bytes()) {
+                Some(path) => path,
+                None => return Err(ImageError::NameTooLong { path: host_path }),
+            };
+            let data = fs::read(&host_path).map_err(host_err(&host_path))?;
+            if data.len() as u64 > MAX_FILE_SIZE {
+                return Err(ImageError::FileTooLarge {
+                    path: host_path,
+                    len: data.len() as u64,
+                });
+            }
+            let fd = fs.create(path)?;
+            fs.get_writer(&fd)?.write_all(&data)?;
+            fs.close(fd)?;
+        }
+        fs.unmount()?;
+    }
+    Ok(storage.into_inner())
+}
+
+/// Writes every file in `image` to `dir`, creating directories for names
+/// containing `/`.
+pub fn unpack(image: &[u8], dir: &HostPath) -> Result<(), ImageError> {
+    let mut storage = Cursor::new(image);
+    let mut fs = FileSystem::mount_read_only(&mut storage)?;
+    let files = fs.list_files().collect::<Vec<_>>();
+    for path in files {
+        let name = String::from_utf8_lossy(path.as_slice()).into_owned();
+        let relative = PathBuf::from(&name);
+        let is_plain = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if name.is_empty() || !is_plain || name.as_bytes() != path.as_slice() {
+            return Err(ImageError::InvalidName { name });
+        }
+        let host_path = dir.join(relative);
+        if let Some(parent) = host_path.parent() {
+            fs::create_dir_all(parent).map_err(host_err(parent))?;
+        }
+        let fd = fs.open_read(path)?;
+        let mut data = Vec::new();
+        {
+            let mut reader = fs.get_reader(&fd)?;
+            let mut buf = [0; 4096];
+            loop {
+                let read = reader.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                data.extend_from_slice(&buf[..read]);
+            }
+        }
+        fs.close_read(fd)?;
+        fs::write(&host_path, data).map_err(host_err(&host_path))?;
+    }
+    Ok(())
+}
+
+/// Cuts `image` down to the part that holds files. The result can still be
+/// mounted for reading, for example straight from a `&'static [u8]`.
+pub fn trim(image: &mut Vec<u8>) -> Result<(), ImageError> {
+    let used = {
+        let mut storage = Cursor::new(&image[..]);
+        FileSystem::mount_read_only(&mut storage)?.storage_used()
+    };
+    image.truncate(used as usize);
+    Ok(())
+}
+
+/// Build script helper: packs `dir` into a trimmed image saved as `name` in
+/// `OUT_DIR`, ready for `include_image!`. Cargo is told to rerun the build
+/// script whenever something in `dir` changes.
+pub fn embed_dir<P: AsRef<HostPath>>(dir: P, name: &str) -> Result<(), ImageError> {
+    let dir = dir.as_ref();
+    let out_dir = env::var_os("OUT_DIR").expect("embed_dir must be called from a build script");
+    let mut image = pack(dir)?;
+    trim(&mut image)?;
+    let out = HostPath::new(&out_dir).join(name);
+    fs::write(&out, image).map_err(host_err(&out))?;
+    println!("cargo:rerun-if-changed={}", dir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_dir() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "spark-fs-image-{}-{}",
+            ::std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst),
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        let src = temp_dir();
+        fs::write(src.join("config.json"), b"{}").unwrap();
+        fs::create_dir(src.join("assets")).unwrap();
+        fs::write(src.join("assets").join("logo.bmp"), [1, 2, 3]).unwrap();
+        let image = pack(&src).expect("failed to pack");
+        assert_eq!(image.len() as u64, FS_SIZE);
+
+        let dst = temp_dir();
+        unpack(&image, &dst).expect("failed to unpack");
+        assert_eq!(fs::read(dst.join("config.json")).unwrap(), b"{}");
+        assert_eq!(
+            fs::read(dst.join("assets").join("logo.bmp")).unwrap(),
+            [1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn trimmed_image() {
+        let src = temp_dir();
+        fs::write(src.join("a"), b"first").unwrap();
+        fs::write(src.join("b"), b"second").unwrap();
+        let mut image = pack(&src).expect("failed to pack");
+        trim(&mut image).expect("failed to trim");
+        assert!((image.len() as u64) < 2 * MAX_FILE_SIZE);
+
+        let dst = temp_dir();
+        unpack(&image, &dst).expect("failed to unpack");
+        assert_eq!(fs::read(dst.join("a")).unwrap(), b"first");
+        assert_eq!(fs::read(dst.join("b")).unwrap(), b"second");
+    }
+
+    #[test]
+    fn name_too_long() {
+        let src = temp_dir();
+        fs::write(src.join("a-very-long-file-name.txt"), b"").unwrap();
+        match pack(&src) {
+            Err(ImageError::NameTooLong { .. }) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn file_too_large() {
+        let src = temp_dir();
+        fs::write(src.join("big"), vec![0; MAX_FILE_SIZE as usize + 1]).unwrap();
+        match pack(&src) {
+            Err(ImageError::FileTooLarge { len, .. }) => assert_eq!(len, MAX_FILE_SIZE + 1),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_escaping_names() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        {
+            let mut fs = FileSystem::new(&mut storage).unwrap();
+            let fd = fs
+                .create(Path::from_ascii_str(b"../evil").unwrap())
+                .unwrap();
+            fs.close(fd).unwrap();
+            fs.unmount().unwrap();
+        }
+        match unpack(storage.get_ref(), &temp_dir()) {
+            Err(ImageError::InvalidName { name }) => assert_eq!(name, "../evil"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
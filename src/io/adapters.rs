@@ -0,0 +1,252 @@
+use core::cmp;
+use io::{BufRead, Read, Result};
+
+#[cfg(any(test, feature = "alloc"))]
+use alloc::string::String;
+
+pub(crate) fn take<T>(inner: T, limit: u64) -> Take<T> {
+    Take { inner, limit }
+}
+
+pub(crate) fn chain<A, B>(first: A, second: B) -> Chain<A, B> {
+    Chain {
+        first,
+        second,
+        done_first: false,
+    }
+}
+
+pub(crate) fn bytes<R>(inner: R) -> Bytes<R> {
+    Bytes { inner }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+pub(crate) fn lines<B>(inner: B) -> Lines<B> {
+    Lines { inner }
+}
+
+/// Reader adapter returned by `Read::take`.
+pub struct Take<T> {
+    inner: T,
+    limit: u64,
+}
+
+impl<T> Take<T> {
+    /// Number of bytes that can still be read.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn set_limit(&mut self, limit: u64) {
+        self.limit = limit;
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Read> Read for Take<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.limit == 0 {
+            return Ok(0);
+        }
+        let max = cmp::min(buf.len() as u64, self.limit) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        self.limit -= n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: BufRead> BufRead for Take<T> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.limit == 0 {
+            return Ok(&[]);
+        }
+        let buf = self.inner.fill_buf()?;
+        let len = cmp::min(buf.len() as u64, self.limit) as usize;
+        Ok(&buf[..len])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = cmp::min(amt as u64, self.limit) as usize;
+        self.limit -= amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+/// Reader adapter returned by `Read::chain`.
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+    done_first: bool,
+}
+
+impl<A, B> Chain<A, B> {
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+
+    pub fn get_ref(&self) -> (&A, &B) {
+        (&self.first, &self.second)
+    }
+
+    pub fn get_mut(&mut self) -> (&mut A, &mut B) {
+        (&mut self.first, &mut self.second)
+    }
+}
+
+impl<A: Read, B: Read> Read for Chain<A, B> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.done_first {
+            match self.first.read(buf)? {
+                0 if !buf.is_empty() => self.done_first = true,
+                n => return Ok(n),
+            }
+        }
+        self.second.read(buf)
+    }
+}
+
+impl<A: BufRead, B: BufRead> BufRead for Chain<A, B> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if !self.done_first {
+            // checked separately, returning the borrow from the first
+            // reader directly would keep it borrowed on the empty path too
+            if self.first.fill_buf()?.is_empty() {
+                self.done_first = true;
+            } else {
+                return self.first.fill_buf();
+            }
+        }
+        self.second.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if !self.done_first {
+            self.first.consume(amt)
+        } else {
+            self.second.consume(amt)
+        }
+    }
+}
+
+/// Iterator returned by `Read::bytes`.
+pub struct Bytes<R> {
+    inner: R,
+}
+
+impl<R: Read> Iterator for Bytes<R> {
+    type Item = Result<u8>;
+
+    fn next(&mut self) -> Option<Result<u8>> {
+        let mut byte = [0];
+        match self.inner.read(&mut byte) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(byte[0])),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Iterator returned by `BufRead::lines`.
+#[cfg(any(test, feature = "alloc"))]
+pub struct Lines<B> {
+    inner: B,
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl<B: BufRead> Iterator for Lines<B> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        let mut line = String::new();
+        match self.inner.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                Some(Ok(line))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use io::{BufRead, BufReader, ErrorKind, Read};
+    use std::string::String;
+    use std::vec::Vec;
+
+    #[test]
+    fn take() {
+        let mut reader = (&b"hello world"[..]).take(5);
+        let mut out = Vec::new();
+        assert_eq!(reader.read_to_end(&mut out).unwrap(), 5);
+        assert_eq!(out, b"hello");
+        assert_eq!(reader.limit(), 0);
+        reader.set_limit(3);
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b" wo");
+        assert_eq!(reader.into_inner(), b"rld");
+    }
+
+    #[test]
+    fn take_buf_read() {
+        let mut reader = (&b"one\ntwo\n"[..]).take(6);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "one\n");
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "tw");
+        assert!(reader.fill_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn chain() {
+        let mut reader = (&b"abc"[..]).chain(&b"def"[..]);
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"def");
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn chain_lines() {
+        let reader = (&b"first\nsec"[..]).chain(&b"ond\r\nthird"[..]);
+        let lines = reader.lines().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(lines, ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn bytes() {
+        let mut buf = [0; 4];
+        let reader = BufReader::new(&b"bytes"[..], &mut buf);
+        let bytes = reader.bytes().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(bytes, b"bytes");
+    }
+
+    #[test]
+    fn lines_invalid_utf8() {
+        let mut lines = (&b"ok\n\xff\n"[..]).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "ok");
+        let err = lines.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
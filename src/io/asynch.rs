@@ -0,0 +1,235 @@
+//! Non-blocking versions of `Read`, `Write` and `Seek`, for storage whose
+//! transfers complete in the background, such as DMA driven flash.
+//!
+//! The traits are poll based and only use `core::task`, so they work with any
+//! executor. Returning `Poll::Pending` means the operation is in progress:
+//! the implementation arranges for the task to be woken, and the caller
+//! repeats the same call, with the same arguments, once it is.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{ready, Context, Poll};
+use io::{Error, ErrorKind, Result, SeekFrom};
+
+pub trait AsyncRead {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize>>;
+
+    fn read<'b>(&'b mut self, buf: &'b mut [u8]) -> ReadFuture<'b, Self> {
+        ReadFuture { reader: self, buf }
+    }
+
+    fn read_exact<'b>(&'b mut self, buf: &'b mut [u8]) -> ReadExactFuture<'b, Self> {
+        ReadExactFuture {
+            reader: self,
+            buf,
+            done: 0,
+        }
+    }
+}
+
+pub trait AsyncWrite {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize>>;
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<Result<()>>;
+
+    fn write<'b>(&'b mut self, buf: &'b [u8]) -> WriteFuture<'b, Self> {
+        WriteFuture { writer: self, buf }
+    }
+
+    fn write_all<'b>(&'b mut self, buf: &'b [u8]) -> WriteAllFuture<'b, Self> {
+        WriteAllFuture {
+            writer: self,
+            buf,
+            done: 0,
+        }
+    }
+
+    fn flush(&mut self) -> FlushFuture<'_, Self> {
+        FlushFuture { writer: self }
+    }
+}
+
+pub trait AsyncSeek {
+    fn poll_seek(&mut self, cx: &mut Context, pos: SeekFrom) -> Poll<Result<u64>>;
+
+    fn seek(&mut self, pos: SeekFrom) -> SeekFuture<'_, Self> {
+        SeekFuture { seeker: self, pos }
+    }
+}
+
+pub trait AsyncReadWriteSeek: AsyncRead + AsyncWrite + AsyncSeek {}
+
+impl<T: AsyncRead + AsyncWrite + AsyncSeek> AsyncReadWriteSeek for T {}
+
+impl<'a, T: ?Sized + AsyncRead + 'a> AsyncRead for &'a mut T {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize>> {
+        (**self).poll_read(cx, buf)
+    }
+}
+
+impl<'a, T: ?Sized + AsyncWrite + 'a> AsyncWrite for &'a mut T {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize>> {
+        (**self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        (**self).poll_flush(cx)
+    }
+}
+
+impl<'a, T: ?Sized + AsyncSeek + 'a> AsyncSeek for &'a mut T {
+    fn poll_seek(&mut self, cx: &mut Context, pos: SeekFrom) -> Poll<Result<u64>> {
+        (**self).poll_seek(cx, pos)
+    }
+}
+
+/// Future returned by `AsyncRead::read`.
+pub struct ReadFuture<'b, R: ?Sized + 'b> {
+    reader: &'b mut R,
+    buf: &'b mut [u8],
+}
+
+impl<'b, R: ?Sized + AsyncRead + 'b> Future for ReadFuture<'b, R> {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        this.reader.poll_read(cx, this.buf)
+    }
+}
+
+/// Future returned by `AsyncRead::read_exact`.
+pub struct ReadExactFuture<'b, R: ?Sized + 'b> {
+    reader: &'b mut R,
+    buf: &'b mut [u8],
+    done: usize,
+}
+
+impl<'b, R: ?Sized + AsyncRead + 'b> Future for ReadExactFuture<'b, R> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while this.done < this.buf.len() {
+            match ready!(this.reader.poll_read(cx, &mut this.buf[this.done..]))? {
+                0 => {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "failed to read exact",
+                    )))
+                }
+                n => this.done += n,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future returned by `AsyncWrite::write`.
+pub struct WriteFuture<'b, W: ?Sized + 'b> {
+    writer: &'b mut W,
+    buf: &'b [u8],
+}
+
+impl<'b, W: ?Sized + AsyncWrite + 'b> Future for WriteFuture<'b, W> {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        this.writer.poll_write(cx, this.buf)
+    }
+}
+
+/// Future returned by `AsyncWrite::write_all`.
+pub struct WriteAllFuture<'b, W: ?Sized + 'b> {
+    writer: &'b mut W,
+    buf: &'b [u8],
+    done: usize,
+}
+
+impl<'b, W: ?Sized + AsyncWrite + 'b> Future for WriteAllFuture<'b, W> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while this.done < this.buf.len() {
+            match ready!(this.writer.poll_write(cx, &this.buf[this.done..]))? {
+                0 => {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    )))
+                }
+                n => this.done += n,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future returned by `AsyncWrite::flush`.
+pub struct FlushFuture<'b, W: ?Sized + 'b> {
+    writer: &'b mut W,
+}
+
+impl<'b, W: ?Sized + AsyncWrite + 'b> Future for FlushFuture<'b, W> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.get_mut().writer.poll_flush(cx)
+    }
+}
+
+/// Future returned by `AsyncSeek::seek`.
+pub struct SeekFuture<'b, S: ?Sized + 'b> {
+    seeker: &'b mut S,
+    pos: SeekFrom,
+}
+
+impl<'b, S: ?Sized + AsyncSeek + 'b> Future for SeekFuture<'b, S> {
+    type Output = Result<u64>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<u64>> {
+        let this = self.get_mut();
+        this.seeker.poll_seek(cx, this.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::Cursor;
+    use testing::asynch::{block_on, Stuttering};
+
+    #[test]
+    fn read_exact_and_write_all() {
+        let mut storage = Stuttering::new(Cursor::new([0u8; 64]));
+        let data = (0..40).collect::<::std::vec::Vec<u8>>();
+        block_on(storage.seek(SeekFrom::Start(10))).unwrap();
+        block_on(storage.write_all(&data)).unwrap();
+        block_on(storage.flush()).unwrap();
+        assert_eq!(block_on(storage.seek(SeekFrom::Current(-20))).unwrap(), 30);
+        let mut buf = [0; 20];
+        block_on(storage.read_exact(&mut buf)).unwrap();
+        assert_eq!(&buf[..], &data[20..]);
+        assert!(storage.polls() > 10);
+    }
+
+    #[test]
+    fn read_exact_past_end() {
+        let mut storage = Stuttering::new(Cursor::new([1u8; 8]));
+        let mut buf = [0; 4];
+        assert_eq!(block_on(storage.read(&mut buf)).unwrap(), 4);
+        let mut buf = [0; 5];
+        let err = block_on(storage.read_exact(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn write_all_to_full_storage() {
+        let mut storage = Stuttering::new(Cursor::new([0u8; 8]));
+        let err = block_on(storage.write_all(&[1; 12])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+        assert_eq!(block_on(storage.write(&[1])).unwrap(), 0);
+    }
+}
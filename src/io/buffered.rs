@@ -0,0 +1,341 @@
+use core::cmp;
+use io::{BufRead, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+
+/// Adds buffering to a reader, using a caller provided buffer so that it
+/// works without an allocator.
+pub struct BufReader<'b, R> {
+    inner: R,
+    buf: &'b mut [u8],
+    pos: usize,
+    filled: usize,
+}
+
+impl<'b, R> BufReader<'b, R> {
+    pub fn new(inner: R, buf: &'b mut [u8]) -> Self {
+        BufReader {
+            inner,
+            buf,
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the underlying reader. Buffered data is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Data read from the inner reader but not yet consumed.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    fn discard_buffer(&mut self) {
+        self.pos = 0;
+        self.filled = 0;
+    }
+}
+
+impl<'b, R: Read> Read for BufReader<'b, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos == self.filled {
+            // nothing buffered and a large read, buffering would only copy
+            if buf.len() >= self.buf.len() {
+                return self.inner.read(buf);
+            }
+            self.filled = self.inner.read(self.buf)?;
+            self.pos = 0;
+        }
+        let len = cmp::min(buf.len(), self.filled - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..(self.pos + len)]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl<'b, R: Read> BufRead for BufReader<'b, R> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.pos == self.filled {
+            self.filled = self.inner.read(self.buf)?;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.filled);
+    }
+}
+
+impl<'b, R: Seek> Seek for BufReader<'b, R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        // the inner reader is ahead by whatever is still buffered
+        let remaining = (self.filled - self.pos) as i64;
+        let pos = match pos {
+            SeekFrom::Current(n) => match n.checked_sub(remaining) {
+                Some(n) => SeekFrom::Current(n),
+                None => return Err(Error::new(ErrorKind::InvalidInput, "seek offset overflow")),
+            },
+            other => other,
+        };
+        self.discard_buffer();
+        self.inner.seek(pos)
+    }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        // unlike seeking, keeps the buffer
+        let remaining = (self.filled - self.pos) as u64;
+        self.inner.stream_position().map(|pos| pos - remaining)
+    }
+}
+
+/// Adds buffering to a writer, using a caller provided buffer so that it
+/// works without an allocator.
+///
+/// Buffered data is written out on `flush`, on seeks and, ignoring errors,
+/// when the writer is dropped.
+pub struct BufWriter<'b, W: Write> {
+    // only `None` once taken by `into_inner`
+    inner: Option<W>,
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b, W: Write> BufWriter<'b, W> {
+    pub fn new(inner: W, buf: &'b mut [u8]) -> Self {
+        BufWriter {
+            inner: Some(inner),
+            buf,
+            len: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().expect("writer taken")
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().expect("writer taken")
+    }
+
+    /// Writes out buffered data and returns the underlying writer.
+    pub fn into_inner(mut self) -> Result<W> {
+        self.flush_buf()?;
+        Ok(self.inner.take().expect("writer taken"))
+    }
+
+    /// Data written but not yet passed to the inner writer.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn flush_buf(&mut self) -> Result<()> {
+        let mut written = 0;
+        let mut result = Ok(());
+        {
+            let inner = self.inner.as_mut().expect("writer taken");
+            while written < self.len {
+                match inner.write(&self.buf[written..self.len]) {
+                    Ok(0) => {
+                        result = Err(Error::new(
+                            ErrorKind::WriteZero,
+                            "failed to write buffered data",
+                        ));
+                        break;
+                    }
+                    Ok(n) => written += n,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+        }
+        // keep whatever could not be written for the next attempt
+        self.buf.copy_within(written..self.len, 0);
+        self.len -= written;
+        result
+    }
+}
+
+impl<'b, W: Write> Write for BufWriter<'b, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.len + buf.len() > self.buf.len() {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.buf.len() {
+            self.get_mut().write(buf)
+        } else {
+            self.buf[self.len..(self.len + buf.len())].copy_from_slice(buf);
+            self.len += buf.len();
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flush_buf()?;
+        self.get_mut().flush()
+    }
+}
+
+impl<'b, W: Write + Seek> Seek for BufWriter<'b, W> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.flush_buf()?;
+        self.get_mut().seek(pos)
+    }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        let buffered = self.len as u64;
+        self.get_mut().stream_position().map(|pos| pos + buffered)
+    }
+}
+
+impl<'b, W: Write> Drop for BufWriter<'b, W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.flush_buf();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::Cursor;
+    use std::vec::Vec;
+
+    /// Counts calls, to check that small operations are batched.
+    struct Counting<T> {
+        inner: T,
+        calls: usize,
+    }
+
+    impl<T: Read> Read for Counting<T> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.calls += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    impl<T: Write> Write for Counting<T> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.calls += 1;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl<T: Seek> Seek for Counting<T> {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    fn counting<T>(inner: T) -> Counting<T> {
+        Counting { inner, calls: 0 }
+    }
+
+    #[test]
+    fn buf_reader() {
+        let data = (0..100).collect::<Vec<u8>>();
+        let mut buf = [0; 16];
+        let mut reader = BufReader::new(counting(&data[..]), &mut buf);
+        let mut out = Vec::new();
+        let mut byte = [0];
+        while reader.read(&mut byte).unwrap() == 1 {
+            out.push(byte[0]);
+        }
+        assert_eq!(out, data);
+        // seven full buffers and the final empty read
+        assert_eq!(reader.get_ref().calls, 8);
+    }
+
+    #[test]
+    fn buf_reader_large_reads_bypass_buffer() {
+        let data = (0..100).collect::<Vec<u8>>();
+        let mut buf = [0; 16];
+        let mut reader = BufReader::new(&data[..], &mut buf);
+        let mut small = [0; 3];
+        reader.read_exact(&mut small).unwrap();
+        assert_eq!(reader.buffer(), &data[3..16]);
+        let mut large = [0; 40];
+        // drains the buffer first
+        assert_eq!(reader.read(&mut large).unwrap(), 13);
+        assert_eq!(reader.read(&mut large).unwrap(), 40);
+        assert_eq!(&large[..], &data[16..56]);
+        assert!(reader.buffer().is_empty());
+    }
+
+    #[test]
+    fn buf_reader_seek() {
+        let data = (0..100).collect::<Vec<u8>>();
+        let mut buf = [0; 16];
+        let mut reader = BufReader::new(Cursor::new(&data[..]), &mut buf);
+        let mut byte = [0];
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(reader.stream_position().unwrap(), 1);
+        assert_eq!(reader.buffer().len(), 15);
+        assert_eq!(reader.seek(SeekFrom::Current(0)).unwrap(), 1);
+        assert_eq!(reader.seek(SeekFrom::Current(4)).unwrap(), 5);
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(byte, [5]);
+        reader.seek(SeekFrom::End(-1)).unwrap();
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(byte, [99]);
+    }
+
+    #[test]
+    fn buf_writer() {
+        let mut storage = [0; 64];
+        let mut buf = [0; 16];
+        {
+            let mut writer = BufWriter::new(counting(&mut storage[..]), &mut buf);
+            for i in 0..40 {
+                writer.write_all(&[i]).unwrap();
+            }
+            assert_eq!(writer.get_ref().calls, 2);
+            assert_eq!(writer.buffer().len(), 8);
+            writer.write_all(&[100; 20]).unwrap();
+            assert_eq!(writer.get_ref().calls, 4);
+            writer.write_all(&[200; 2]).unwrap();
+        }
+        let mut expected = (0..40).collect::<Vec<u8>>();
+        expected.extend_from_slice(&[100; 20]);
+        expected.extend_from_slice(&[200; 2]);
+        assert_eq!(&storage[..62], &expected[..]);
+    }
+
+    #[test]
+    fn buf_writer_seek_and_into_inner() {
+        let mut buf = [0; 16];
+        let mut writer = BufWriter::new(Cursor::new([0u8; 8]), &mut buf);
+        writer.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(writer.stream_position().unwrap(), 3);
+        assert_eq!(writer.buffer(), [1, 2, 3]);
+        writer.seek(SeekFrom::Start(6)).unwrap();
+        writer.write_all(&[4, 5]).unwrap();
+        let cursor = writer.into_inner().unwrap();
+        assert_eq!(cursor.into_inner(), [1, 2, 3, 0, 0, 0, 4, 5]);
+    }
+
+    #[test]
+    fn buf_writer_keeps_unwritten_data() {
+        let mut storage = [0; 4];
+        let mut buf = [0; 8];
+        let mut writer = BufWriter::new(&mut storage[..], &mut buf);
+        writer.write_all(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(writer.flush().unwrap_err().kind(), ErrorKind::WriteZero);
+        assert_eq!(writer.buffer(), [5, 6]);
+    }
+}
@@ -1,5 +1,9 @@
+#[cfg(any(test, feature = "alloc"))]
+use alloc::vec::Vec;
 use core::cmp;
-use io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+#[cfg(any(test, feature = "alloc"))]
+use core::convert::TryFrom;
+use io::{BufRead, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
 
 #[derive(Clone, Debug)]
 pub struct Cursor<T> {
@@ -11,7 +15,7 @@ impl<T> Cursor<T> {
     pub fn new(inner: T) -> Cursor<T> {
         Cursor {
             pos: 0,
-            inner: inner,
+            inner,
         }
     }
 
@@ -65,6 +69,10 @@ where
             )),
         }
     }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        Ok(self.pos)
+    }
 }
 
 impl<T> Read for Cursor<T>
@@ -72,14 +80,14 @@ where
     T: AsRef<[u8]>,
 {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let n = Read::read(&mut self.fill_buf(), buf)?;
+        let n = Read::read(&mut self.remaining_slice(), buf)?;
         self.pos += n as u64;
         Ok(n)
     }
 
     fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
         let n = buf.len();
-        Read::read_exact(&mut self.fill_buf(), buf)?;
+        Read::read_exact(&mut self.remaining_slice(), buf)?;
         self.pos += n as u64;
         Ok(())
     }
@@ -99,16 +107,72 @@ where
     }
 }
 
+/// Vector that a `Cursor` extends on write, zero filling any gap, as std's
+/// `Cursor<Vec<u8>>` does. A `Cursor` over a plain vector writes in place
+/// like over any other buffer.
+#[cfg(any(test, feature = "alloc"))]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Growing<V>(pub V);
+
+#[cfg(any(test, feature = "alloc"))]
+impl AsRef<[u8]> for Growing<Vec<u8>> {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl AsRef<[u8]> for Growing<&mut Vec<u8>> {
+    fn as_ref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl Write for Cursor<Growing<Vec<u8>>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        vec_write(&mut self.pos, &mut self.inner.0, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl Write for Cursor<Growing<&mut Vec<u8>>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        vec_write(&mut self.pos, self.inner.0, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
 impl<T> Cursor<T>
 where
     T: AsRef<[u8]>,
 {
-    fn fill_buf(&mut self) -> &[u8] {
+    fn remaining_slice(&self) -> &[u8] {
         let amt = cmp::min(self.pos, self.inner.as_ref().len() as u64);
         &self.inner.as_ref()[(amt as usize)..]
     }
 }
 
+impl<T> BufRead for Cursor<T>
+where
+    T: AsRef<[u8]>,
+{
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        Ok(self.remaining_slice())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
 // Non-resizing write implementation
 fn slice_write(pos_mut: &mut u64, slice: &mut [u8], buf: &[u8]) -> Result<usize> {
     let pos = cmp::min(*pos_mut, slice.len() as u64);
@@ -116,3 +180,83 @@ fn slice_write(pos_mut: &mut u64, slice: &mut [u8], buf: &[u8]) -> Result<usize>
     *pos_mut += amt as u64;
     Ok(amt)
 }
+
+// Resizing write implementation, zero fills when writing past the end
+#[cfg(any(test, feature = "alloc"))]
+fn vec_write(pos_mut: &mut u64, vec: &mut Vec<u8>, buf: &[u8]) -> Result<usize> {
+    let pos = usize::try_from(*pos_mut).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "cursor position exceeds maximum possible vector length",
+        )
+    })?;
+    if vec.len() < pos {
+        vec.resize(pos, 0);
+    }
+    let overlap = cmp::min(vec.len() - pos, buf.len());
+    vec[pos..(pos + overlap)].copy_from_slice(&buf[..overlap]);
+    vec.extend_from_slice(&buf[overlap..]);
+    *pos_mut = (pos + buf.len()) as u64;
+    Ok(buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn slice_write_does_not_grow() {
+        let mut cursor = Cursor::new([0u8; 4]);
+        assert_eq!(cursor.write(&[1, 2, 3, 4, 5]).unwrap(), 4);
+        assert_eq!(cursor.write(&[6]).unwrap(), 0);
+        assert_eq!(cursor.into_inner(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn borrowed_array_write_does_not_grow() {
+        let mut buf = [0u8; 2];
+        let mut cursor = Cursor::new(&mut buf);
+        assert_eq!(cursor.write(&[1, 2, 3]).unwrap(), 2);
+        assert_eq!(buf, [1, 2]);
+    }
+
+    #[test]
+    fn vec_write_does_not_grow() {
+        let mut cursor = Cursor::new(vec![0u8; 2]);
+        assert_eq!(cursor.write(&[1, 2, 3]).unwrap(), 2);
+        assert_eq!(cursor.into_inner(), [1, 2]);
+    }
+
+    #[test]
+    fn growing_vec_write_grows() {
+        let mut cursor = Cursor::new(Growing(Vec::new()));
+        cursor.write_all(&[1, 2, 3]).unwrap();
+        cursor.seek(SeekFrom::Start(1)).unwrap();
+        cursor.write_all(&[4, 5, 6]).unwrap();
+        assert_eq!(cursor.stream_position().unwrap(), 4);
+        assert_eq!(cursor.get_ref().0, [1, 4, 5, 6]);
+    }
+
+    #[test]
+    fn growing_vec_write_past_end_fills_zeros() {
+        let mut vec = vec![1];
+        {
+            let mut cursor = Cursor::new(Growing(&mut vec));
+            cursor.seek(SeekFrom::End(3)).unwrap();
+            cursor.write_all(&[2]).unwrap();
+        }
+        assert_eq!(vec, [1, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn stream_position() {
+        let mut cursor = Cursor::new(&b"data"[..]);
+        cursor.seek(SeekFrom::End(-1)).unwrap();
+        assert_eq!(cursor.stream_position().unwrap(), 3);
+        // past the end is allowed, reads then return nothing
+        cursor.set_position(10);
+        assert_eq!(cursor.stream_position().unwrap(), 10);
+        assert_eq!(cursor.read(&mut [0; 4]).unwrap(), 0);
+    }
+}
@@ -1,11 +1,35 @@
+mod adapters;
+mod asynch;
+mod buffered;
 mod cursor;
+mod util;
 
+#[cfg(any(test, feature = "alloc"))]
+pub use self::adapters::Lines;
+pub use self::adapters::{Bytes, Chain, Take};
+pub use self::asynch::{
+    AsyncRead, AsyncReadWriteSeek, AsyncSeek, AsyncWrite, FlushFuture, ReadExactFuture,
+    ReadFuture, SeekFuture, WriteAllFuture, WriteFuture,
+};
+pub use self::buffered::{BufReader, BufWriter};
 pub use self::cursor::Cursor;
-use core::fmt;
+#[cfg(any(test, feature = "alloc"))]
+pub use self::cursor::Growing;
+pub use self::util::copy;
+pub(crate) use self::util::read_full;
+#[cfg(any(test, feature = "alloc"))]
+use alloc::string::String;
+#[cfg(any(test, feature = "alloc"))]
+use alloc::vec::Vec;
+use core::{fmt, str};
 
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum ErrorKind {
     InvalidInput,
+    InvalidData,
+    NotFound,
+    ReadOnly,
+    PermissionDenied,
     UnexpectedEof,
     WriteZero,
     Other,
@@ -15,11 +39,19 @@ pub enum ErrorKind {
 pub struct Error {
     kind: ErrorKind,
     msg: &'static str,
+    /// Error of the host's storage this one was converted from.
+    #[cfg(feature = "std")]
+    host: Option<::std::io::Error>,
 }
 
 impl Error {
     pub fn new(kind: ErrorKind, msg: &'static str) -> Self {
-        Error { kind, msg }
+        Error {
+            kind,
+            msg,
+            #[cfg(feature = "std")]
+            host: None,
+        }
     }
 
     pub fn kind(&self) -> ErrorKind {
@@ -29,10 +61,45 @@ impl Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        #[cfg(feature = "std")]
+        {
+            if let Some(ref host) = self.host {
+                return write!(f, "io error: {}", host);
+            }
+        }
         write!(f, "io error: {}", self.msg)
     }
 }
 
+#[cfg(feature = "std")]
+impl ::std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn (::std::error::Error) + 'static)> {
+        self.host.as_ref().map(|host| host as _)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<::std::io::Error> for Error {
+    fn from(err: ::std::io::Error) -> Self {
+        use std::io::ErrorKind as StdKind;
+        let kind = match err.kind() {
+            StdKind::InvalidInput => ErrorKind::InvalidInput,
+            StdKind::InvalidData => ErrorKind::InvalidData,
+            StdKind::NotFound => ErrorKind::NotFound,
+            StdKind::ReadOnlyFilesystem => ErrorKind::ReadOnly,
+            StdKind::PermissionDenied => ErrorKind::PermissionDenied,
+            StdKind::UnexpectedEof => ErrorKind::UnexpectedEof,
+            StdKind::WriteZero => ErrorKind::WriteZero,
+            _ => ErrorKind::Other,
+        };
+        Error {
+            kind,
+            msg: "host io error",
+            host: Some(err),
+        }
+    }
+}
+
 pub type Result<T> = ::core::result::Result<T, Error>;
 
 pub trait Read {
@@ -56,12 +123,124 @@ pub trait Read {
         }
     }
 
+    /// Reads until EOF, appending everything to `buf`.
+    #[cfg(any(test, feature = "alloc"))]
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let start = buf.len();
+        let mut chunk = [0; 512];
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(buf.len() - start),
+                n => buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    /// Reads until EOF, appending everything to `buf`, which is left as it
+    /// was if the data is not valid UTF-8.
+    #[cfg(any(test, feature = "alloc"))]
+    fn read_to_string(&mut self, buf: &mut String) -> Result<usize> {
+        let mut bytes = Vec::new();
+        self.read_to_end(&mut bytes)?;
+        buf.push_str(to_str(&bytes)?);
+        Ok(bytes.len())
+    }
+
     fn by_ref(&mut self) -> &mut Self
     where
         Self: Sized,
     {
         self
     }
+
+    /// Iterates over the bytes of this reader, best used on a `BufRead`.
+    fn bytes(self) -> Bytes<Self>
+    where
+        Self: Sized,
+    {
+        adapters::bytes(self)
+    }
+
+    /// Reads from this reader until EOF, then from `next`.
+    fn chain<R: Read>(self, next: R) -> Chain<Self, R>
+    where
+        Self: Sized,
+    {
+        adapters::chain(self, next)
+    }
+
+    /// Reads at most `limit` bytes from this reader.
+    fn take(self, limit: u64) -> Take<Self>
+    where
+        Self: Sized,
+    {
+        adapters::take(self, limit)
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+fn to_str(bytes: &[u8]) -> Result<&str> {
+    str::from_utf8(bytes).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        )
+    })
+}
+
+/// A reader with an internal buffer, which allows reading up to a delimiter.
+pub trait BufRead: Read {
+    /// Returns buffered data, reading more from the inner reader if none is
+    /// left. An empty result means EOF.
+    fn fill_buf(&mut self) -> Result<&[u8]>;
+
+    /// Marks `amt` bytes returned by `fill_buf` as read.
+    fn consume(&mut self, amt: usize);
+
+    /// Reads until `byte` or EOF, appending everything including the
+    /// delimiter to `buf`.
+    #[cfg(any(test, feature = "alloc"))]
+    fn read_until(&mut self, byte: u8, buf: &mut Vec<u8>) -> Result<usize> {
+        let mut read = 0;
+        loop {
+            let (done, used) = {
+                let available = self.fill_buf()?;
+                match available.iter().position(|&b| b == byte) {
+                    Some(i) => {
+                        buf.extend_from_slice(&available[..=i]);
+                        (true, i + 1)
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        (available.is_empty(), available.len())
+                    }
+                }
+            };
+            self.consume(used);
+            read += used;
+            if done {
+                return Ok(read);
+            }
+        }
+    }
+
+    /// Reads a line including the `\n`, appending it to `buf`.
+    #[cfg(any(test, feature = "alloc"))]
+    fn read_line(&mut self, buf: &mut String) -> Result<usize> {
+        let mut bytes = Vec::new();
+        let read = self.read_until(b'\n', &mut bytes)?;
+        buf.push_str(to_str(&bytes)?);
+        Ok(read)
+    }
+
+    /// Iterates over lines, without their `\n` or `\r\n`.
+    #[cfg(any(test, feature = "alloc"))]
+    fn lines(self) -> Lines<Self>
+    where
+        Self: Sized,
+    {
+        adapters::lines(self)
+    }
 }
 
 pub trait Write {
@@ -85,6 +264,34 @@ pub trait Write {
         Ok(())
     }
 
+    /// Writes formatted output, so that `write!` works.
+    fn write_fmt(&mut self, args: fmt::Arguments) -> Result<()> {
+        // keeps the io error, which fmt::Error cannot carry
+        struct Adapter<'a, T: ?Sized + 'a> {
+            inner: &'a mut T,
+            error: Result<()>,
+        }
+
+        impl<'a, T: Write + ?Sized + 'a> fmt::Write for Adapter<'a, T> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.inner.write_all(s.as_bytes()).map_err(|e| {
+                    self.error = Err(e);
+                    fmt::Error
+                })
+            }
+        }
+
+        let mut output = Adapter {
+            inner: self,
+            error: Ok(()),
+        };
+        match fmt::write(&mut output, args) {
+            Ok(()) => Ok(()),
+            Err(_) if output.error.is_err() => output.error,
+            Err(_) => Err(Error::new(ErrorKind::Other, "formatter error")),
+        }
+    }
+
     fn by_ref(&mut self) -> &mut Self
     where
         Self: Sized,
@@ -95,6 +302,11 @@ pub trait Write {
 
 pub trait Seek {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
+
+    /// Returns the current position from the start of the stream.
+    fn stream_position(&mut self) -> Result<u64> {
+        self.seek(SeekFrom::Current(0))
+    }
 }
 
 #[derive(Copy, PartialEq, Eq, Clone, Debug)]
@@ -110,6 +322,16 @@ impl<'a, T: ?Sized + Read + 'a> Read for &'a mut T {
     }
 }
 
+impl<'a, B: ?Sized + BufRead + 'a> BufRead for &'a mut B {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        (**self).fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        (**self).consume(amt)
+    }
+}
+
 impl<'a, T: ?Sized + Write + 'a> Write for &'a mut T {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         <T as Write>::write(&mut **self, buf)
@@ -124,14 +346,18 @@ impl<'a, T: ?Sized + Seek + 'a> Seek for &'a mut T {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
         <T as Seek>::seek(&mut **self, pos)
     }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        <T as Seek>::stream_position(&mut **self)
+    }
 }
 
-impl<'a> Write for &'a mut [u8] {
+impl Write for &mut [u8] {
     #[inline]
     fn write(&mut self, data: &[u8]) -> Result<usize> {
         use core::{cmp, mem};
         let amt = cmp::min(data.len(), self.len());
-        let (a, b) = mem::replace(self, &mut []).split_at_mut(amt);
+        let (a, b) = mem::take(self).split_at_mut(amt);
         a.copy_from_slice(&data[..amt]);
         *self = b;
         Ok(amt)
@@ -155,7 +381,7 @@ impl<'a> Write for &'a mut [u8] {
     }
 }
 
-impl<'a> Read for &'a [u8] {
+impl Read for &[u8] {
     #[inline]
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         use core::cmp;
@@ -199,6 +425,140 @@ impl<'a> Read for &'a [u8] {
     }
 }
 
+impl BufRead for &[u8] {
+    #[inline]
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        Ok(*self)
+    }
+
+    #[inline]
+    fn consume(&mut self, amt: usize) {
+        *self = &self[amt..];
+    }
+}
+
+#[cfg(any(test, feature = "alloc"))]
+impl Write for Vec<u8> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Read for ::std::fs::File {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(::std::io::Read::read(self, buf)?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Write for ::std::fs::File {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(::std::io::Write::write(self, buf)?)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(::std::io::Write::flush(self)?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Seek for ::std::fs::File {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => ::std::io::SeekFrom::Start(n),
+            SeekFrom::End(n) => ::std::io::SeekFrom::End(n),
+            SeekFrom::Current(n) => ::std::io::SeekFrom::Current(n),
+        };
+        Ok(::std::io::Seek::seek(self, pos)?)
+    }
+}
+
 pub trait ReadWriteSeek: Read + Write + Seek {}
 
 impl<T: Read + Write + Seek> ReadWriteSeek for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    #[test]
+    fn write_fmt() {
+        let mut buf = [0; 16];
+        let mut cursor = Cursor::new(&mut buf[..]);
+        let name = "id";
+        write!(cursor, "{}-{:02x}", name, 10).unwrap();
+        assert_eq!(cursor.position(), 5);
+        assert_eq!(&buf[..5], b"id-0a");
+    }
+
+    #[test]
+    fn write_fmt_keeps_io_error() {
+        let mut buf = [0; 4];
+        let err = write!(&mut buf[..], "{}", 12345).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn host_error_keeps_message() {
+        use std::string::ToString;
+        let host = ::std::io::Error::new(::std::io::ErrorKind::InvalidData, "bad sector 7");
+        let err = Error::from(host);
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "io error: bad sector 7");
+        assert!(::std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn read_to_end() {
+        let data = (0..1500).map(|i| i as u8).collect::<Vec<_>>();
+        let mut out = vec![1, 2];
+        assert_eq!((&data[..]).read_to_end(&mut out).unwrap(), 1500);
+        assert_eq!(&out[..2], [1, 2]);
+        assert_eq!(&out[2..], &data[..]);
+    }
+
+    #[test]
+    fn read_to_string() {
+        let mut out = String::from("> ");
+        (&b"text"[..]).read_to_string(&mut out).unwrap();
+        assert_eq!(out, "> text");
+        let err = (&b"\xc3"[..]).read_to_string(&mut out).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(out, "> text");
+    }
+
+    #[test]
+    fn read_until() {
+        let mut buf = [0; 3];
+        let mut reader = BufReader::new(&b"a,bcde,f"[..], &mut buf);
+        let mut out = Vec::new();
+        assert_eq!(reader.read_until(b',', &mut out).unwrap(), 2);
+        assert_eq!(reader.read_until(b',', &mut out).unwrap(), 5);
+        assert_eq!(reader.read_until(b',', &mut out).unwrap(), 1);
+        assert_eq!(reader.read_until(b',', &mut out).unwrap(), 0);
+        assert_eq!(out, b"a,bcde,f");
+    }
+
+    #[test]
+    fn cursor_read_line() {
+        let mut cursor = Cursor::new(&b"one\ntwo"[..]);
+        let mut line = String::new();
+        cursor.read_line(&mut line).unwrap();
+        assert_eq!(line, "one\n");
+        assert_eq!(cursor.position(), 4);
+        line.clear();
+        cursor.read_line(&mut line).unwrap();
+        assert_eq!(line, "two");
+    }
+}
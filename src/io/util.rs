@@ -0,0 +1,61 @@
+use io::{ErrorKind, Read, Result, Write};
+
+/// Copies everything from `reader` to `writer` through a buffer on the stack,
+/// returning the number of bytes copied.
+pub fn copy<R, W>(reader: &mut R, writer: &mut W) -> Result<u64>
+where
+    R: ?Sized + Read,
+    W: ?Sized + Write,
+{
+    let mut buf = [0; 512];
+    let mut copied = 0;
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            return Ok(copied);
+        }
+        writer.write_all(&buf[..read])?;
+        copied += read as u64;
+    }
+}
+
+/// Fills `buf` like `read_exact`, but returns `false` instead of failing if
+/// `reader` ends first, as at the end of a torn record.
+pub(crate) fn read_full<R: ?Sized + Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::{Cursor, Read};
+    use std::vec::Vec;
+
+    #[test]
+    fn copy_stream() {
+        let data = (0..2000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut out = Cursor::new([0; 3000]);
+        assert_eq!(copy(&mut &data[..], &mut out).unwrap(), 2000);
+        assert_eq!(&out.get_ref()[..2000], &data[..]);
+        assert_eq!(out.position(), 2000);
+    }
+
+    #[test]
+    fn copy_limited() {
+        let mut reader = (&b"hello world"[..]).take(5);
+        let mut out = Vec::new();
+        assert_eq!(copy(&mut reader, &mut out).unwrap(), 5);
+        assert_eq!(out, b"hello");
+    }
+
+    #[test]
+    fn copy_into_full_writer() {
+        let mut out = [0; 4];
+        let result = copy(&mut &b"too long"[..], &mut &mut out[..]);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,481 @@
+//! Key-value store kept in a single log-structured file.
+//!
+//! `KvStore` appends every `set` and `remove` to a log file and replays it
+//! into memory on `open`, so thousands of small settings share one file
+//! instead of taking a slot each:
+//!
+//! ```ignore
+//! let mut kv = KvStore::open(&mut fs, b"settings")?;
+//! kv.set(&mut fs, "wifi.ssid", b"home")?;
+//! let ssid = kv.get("wifi.ssid");
+//! ```
+//!
+//! Once the log fills up, or on `compact`, live entries are written to a new
+//! log in the other of two files, `settings.0` and `settings.1`, ending with
+//! a commit record. `open` uses the new log only if it has been committed,
+//! and drops a torn record at the end of the log, so a store interrupted by
+//! power loss holds every update that reached storage in full. Updates reach
+//! storage as any other file writes do, see `FileSystem::set_flush_on_close`.
+
+use alloc::collections::btree_map::{self, BTreeMap};
+use alloc::vec::Vec;
+use fs::{FileSystem, MAX_FILE_SIZE};
+use io::{self, ReadWriteSeek, Write};
+use path::{self, Path};
+use record::{seq_newer, RecordReader, RecordWriter, RECORD_HEADER_SIZE};
+
+pub const MAX_KEY_SIZE: usize = 255;
+pub const MAX_VALUE_SIZE: usize = 4096;
+// each entry is a record of kind (1), key length (1), key and value
+const FRAME_HEADER_SIZE: usize = RECORD_HEADER_SIZE + 2;
+const MAX_PAYLOAD_SIZE: usize = 2 + MAX_KEY_SIZE + MAX_VALUE_SIZE;
+
+/// First record of a log, holding its generation as the value.
+const KIND_HEADER: u8 = 1;
+const KIND_SET: u8 = 2;
+const KIND_REMOVE: u8 = 3;
+/// Ends the entries copied into a log by compaction.
+const KIND_COMMIT: u8 = 4;
+
+pub struct KvStore {
+    files: [Path; 2],
+    /// File holding the log in use.
+    current: usize,
+    generation: u32,
+    /// Length of the valid part of the log.
+    len: u64,
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Valid contents of one log file.
+struct Log {
+    generation: u32,
+    committed: bool,
+    len: u64,
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KvStore {
+    /// Opens the store kept in files named `prefix` followed by `.0` or `.1`,
+    /// creating it if neither exists.
+    pub fn open<T: ReadWriteSeek>(fs: &mut FileSystem<T>, prefix: &[u8]) -> io::Result<KvStore> {
+        let mut files = [path::EMPTY; 2];
+        for (i, file) in files.iter_mut().enumerate() {
+            *file = Path::numbered(prefix, i)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad store name"))?;
+        }
+        let mut logs = [read_log(fs, files[0])?, read_log(fs, files[1])?];
+        let current = match (&logs[0], &logs[1]) {
+            (Some(a), Some(b)) => {
+                let (newer, older) = if seq_newer(b.generation, a.generation) {
+                    (1, 0)
+                } else {
+                    (0, 1)
+                };
+                let newer_log = if newer == 0 { a } else { b };
+                // the newer log is only complete once committed, and the older
+                // one is removed after that
+                let keep = if newer_log.committed { newer } else { older };
+                fs.remove(files[1 - keep])?;
+                Some(keep)
+            }
+            (Some(_), None) => Some(0),
+            (None, Some(_)) => Some(1),
+            (None, None) => None,
+        };
+        let mut store = match current.and_then(|i| logs[i].take().map(|log| (i, log))) {
+            Some((current, log)) => {
+                fs.truncate(files[current], log.len)?;
+                KvStore {
+                    files,
+                    current,
+                    generation: log.generation,
+                    len: log.len,
+                    entries: log.entries,
+                }
+            }
+            None => KvStore {
+                files,
+                current: 1,
+                generation: 0,
+                len: 0,
+                entries: BTreeMap::new(),
+            },
+        };
+        if current.is_none() {
+            store.compact(fs)?;
+        }
+        Ok(store)
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<&[u8]> {
+        self.entries.get(key.as_ref()).map(|value| &value[..])
+    }
+
+    pub fn set<T, K>(&mut self, fs: &mut FileSystem<T>, key: K, value: &[u8]) -> io::Result<()>
+    where
+        T: ReadWriteSeek,
+        K: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        if key.len() > MAX_KEY_SIZE || value.len() > MAX_VALUE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key or value too large",
+            ));
+        }
+        if self.get(key) == Some(value) {
+            return Ok(());
+        }
+        self.append(fs, KIND_SET, key, value)?;
+        self.entries.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    /// Removes `key`, returning whether it was present.
+    pub fn remove<T, K>(&mut self, fs: &mut FileSystem<T>, key: K) -> io::Result<bool>
+    where
+        T: ReadWriteSeek,
+        K: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        if !self.entries.contains_key(key) {
+            return Ok(false);
+        }
+        self.append(fs, KIND_REMOVE, key, &[])?;
+        self.entries.remove(key);
+        Ok(true)
+    }
+
+    /// Iterates over all entries, ordered by key.
+    pub fn iter<'b>(&'b self) -> impl Iterator<Item = (&'b [u8], &'b [u8])> + 'b {
+        Iter {
+            inner: self.entries.iter(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes of the log in use, which compaction brings down to what the
+    /// live entries take.
+    pub fn log_len(&self) -> u64 {
+        self.len
+    }
+
+    /// Writes live entries to a fresh log in the other file and switches to
+    /// it.
+    pub fn compact<T: ReadWriteSeek>(&mut self, fs: &mut FileSystem<T>) -> io::Result<()> {
+        let generation = self.generation.wrapping_add(1);
+        let target = 1 - self.current;
+        let fd = fs.create(self.files[target])?;
+        let result = fs.get_writer(&fd).and_then(|writer| {
+            let mut writer = RecordWriter::new(writer);
+            let mut len = write_frame(&mut writer, KIND_HEADER, &[], &generation.to_le_bytes())?;
+            for (key, value) in &self.entries {
+                len += write_frame(&mut writer, KIND_SET, key, value)?;
+            }
+            len += write_frame(&mut writer, KIND_COMMIT, &[], &[])?;
+            Ok(len)
+        });
+        let result = result.and_then(|len| fs.sync(&fd).map(|()| len));
+        fs.close(fd)?;
+        let len = result?;
+        // the old log goes only once the new one is in storage
+        if fs.metadata(self.files[self.current]).is_ok() {
+            fs.remove(self.files[self.current])?;
+            fs.flush_to_storage()?;
+        }
+        self.current = target;
+        self.generation = generation;
+        self.len = len;
+        Ok(())
+    }
+
+    fn append<T: ReadWriteSeek>(
+        &mut self,
+        fs: &mut FileSystem<T>,
+        kind: u8,
+        key: &[u8],
+        value: &[u8],
+    ) -> io::Result<()> {
+        let frame_len = (FRAME_HEADER_SIZE + key.len() + value.len()) as u64;
+        if self.len + frame_len > MAX_FILE_SIZE {
+            self.compact(fs)?;
+            if self.len + frame_len > MAX_FILE_SIZE {
+                return Err(io::Error::new(io::ErrorKind::Other, "store full"));
+            }
+        }
+        let fd = fs.open_append(self.files[self.current])?;
+        let result = fs
+            .get_writer(&fd)
+            .and_then(|writer| write_frame(&mut RecordWriter::new(writer), kind, key, value));
+        fs.close(fd)?;
+        match result {
+            Ok(len) => {
+                self.len += len;
+                Ok(())
+            }
+            Err(e) => {
+                // a torn record would hide everything appended after it
+                fs.truncate(self.files[self.current], self.len)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+struct Iter<'a> {
+    inner: btree_map::Iter<'a, Vec<u8>, Vec<u8>>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, value)| (&key[..], &value[..]))
+    }
+}
+
+fn write_frame<W: Write>(
+    writer: &mut RecordWriter<W>,
+    kind: u8,
+    key: &[u8],
+    value: &[u8],
+) -> io::Result<u64> {
+    writer.write_record_parts(&[&[kind, key.len() as u8], key, value])?;
+    Ok((FRAME_HEADER_SIZE + key.len() + value.len()) as u64)
+}
+
+/// Replays the valid part of a log, `None` if the file is missing or does not
+/// start with a header.
+fn read_log<T: ReadWriteSeek>(fs: &mut FileSystem<T>, path: Path) -> io::Result<Option<Log>> {
+    let fd = match fs.open_read(path) {
+        Ok(fd) => fd,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut log: Option<Log> = None;
+    let result = fs.get_reader(&fd).and_then(|reader| {
+        let mut reader = RecordReader::with_max_len(reader, MAX_PAYLOAD_SIZE);
+        let mut payload = [0; MAX_PAYLOAD_SIZE];
+        while let Some(len) = reader.read_record(&mut payload)? {
+            let key_len = payload[1] as usize;
+            if len < 2 || 2 + key_len > len {
+                return Ok(());
+            }
+            let (key, value) = payload[2..len].split_at(key_len);
+            match (payload[0], log.as_mut()) {
+                (KIND_HEADER, None) if value.len() == 4 => {
+                    log = Some(Log {
+                        generation: u32::from_le_bytes([value[0], value[1], value[2], value[3]]),
+                        committed: false,
+                        len: 0,
+                        entries: BTreeMap::new(),
+                    });
+                }
+                (KIND_SET, Some(log)) => {
+                    log.entries.insert(key.to_vec(), value.to_vec());
+                }
+                (KIND_REMOVE, Some(log)) => {
+                    log.entries.remove(key);
+                }
+                (KIND_COMMIT, Some(ref mut log)) if !log.committed => log.committed = true,
+                _ => return Ok(()),
+            }
+            if let Some(ref mut log) = log {
+                log.len = reader.valid_len();
+            }
+        }
+        Ok(())
+    });
+    fs.close(fd)?;
+    result.map(|()| log)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::{Cursor, Read};
+    use testing::fault::{Fault, FaultyStorage};
+    use FS_SIZE;
+
+    fn storage() -> Cursor<Vec<u8>> {
+        Cursor::new(vec![0; FS_SIZE as usize])
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let mut storage = storage();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            let mut kv = KvStore::open(&mut fs, b"settings").expect("failed to open");
+            assert!(kv.is_empty());
+            kv.set(&mut fs, "wifi.ssid", b"home").unwrap();
+            kv.set(&mut fs, "wifi.pass", b"hunter2").unwrap();
+            kv.set(&mut fs, b"volume", &[7]).unwrap();
+            kv.set(&mut fs, "wifi.ssid", b"office").unwrap();
+            assert!(kv.remove(&mut fs, "wifi.pass").unwrap());
+            assert!(!kv.remove(&mut fs, "wifi.pass").unwrap());
+            fs.unmount().expect("failed to unmount");
+        }
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let kv = KvStore::open(&mut fs, b"settings").expect("failed to open");
+        assert_eq!(kv.get("wifi.ssid"), Some(&b"office"[..]));
+        assert_eq!(kv.get("wifi.pass"), None);
+        let entries = kv.iter().collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                (&b"volume"[..], &[7][..]),
+                (&b"wifi.ssid"[..], &b"office"[..])
+            ]
+        );
+        assert_eq!(fs.list_files().count(), 1);
+    }
+
+    #[test]
+    fn thousands_of_keys_with_compaction() {
+        let mut storage = storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let mut kv = KvStore::open(&mut fs, b"settings").expect("failed to open");
+        let value = [0x5a; 250];
+        for round in 0..3 {
+            for i in 0..2000 {
+                let key = format!("key.{}", i);
+                kv.set(&mut fs, &key, &value[..(150 + round * 10 + i % 50)])
+                    .unwrap();
+            }
+        }
+        // three rounds of writes do not fit in a file
+        assert!(kv.generation > 1, "log was not compacted");
+        assert_eq!(kv.len(), 2000);
+        fs.flush_to_storage().unwrap();
+        let mut fs = FileSystem::new(fs.inner_mut()).expect("failed to mount");
+        let kv = KvStore::open(&mut fs, b"settings").expect("failed to open");
+        assert_eq!(kv.len(), 2000);
+        assert_eq!(kv.get("key.1234").unwrap().len(), 170 + 34);
+        assert_eq!(fs.list_files().count(), 1);
+    }
+
+    fn populated_image() -> Vec<u8> {
+        let mut storage = storage();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            let mut kv = KvStore::open(&mut fs, b"kv").expect("failed to open");
+            kv.set(&mut fs, "a", b"1").unwrap();
+            kv.set(&mut fs, "b", b"2").unwrap();
+            kv.remove(&mut fs, "a").unwrap();
+            fs.flush_to_storage().unwrap();
+        }
+        storage.into_inner()
+    }
+
+    fn run(
+        image: Vec<u8>,
+        fault: Option<Fault>,
+        op: fn(&mut KvStore, &mut FileSystem<&mut FaultyStorage>),
+    ) -> (u64, Cursor<Vec<u8>>) {
+        let mut storage = FaultyStorage::new(image);
+        if let Some(fault) = fault {
+            storage = storage.with_fault(fault);
+        }
+        {
+            let mut storage = &mut storage;
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            fs.set_flush_on_close(true);
+            let mut kv = KvStore::open(&mut fs, b"kv").expect("failed to open");
+            op(&mut kv, &mut fs);
+            fs.flush_to_storage().expect("failed to flush");
+        }
+        (storage.bytes_written(), storage.into_image())
+    }
+
+    fn check_power_loss(
+        op: fn(&mut KvStore, &mut FileSystem<&mut FaultyStorage>),
+        after: &[(&[u8], &[u8])],
+    ) {
+        let before: &[(&[u8], &[u8])] = &[(b"b", b"2")];
+        let (total, _) = run(populated_image(), None, op);
+        for cut in 0..total {
+            let fault = Fault::PowerLoss { after_bytes: cut };
+            let (_, mut image) = run(populated_image(), Some(fault), op);
+            let mut fs = FileSystem::new(&mut image).expect("failed to mount");
+            let kv = KvStore::open(&mut fs, b"kv").expect("failed to open");
+            let entries = kv.iter().collect::<Vec<_>>();
+            assert!(
+                entries == before || entries == after,
+                "cut at {}: {:?}",
+                cut,
+                entries
+            );
+        }
+    }
+
+    #[test]
+    fn set_survives_power_loss() {
+        check_power_loss(
+            |kv, fs| kv.set(fs, "c", b"3").expect("failed to set"),
+            &[(b"b", b"2"), (b"c", b"3")],
+        );
+    }
+
+    #[test]
+    fn compaction_survives_power_loss() {
+        check_power_loss(
+            |kv, fs| kv.compact(fs).expect("failed to compact"),
+            &[(b"b", b"2")],
+        );
+    }
+
+    fn read_file<T: ReadWriteSeek>(fs: &mut FileSystem<T>, path: Path) -> Vec<u8> {
+        let fd = fs.open_read(path).expect("failed to open");
+        let mut data = Vec::new();
+        fs.get_reader(&fd).unwrap().read_to_end(&mut data).unwrap();
+        fs.close(fd).unwrap();
+        data
+    }
+
+    #[test]
+    fn generation_wraps_around() {
+        let mut storage = storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let mut kv = KvStore::open(&mut fs, b"kv").expect("failed to open");
+        kv.generation = u32::MAX - 1;
+        kv.set(&mut fs, "v", b"old").unwrap();
+        kv.compact(&mut fs).unwrap();
+        assert_eq!(kv.generation, u32::MAX);
+        let old_path = kv.files[kv.current];
+        let old_log = read_file(&mut fs, old_path);
+        kv.set(&mut fs, "v", b"new").unwrap();
+        kv.compact(&mut fs).unwrap();
+        assert_eq!(kv.generation, 0);
+        // as if power was lost before the older log was removed
+        let fd = fs.create(old_path).unwrap();
+        fs.get_writer(&fd).unwrap().write_all(&old_log).unwrap();
+        fs.close(fd).unwrap();
+
+        let kv = KvStore::open(&mut fs, b"kv").expect("failed to open");
+        assert_eq!(kv.get("v"), Some(&b"new"[..]));
+        assert_eq!(fs.list_files().count(), 1);
+    }
+
+    #[test]
+    fn open_fails_on_locked_log() {
+        let mut storage = storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let mut kv = KvStore::open(&mut fs, b"kv").expect("failed to open");
+        kv.set(&mut fs, "a", b"1").unwrap();
+        let fd = fs.open_append(kv.files[kv.current]).unwrap();
+        let err = KvStore::open(&mut fs, b"kv").err().expect("opened a locked log");
+        assert_ne!(err.kind(), io::ErrorKind::NotFound);
+        fs.close(fd).unwrap();
+        let kv = KvStore::open(&mut fs, b"kv").expect("failed to open");
+        assert_eq!(kv.get("a"), Some(&b"1"[..]));
+    }
+}
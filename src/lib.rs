@@ -1,13 +1,51 @@
 #![no_std]
-#![feature(nll)]
+// The original tests predate this lint and are kept as written.
+#![cfg_attr(test, allow(clippy::manual_contains))]
 
-#[cfg(test)]
+#[cfg(any(test, feature = "alloc"))]
+extern crate alloc;
+#[cfg(any(test, feature = "critical-section"))]
+extern crate critical_section;
+#[cfg(any(test, feature = "std"))]
 #[macro_use]
 extern crate std;
 
+/// Embeds an image produced by `image::embed_dir` in a build script, as a
+/// `&'static [u8; N]` that can be mounted through `io::Cursor`.
+#[macro_export]
+macro_rules! include_image {
+    ($name:expr) => {
+        include_bytes!(concat!(env!("OUT_DIR"), "/", $name))
+    };
+}
+
+pub mod ab_update;
+#[cfg(any(test, feature = "encryption"))]
+mod aead;
+pub mod cache;
+mod crc;
+#[cfg(any(test, feature = "encryption"))]
+pub mod encrypted;
 mod fs;
+#[cfg(feature = "std")]
+pub mod image;
 pub mod io;
+#[cfg(any(test, feature = "alloc"))]
+pub mod kv;
+mod lz4;
 mod path;
+pub mod record;
+pub mod ring_log;
+pub mod shared;
+pub mod snapshot;
+#[cfg(test)]
+mod testing;
 
-pub use fs::{format_storage, Fd, FileSystem};
-pub use path::Path;
+pub use cache::{BlockCache, CachePolicy};
+pub use fs::{
+    check_storage, format_storage, AsyncFileSystem, CreateOptions, Fd, FileFlags,
+    FileSystem, Metadata, Problem, Xattrs, FS_SIZE, MAX_FILES, MAX_FILE_SIZE,
+};
+pub use path::{Path, MAX_PATH_LENGTH};
+pub use shared::SharedFileSystem;
+pub use snapshot::{format_snapshots, SnapshotStorage};
@@ -0,0 +1,233 @@
+//! LZ4 block format compressor and decompressor for small blocks.
+//!
+//! Blocks must be at most 64 KiB. Compression is greedy with a tiny hash
+//! table, trading ratio for a small stack footprint.
+
+const MIN_MATCH: usize = 4;
+/// Last match must start at least this many bytes before the end of block.
+const MF_LIMIT: usize = 12;
+/// Last bytes of a block are always literals.
+const LAST_LITERALS: usize = 5;
+const HASH_LOG: u32 = 8;
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from(buf[pos])
+        | u32::from(buf[pos + 1]) << 8
+        | u32::from(buf[pos + 2]) << 16
+        | u32::from(buf[pos + 3]) << 24
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+struct Output<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Output<'a> {
+    fn push(&mut self, byte: u8) -> Option<()> {
+        *self.buf.get_mut(self.pos)? = byte;
+        self.pos += 1;
+        Some(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.pos + bytes.len();
+        self.buf.get_mut(self.pos..end)?.copy_from_slice(bytes);
+        self.pos = end;
+        Some(())
+    }
+
+    fn push_len(&mut self, mut len: usize) -> Option<()> {
+        while len >= 255 {
+            self.push(255)?;
+            len -= 255;
+        }
+        self.push(len as u8)
+    }
+
+    fn sequence(&mut self, literals: &[u8], offset: u16, match_len: usize) -> Option<()> {
+        let lit_code = cmp_min(literals.len(), 15);
+        let match_code = cmp_min(match_len - MIN_MATCH, 15);
+        self.push((lit_code << 4 | match_code) as u8)?;
+        if lit_code == 15 {
+            self.push_len(literals.len() - 15)?;
+        }
+        self.extend(literals)?;
+        self.push(offset as u8)?;
+        self.push((offset >> 8) as u8)?;
+        if match_code == 15 {
+            self.push_len(match_len - MIN_MATCH - 15)?;
+        }
+        Some(())
+    }
+
+    fn last_literals(&mut self, literals: &[u8]) -> Option<()> {
+        let lit_code = cmp_min(literals.len(), 15);
+        self.push((lit_code << 4) as u8)?;
+        if lit_code == 15 {
+            self.push_len(literals.len() - 15)?;
+        }
+        self.extend(literals)
+    }
+}
+
+fn cmp_min(a: usize, b: usize) -> usize {
+    ::core::cmp::min(a, b)
+}
+
+/// Compresses `input` into `output`, returning the compressed length, or
+/// `None` if it does not fit.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    debug_assert!(input.len() <= 0xFFFF, "block too large");
+    // positions are stored off by one, so that zero means empty
+    let mut table = [0u16; 1 << HASH_LOG];
+    let mut out = Output {
+        buf: output,
+        pos: 0,
+    };
+    let mut anchor = 0;
+    let mut pos = 0;
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - MF_LIMIT;
+        let end_limit = input.len() - LAST_LITERALS;
+        while pos < match_limit {
+            let seq = read_u32(input, pos);
+            let slot = &mut table[hash(seq)];
+            let candidate = *slot as usize;
+            *slot = pos as u16 + 1;
+            if candidate != 0 && read_u32(input, candidate - 1) == seq {
+                let start = candidate - 1;
+                let mut len = MIN_MATCH;
+                while pos + len < end_limit && input[start + len] == input[pos + len] {
+                    len += 1;
+                }
+                out.sequence(&input[anchor..pos], (pos - start) as u16, len)?;
+                pos += len;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+    out.last_literals(&input[anchor..])?;
+    Some(out.pos)
+}
+
+fn read_len(input: &[u8], pos: &mut usize, mut len: usize) -> Option<usize> {
+    loop {
+        let byte = *input.get(*pos)?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Decompresses a block produced by `compress` into `output`, returning the
+/// decompressed length, or `None` if the block is malformed or does not fit.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut out = 0;
+    loop {
+        let token = *input.get(pos)?;
+        pos += 1;
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len = read_len(input, &mut pos, lit_len)?;
+        }
+        let literals = input.get(pos..pos.checked_add(lit_len)?)?;
+        output.get_mut(out..out + lit_len)?.copy_from_slice(literals);
+        pos += lit_len;
+        out += lit_len;
+        if pos == input.len() {
+            return Some(out);
+        }
+        let offset = *input.get(pos)? as usize | (*input.get(pos + 1)? as usize) << 8;
+        pos += 2;
+        if offset == 0 || offset > out {
+            return None;
+        }
+        let mut match_len = (token & 15) as usize;
+        if match_len == 15 {
+            match_len = read_len(input, &mut pos, match_len)?;
+        }
+        match_len += MIN_MATCH;
+        if out + match_len > output.len() {
+            return None;
+        }
+        // byte by byte, since the match may overlap bytes it produces
+        for i in out..(out + match_len) {
+            output[i] = output[i - offset];
+        }
+        out += match_len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(data: &[u8]) -> usize {
+        let mut compressed = [0; 1024];
+        let len = compress(data, &mut compressed).expect("failed to compress");
+        let mut decompressed = [0; 512];
+        let out = decompress(&compressed[..len], &mut decompressed).expect("failed to decompress");
+        assert_eq!(&decompressed[..out], data);
+        len
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(roundtrip(b""), 1);
+    }
+
+    #[test]
+    fn short_literals() {
+        assert_eq!(roundtrip(b"hello"), 6);
+    }
+
+    #[test]
+    fn repetitive() {
+        let data = [b'a'; 300];
+        assert!(roundtrip(&data) < 20);
+        let log = b"[info] tick\n[info] tick\n[info] tick\n[info] tock\n[info] tick\n";
+        assert!(roundtrip(log) < log.len());
+    }
+
+    #[test]
+    fn incompressible() {
+        let mut data = [0; 300];
+        let mut x = 1u32;
+        for byte in data.iter_mut() {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            *byte = (x >> 16) as u8;
+        }
+        roundtrip(&data);
+        let mut small = [0; 100];
+        assert_eq!(compress(&data, &mut small), None);
+    }
+
+    #[test]
+    fn hand_assembled_block() {
+        // literals "abc", an overlapping 10 byte match at offset 3, and the
+        // final 5 literals
+        let block = [
+            0x36, b'a', b'b', b'c', 0x03, 0x00, 0x50, b'b', b'c', b'a', b'b', b'c',
+        ];
+        let mut out = [0; 32];
+        let len = decompress(&block, &mut out).expect("failed to decompress");
+        assert_eq!(&out[..len], b"abcabcabcabcabcabc");
+    }
+
+    #[test]
+    fn malformed() {
+        let mut out = [0; 32];
+        assert_eq!(decompress(&[], &mut out), None);
+        assert_eq!(decompress(&[0x10, b'a', 0x05, 0x00], &mut out), None);
+        assert_eq!(decompress(&[0x50, b'a'], &mut out), None);
+    }
+}
@@ -0,0 +1,278 @@
+//! Host-side tool for building and inspecting spark-fs images.
+
+extern crate spark_fs;
+
+use spark_fs::io::{Read, Write};
+use spark_fs::{CreateOptions, FileSystem, Path};
+use std::fs::{File, OpenOptions};
+use std::process;
+
+const USAGE: &str = "usage:
+    spark-fs mkfs <image> [--size <bytes>]
+    spark-fs ls <image> [<pattern>]
+    spark-fs cat <image> <path>
+    spark-fs put <image> <host-file> <path> [--compress]
+    spark-fs get <image> <path> <host-file>
+    spark-fs rm <image> <path>
+    spark-fs stat <image> <path>
+    spark-fs fsck <image>
+    spark-fs pack <dir> <image>
+    spark-fs unpack <image> <dir>";
+
+type CliResult<T> = Result<T, String>;
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+    if let Err(msg) = run(&args) {
+        eprintln!("spark-fs: {}", msg);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[&str]) -> CliResult<()> {
+    match *args {
+        ["mkfs", image] => mkfs(image, spark_fs::FS_SIZE),
+        ["mkfs", image, "--size", size] => {
+            let size = size
+                .parse()
+                .map_err(|_| format!("invalid size: {}", size))?;
+            mkfs(image, size)
+        }
+        ["ls", image] => ls(image, "*"),
+        ["ls", image, pattern] => ls(image, pattern),
+        ["cat", image, path] => cat(image, path),
+        ["put", image, host_file, path] => put(image, host_file, path, false),
+        ["put", image, host_file, path, "--compress"] => put(image, host_file, path, true),
+        ["get", image, path, host_file] => get(image, path, host_file),
+        ["rm", image, path] => rm(image, path),
+        ["stat", image, path] => stat(image, path),
+        ["fsck", image] => fsck(image),
+        ["pack", dir, image] => pack(dir, image),
+        ["unpack", image, dir] => unpack(image, dir),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn open_image(image: &str, write: bool) -> CliResult<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(image)
+        .map_err(|e| format!("cannot open {}: {}", image, e))
+}
+
+fn mount<'a>(image: &'a mut File) -> CliResult<FileSystem<'a, File>> {
+    FileSystem::new(image).map_err(|e| format!("cannot mount: {}", e))
+}
+
+fn mount_read_only<'a>(image: &'a mut File) -> CliResult<FileSystem<'a, File>> {
+    FileSystem::mount_read_only(image).map_err(|e| format!("cannot mount: {}", e))
+}
+
+fn parse_path(path: &str) -> CliResult<Path> {
+    Path::from_ascii_str(path.as_bytes()).ok_or_else(|| {
+        format!(
+            "invalid path {:?}: must be at most {} bytes without zero bytes",
+            path,
+            spark_fs::MAX_PATH_LENGTH,
+        )
+    })
+}
+
+fn read_file(fs: &mut FileSystem<File>, name: &str) -> CliResult<Vec<u8>> {
+    let fd = fs
+        .open_read(parse_path(name)?)
+        .map_err(|e| format!("cannot open {}: {}", name, e))?;
+    let mut data = Vec::new();
+    {
+        let mut reader = fs.get_reader(&fd).map_err(|e| e.to_string())?;
+        let mut buf = [0; 4096];
+        loop {
+            let read = reader.read(&mut buf).map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..read]);
+        }
+    }
+    fs.close(fd).map_err(|e| e.to_string())?;
+    Ok(data)
+}
+
+fn mkfs(image: &str, size: u64) -> CliResult<()> {
+    if size < spark_fs::FS_SIZE {
+        return Err(format!(
+            "image size must be at least {} bytes",
+            spark_fs::FS_SIZE
+        ));
+    }
+    let mut file = File::create(image).map_err(|e| format!("cannot create {}: {}", image, e))?;
+    file.set_len(size).map_err(|e| e.to_string())?;
+    spark_fs::format_storage(&mut file, size).map_err(|e| format!("cannot format: {}", e))
+}
+
+fn ls(image: &str, pattern: &str) -> CliResult<()> {
+    let mut file = open_image(image, false)?;
+    let fs = mount_read_only(&mut file)?;
+    let files = fs.find_sorted(pattern.as_bytes()).collect::<Vec<_>>();
+    for path in files {
+        let meta = fs.metadata(path).map_err(|e| e.to_string())?;
+        println!(
+            "{:>8} {}",
+            meta.len(),
+            String::from_utf8_lossy(path.as_slice())
+        );
+    }
+    Ok(())
+}
+
+fn cat(image: &str, path: &str) -> CliResult<()> {
+    let mut file = open_image(image, false)?;
+    let data = read_file(&mut mount_read_only(&mut file)?, path)?;
+    let stdout = std::io::stdout();
+    std::io::Write::write_all(&mut stdout.lock(), &data).map_err(|e| e.to_string())
+}
+
+fn put(image: &str, host_file: &str, name: &str, compress: bool) -> CliResult<()> {
+    let path = parse_path(name)?;
+    let data = std::fs::read(host_file).map_err(|e| format!("cannot read {}: {}", host_file, e))?;
+    // compressed files are checked by the write itself
+    if !compress && data.len() as u64 > spark_fs::MAX_FILE_SIZE {
+        return Err(format!(
+            "{} is {} bytes, files can be at most {} bytes",
+            host_file,
+            data.len(),
+            spark_fs::MAX_FILE_SIZE,
+        ));
+    }
+    let mut file = open_image(image, true)?;
+    let mut fs = mount(&mut file)?;
+    let fd = fs
+        .create_with(path, &CreateOptions::new().compressed(compress))
+        .map_err(|e| format!("cannot create {}: {}", name, e))?;
+    fs.get_writer(&fd)
+        .and_then(|mut writer| writer.write_all(&data))
+        .map_err(|e| format!("cannot write {}: {}", name, e))?;
+    fs.close(fd).map_err(|e| e.to_string())?;
+    fs.unmount().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn get(image: &str, path: &str, host_file: &str) -> CliResult<()> {
+    let mut file = open_image(image, false)?;
+    let data = read_file(&mut mount_read_only(&mut file)?, path)?;
+    std::fs::write(host_file, data).map_err(|e| format!("cannot write {}: {}", host_file, e))
+}
+
+fn rm(image: &str, name: &str) -> CliResult<()> {
+    let path = parse_path(name)?;
+    let mut file = open_image(image, true)?;
+    let mut fs = mount(&mut file)?;
+    fs.remove(path)
+        .map_err(|e| format!("cannot remove {}: {}", name, e))?;
+    fs.unmount().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn stat(image: &str, name: &str) -> CliResult<()> {
+    let path = parse_path(name)?;
+    let mut file = open_image(image, false)?;
+    let meta = mount_read_only(&mut file)?
+        .metadata(path)
+        .map_err(|e| format!("cannot stat {}: {}", name, e))?;
+    println!("name: {}", name);
+    println!("size: {}", meta.len());
+    if meta.is_compressed() {
+        println!("stored size: {} (compressed)", meta.stored_len());
+    }
+    Ok(())
+}
+
+fn fsck(image: &str) -> CliResult<()> {
+    let mut file = open_image(image, false)?;
+    let mut problems = 0;
+    spark_fs::check_storage(&mut file, |problem| {
+        println!("{}", problem);
+        problems += 1;
+    })
+    .map_err(|e| format!("cannot check: {}", e))?;
+    if problems == 0 {
+        println!("no problems found");
+        Ok(())
+    } else {
+        Err(format!("found {} problem(s)", problems))
+    }
+}
+
+fn pack(dir: &str, image: &str) -> CliResult<()> {
+    let data = spark_fs::image::pack(dir.as_ref()).map_err(|e| format!("cannot pack: {}", e))?;
+    std::fs::write(image, data).map_err(|e| format!("cannot write {}: {}", image, e))
+}
+
+fn unpack(image: &str, dir: &str) -> CliResult<()> {
+    let data = std::fs::read(image).map_err(|e| format!("cannot read {}: {}", image, e))?;
+    spark_fs::image::unpack(&data, dir.as_ref()).map_err(|e| format!("cannot unpack: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn temp_dir() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "spark-fs-cli-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst),
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs the tool with arguments starting with `@` taken as file names
+    /// in `dir`.
+    fn run_in(dir: &Path, args: &[&str]) -> CliResult<()> {
+        let args = args
+            .iter()
+            .map(|arg| match arg.strip_prefix('@') {
+                Some(name) => dir.join(name).to_str().unwrap().to_string(),
+                None => arg.to_string(),
+            })
+            .collect::<Vec<_>>();
+        run(&args.iter().map(|a| a.as_str()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn put_get_rm() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("in.txt"), b"some host file\n").unwrap();
+        run_in(&dir, &["mkfs", "@img"]).unwrap();
+        run_in(&dir, &["put", "@img", "@in.txt", "plain"]).unwrap();
+        run_in(&dir, &["put", "@img", "@in.txt", "packed", "--compress"]).unwrap();
+        run_in(&dir, &["ls", "@img"]).unwrap();
+        run_in(&dir, &["fsck", "@img"]).unwrap();
+        for name in &["plain", "packed"] {
+            run_in(&dir, &["get", "@img", name, "@out.txt"]).unwrap();
+            assert_eq!(std::fs::read(dir.join("out.txt")).unwrap(), b"some host file\n");
+        }
+        run_in(&dir, &["rm", "@img", "plain"]).unwrap();
+        let err = run_in(&dir, &["get", "@img", "plain", "@out.txt"]).unwrap_err();
+        assert!(err.starts_with("cannot open plain:"), "{}", err);
+        assert_eq!(run_in(&dir, &["bogus"]).unwrap_err(), USAGE);
+    }
+
+    #[test]
+    fn host_errors_are_shown() {
+        let dir = temp_dir();
+        let missing = dir.join("missing");
+        let err = run_in(&dir, &["ls", "@missing"]).unwrap_err();
+        let host = File::open(&missing).unwrap_err().to_string();
+        assert!(err.ends_with(&host), "{}", err);
+        // a directory opens fine but fails to read, through the library
+        let err = run_in(&dir, &["ls", "@"]).unwrap_err();
+        let host = std::fs::read(&dir).unwrap_err().to_string();
+        assert_eq!(err, format!("cannot mount: io error: {}", host));
+    }
+}
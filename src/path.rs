@@ -28,6 +28,19 @@ impl Path {
         }
     }
 
+    /// Builds `prefix` followed by a dot and a single digit, such as
+    /// `app.log.3`, for data kept in a few numbered files.
+    pub(crate) fn numbered(prefix: &[u8], index: usize) -> Option<Self> {
+        if index > 9 || prefix.len() + 2 > MAX_PATH_LENGTH {
+            return None;
+        }
+        let mut name = [0; MAX_PATH_LENGTH];
+        name[..prefix.len()].copy_from_slice(prefix);
+        name[prefix.len()] = b'.';
+        name[prefix.len() + 1] = b'0' + index as u8;
+        Self::from_ascii_str(&name[..(prefix.len() + 2)])
+    }
+
     pub fn as_slice(&self) -> &[u8] {
         for i in 0..MAX_PATH_LENGTH {
             if self.buf[i] == 0 {
@@ -36,6 +49,89 @@ impl Path {
         }
         &self.buf
     }
+
+    /// Matches the whole path against a glob pattern. `*` matches any run of
+    /// bytes, `?` any single byte, and a class such as `[abc]`, `[a-z]` or
+    /// `[!0-9]` any byte in it, or not in it. A `[` without a closing `]`
+    /// matches itself.
+    pub fn matches(&self, pattern: &[u8]) -> bool {
+        glob_match(pattern, self.as_slice())
+    }
+}
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // pattern after the last `*` seen, and where in `name` that `*` stopped
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                p += 1;
+                star = Some((p, n));
+                continue;
+            }
+            if let Some(len) = match_byte(&pattern[p..], name[n]) {
+                p += len;
+                n += 1;
+                continue;
+            }
+        }
+        // let the last `*` take one more byte and try again
+        match star {
+            Some((after_star, matched)) => {
+                p = after_star;
+                n = matched + 1;
+                star = Some((after_star, n));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `byte` against the first token of `pattern`, which is not `*`,
+/// returning the length of the token.
+fn match_byte(pattern: &[u8], byte: u8) -> Option<usize> {
+    match pattern[0] {
+        b'?' => Some(1),
+        b'[' => match match_class(pattern, byte) {
+            Some((true, len)) => Some(len),
+            Some((false, _)) => None,
+            None if byte == b'[' => Some(1),
+            None => None,
+        },
+        c if c == byte => Some(1),
+        _ => None,
+    }
+}
+
+/// Matches `byte` against the class `pattern` starts with, returning whether
+/// it matched and the length of the class, or `None` if the class is not
+/// closed. A `]` right after the opening `[` or `[!` is part of the class.
+fn match_class(pattern: &[u8], byte: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = pattern.get(i) == Some(&b'!') || pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+    let start = i;
+    let mut matched = false;
+    loop {
+        let c = *pattern.get(i)?;
+        if c == b']' && i > start {
+            return Some((matched != negated, i + 1));
+        }
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some(&b'-'), Some(&end)) if end != b']' => {
+                matched |= c <= byte && byte <= end;
+                i += 3;
+            }
+            _ => {
+                matched |= c == byte;
+                i += 1;
+            }
+        }
+    }
 }
 
 pub const EMPTY: Path = Path {
@@ -58,7 +154,7 @@ impl<'a> fmt::Debug for FmtPathData<'a> {
             if ch == 0 {
                 break;
             }
-            if ch < 0x20 || ch >= 127 || ch == b'\\' {
+            if !(0x20..127).contains(&ch) || ch == b'\\' {
                 write!(f, "\\x{:>02x}", ch)?;
             } else {
                 write!(f, "{}", ch as char)?;
@@ -98,6 +194,39 @@ mod tests {
     }
     
     #[test]
+    fn glob() {
+        let path = Path::from_ascii_str(b"app.log.3").unwrap();
+        for pattern in &[
+            &b"app.log.3"[..],
+            b"*",
+            b"*.log.?",
+            b"app*3",
+            b"*.*.*",
+            b"a*p*l*3",
+            b"app.log.[0-9]",
+            b"app.log.[!a-z]",
+            b"[]a]pp.log.3",
+        ] {
+            assert!(path.matches(pattern), "{:?}", ::std::str::from_utf8(pattern));
+        }
+        for pattern in &[
+            &b"app.log"[..],
+            b"*.txt",
+            b"app.log.??",
+            b"?",
+            b"app.log.[4-9]",
+            b"app.log.[!0-9]",
+            b"",
+        ] {
+            assert!(!path.matches(pattern), "{:?}", ::std::str::from_utf8(pattern));
+        }
+        let path = Path::from_ascii_str(b"a[b").unwrap();
+        assert!(path.matches(b"a[b"), "unclosed class should match itself");
+        assert!(Path::from_ascii_str(b"").unwrap().matches(b"*"));
+    }
+
+    #[test]
+    #[allow(clippy::octal_escapes)]
     fn construct_inner_zeros() {
         let data = b"123\0123";
         assert!(Path::from_ascii_str(data).is_none());
@@ -0,0 +1,336 @@
+//! Length-prefixed records with a CRC, for append-only files.
+//!
+//! `RecordWriter` wraps the writer of a file and frames every record with its
+//! length and CRC. `RecordReader` wraps a reader and returns the records one
+//! by one, stopping at the first one that is torn or corrupt:
+//!
+//! ```ignore
+//! recover(&mut fs, path)?;
+//! let fd = fs.open_append(path)?;
+//! RecordWriter::new(fs.get_writer(&fd)?).write_record(b"temp=21.5")?;
+//! fs.close(fd)?;
+//! ```
+//!
+//! A record cut short by power loss leaves garbage at the end of the file,
+//! which `recover` truncates before anything is appended after it.
+
+use crc::Crc32;
+use fs::FileSystem;
+use io::{self, Read, ReadWriteSeek, Seek, Write};
+use path::Path;
+
+// payload length (4), crc of the length and payload (4)
+pub const RECORD_HEADER_SIZE: usize = 8;
+
+pub struct RecordWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(inner: W) -> Self {
+        RecordWriter { inner }
+    }
+
+    pub fn write_record(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_record_parts(&[payload])
+    }
+
+    /// Writes a single record whose payload is `parts` one after another.
+    pub fn write_record_parts(&mut self, parts: &[&[u8]]) -> io::Result<()> {
+        let len = parts.iter().map(|part| part.len() as u64).sum::<u64>();
+        if len > u64::from(u32::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record too large",
+            ));
+        }
+        let len = (len as u32).to_le_bytes();
+        let mut crc = Crc32::new();
+        crc.update(&len);
+        for part in parts {
+            crc.update(part);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE];
+        header[..4].copy_from_slice(&len);
+        header[4..].copy_from_slice(&crc.finish().to_le_bytes());
+        self.inner.write_all(&header)?;
+        for part in parts {
+            self.inner.write_all(part)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+pub struct RecordReader<R> {
+    inner: R,
+    /// Bytes taken by the records read so far.
+    valid_len: u64,
+    /// Longer records are taken for corruption, see `with_max_len`.
+    max_len: usize,
+    done: bool,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(inner: R) -> Self {
+        RecordReader {
+            inner,
+            valid_len: 0,
+            max_len: usize::MAX,
+            done: false,
+        }
+    }
+
+    /// Like `new`, but a record longer than `max_len` ends the records
+    /// instead of failing `read_record`. For files whose end may hold
+    /// leftovers of older data, where such a length is just garbage.
+    pub fn with_max_len(inner: R, max_len: usize) -> Self {
+        RecordReader {
+            max_len,
+            ..RecordReader::new(inner)
+        }
+    }
+
+    /// Reads the next record into `buf`, returning its length, or `None`
+    /// once the records end, be it at the end of the file or at a torn or
+    /// corrupt record. Fails if the record does not fit in `buf`, after which
+    /// no more records are returned.
+    pub fn read_record(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE];
+        if !self.fill(&mut header)? {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if len > self.max_len {
+            self.done = true;
+            return Ok(None);
+        }
+        if len > buf.len() {
+            self.done = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record larger than buffer",
+            ));
+        }
+        if !self.fill(&mut buf[..len])? {
+            return Ok(None);
+        }
+        let mut crc = Crc32::new();
+        crc.update(&header[..4]);
+        crc.update(&buf[..len]);
+        if crc.finish().to_le_bytes() != header[4..] {
+            self.done = true;
+            return Ok(None);
+        }
+        self.valid_len += (RECORD_HEADER_SIZE + len) as u64;
+        Ok(Some(len))
+    }
+
+    /// Skips the next record, checking its CRC without keeping it. Returns
+    /// `false` once the records end.
+    pub fn skip_record(&mut self) -> io::Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE];
+        if !self.fill(&mut header)? {
+            return Ok(false);
+        }
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if len > self.max_len {
+            self.done = true;
+            return Ok(false);
+        }
+        let mut crc = Crc32::new();
+        crc.update(&header[..4]);
+        let mut chunk = [0; 64];
+        let mut left = len;
+        while left > 0 {
+            let n = ::core::cmp::min(left, chunk.len());
+            if !self.fill(&mut chunk[..n])? {
+                return Ok(false);
+            }
+            crc.update(&chunk[..n]);
+            left -= n;
+        }
+        if crc.finish().to_le_bytes() != header[4..] {
+            self.done = true;
+            return Ok(false);
+        }
+        self.valid_len += (RECORD_HEADER_SIZE + len) as u64;
+        Ok(true)
+    }
+
+    /// Bytes from the start of the reader up to the end of the last valid
+    /// record read.
+    pub fn valid_len(&self) -> u64 {
+        self.valid_len
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Fills `buf`, returning `false` if the reader ends first.
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let filled = io::read_full(&mut self.inner, buf)?;
+        self.done = !filled;
+        Ok(filled)
+    }
+}
+
+/// Whether sequence number `a` comes after `b`, allowing the numbers to wrap
+/// around as long as the two are less than 2^31 apart.
+pub(crate) fn seq_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Truncates the file at `path` to its last valid record, returning the new
+/// length.
+pub fn recover<T: ReadWriteSeek>(fs: &mut FileSystem<T>, path: Path) -> io::Result<u64> {
+    let len = valid_len(fs, path)?;
+    fs.truncate(path, len)?;
+    Ok(len)
+}
+
+/// Returns the length of the valid records at the start of the file at
+/// `path`.
+pub fn valid_len<T: Read + Seek>(fs: &mut FileSystem<T>, path: Path) -> io::Result<u64> {
+    let fd = fs.open_read(path)?;
+    let result = fs.get_reader(&fd).and_then(|reader| {
+        let mut reader = RecordReader::new(reader);
+        while reader.skip_record()? {}
+        Ok(reader.valid_len())
+    });
+    fs.close_read(fd)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::Cursor;
+    use std::vec::Vec;
+    use FS_SIZE;
+
+    fn path() -> Path {
+        Path::from_ascii_str(b"telemetry").unwrap()
+    }
+
+    fn append<T: ReadWriteSeek>(fs: &mut FileSystem<T>, records: &[&[u8]]) {
+        let fd = fs.open_append(path()).expect("failed to open");
+        {
+            let mut writer = RecordWriter::new(fs.get_writer(&fd).expect("failed to get writer"));
+            for record in records {
+                writer.write_record(record).expect("failed to write");
+            }
+        }
+        fs.close(fd).expect("failed to close");
+    }
+
+    fn read_all<T: Read + Seek>(fs: &mut FileSystem<T>) -> Vec<Vec<u8>> {
+        let fd = fs.open_read(path()).expect("failed to open");
+        let mut records = Vec::new();
+        {
+            let mut reader = RecordReader::new(fs.get_reader(&fd).expect("failed to get reader"));
+            let mut buf = [0; 64];
+            while let Some(len) = reader.read_record(&mut buf).expect("failed to read") {
+                records.push(buf[..len].to_vec());
+            }
+        }
+        fs.close_read(fd).expect("failed to close");
+        records
+    }
+
+    fn with_file<F: FnOnce(&mut FileSystem<Cursor<Vec<u8>>>)>(f: F) {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let fd = fs.create(path()).expect("failed to create");
+        fs.close(fd).expect("failed to close");
+        f(&mut fs);
+    }
+
+    #[test]
+    fn roundtrip() {
+        with_file(|fs| {
+            append(fs, &[b"first", b"", b"third record"]);
+            append(fs, &[b"fourth"]);
+            assert_eq!(
+                read_all(fs),
+                [&b"first"[..], b"", b"third record", b"fourth"]
+            );
+        });
+    }
+
+    #[test]
+    fn torn_record_is_skipped_and_recovered() {
+        with_file(|fs| {
+            append(fs, &[b"first", b"second"]);
+            let valid = fs.metadata(path()).unwrap().len();
+            // the start of a record, as left by power loss
+            let fd = fs.open_append(path()).unwrap();
+            fs.get_writer(&fd)
+                .unwrap()
+                .write_all(&[11, 0, 0, 0, 1, 2, 3, 4, b'x'])
+                .unwrap();
+            fs.close(fd).unwrap();
+            assert_eq!(read_all(fs), [&b"first"[..], b"second"]);
+
+            assert_eq!(recover(fs, path()).unwrap(), valid);
+            append(fs, &[b"third"]);
+            assert_eq!(read_all(fs), [&b"first"[..], b"second", b"third"]);
+        });
+    }
+
+    #[test]
+    fn corrupt_record_ends_records() {
+        with_file(|fs| {
+            append(fs, &[b"first", b"second", b"third"]);
+            let fd = fs.open_append(path()).unwrap();
+            // same length as a valid record, wrong crc
+            fs.get_writer(&fd)
+                .unwrap()
+                .write_all(&[1, 0, 0, 0, 0, 0, 0, 0, b'x'])
+                .unwrap();
+            fs.close(fd).unwrap();
+            append(fs, &[b"lost"]);
+            assert_eq!(read_all(fs), [&b"first"[..], b"second", b"third"]);
+        });
+    }
+
+    #[test]
+    fn record_larger_than_buffer() {
+        with_file(|fs| {
+            append(fs, &[&[7; 100]]);
+            let fd = fs.open_read(path()).unwrap();
+            let mut reader = RecordReader::new(fs.get_reader(&fd).unwrap());
+            let err = reader.read_record(&mut [0; 10]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    fn garbage_length_ends_records() {
+        with_file(|fs| {
+            append(fs, &[b"first"]);
+            let fd = fs.open_append(path()).unwrap();
+            fs.get_writer(&fd)
+                .unwrap()
+                .write_all(&[0xff, 0xff, 0, 0, 1, 2, 3, 4])
+                .unwrap();
+            fs.close(fd).unwrap();
+            let fd = fs.open_read(path()).unwrap();
+            let mut reader = RecordReader::with_max_len(fs.get_reader(&fd).unwrap(), 64);
+            let mut buf = [0; 64];
+            assert_eq!(reader.read_record(&mut buf).unwrap(), Some(5));
+            assert_eq!(reader.read_record(&mut buf).unwrap(), None);
+            assert_eq!(reader.valid_len(), (RECORD_HEADER_SIZE + 5) as u64);
+        });
+    }
+}
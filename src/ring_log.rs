@@ -0,0 +1,376 @@
+//! Append-only logs of bounded size that overwrite their oldest records.
+//!
+//! A `RingLog` is stored in a few segment files named after a prefix, such
+//! as `app.log.0` to `app.log.3`. Records are appended to the newest segment,
+//! and once it is full the oldest segment is recreated and written next, so
+//! the log never takes more than `segments * segment_size` bytes:
+//!
+//! ```ignore
+//! let mut log = RingLog::open(&mut fs, b"app.log", 4, 16 * 1024)?;
+//! log.append(&mut fs, b"booted")?;
+//! log.read(&mut fs, |seq, record| println!("{}: {:?}", seq, record))?;
+//! ```
+//!
+//! Every record is stored with its sequence number, framed with a length and
+//! CRC as by `record::RecordWriter`.
+//! Reading a segment stops at the first frame that is torn or out of
+//! sequence, so records cut short by power loss, or left over from a segment
+//! that was being recreated, are never returned. Records reach storage as
+//! any other file writes do, see `FileSystem::set_flush_on_close`.
+
+use fs::{FileSystem, MAX_FILE_SIZE};
+use io::{self, Read, ReadWriteSeek, Seek};
+use path::{self, Path};
+use record::{seq_newer, RecordReader, RecordWriter, RECORD_HEADER_SIZE};
+
+pub const MAX_SEGMENTS: usize = 8;
+/// Largest record that can be appended.
+pub const MAX_RECORD_SIZE: usize = 1024;
+// record header, sequence number (4)
+const FRAME_HEADER_SIZE: usize = RECORD_HEADER_SIZE + 4;
+
+pub struct RingLog {
+    segments: [Path; MAX_SEGMENTS],
+    count: usize,
+    segment_size: u64,
+    /// Segment being appended to, `None` until the first record.
+    current: Option<usize>,
+    current_len: u64,
+    next_seq: u32,
+}
+
+/// Valid records found at the start of a segment.
+#[derive(Debug, Copy, Clone)]
+struct SegmentInfo {
+    first_seq: u32,
+    last_seq: u32,
+    len: u64,
+}
+
+impl RingLog {
+    /// Opens the log stored in `count` segments of at most `segment_size`
+    /// bytes, named `prefix` followed by a dot and the segment number. Cuts
+    /// off a torn record at the end of the newest segment, if there is one.
+    pub fn open<T: ReadWriteSeek>(
+        fs: &mut FileSystem<T>,
+        prefix: &[u8],
+        count: usize,
+        segment_size: u64,
+    ) -> io::Result<RingLog> {
+        if !(2..=MAX_SEGMENTS).contains(&count) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bad segment count",
+            ));
+        }
+        if segment_size <= FRAME_HEADER_SIZE as u64 || segment_size > MAX_FILE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bad segment size",
+            ));
+        }
+        let mut segments = [path::EMPTY; MAX_SEGMENTS];
+        for (i, segment) in segments.iter_mut().enumerate().take(count) {
+            *segment = Path::numbered(prefix, i)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad log name"))?;
+        }
+        let mut log = RingLog {
+            segments,
+            count,
+            segment_size,
+            current: None,
+            current_len: 0,
+            next_seq: 0,
+        };
+        let mut newest: Option<(usize, SegmentInfo)> = None;
+        for i in 0..count {
+            let info = read_segment(fs, log.segments[i], |_, _| ())?;
+            match (info, newest) {
+                (Some(info), Some((_, n))) if seq_newer(info.first_seq, n.first_seq) => {
+                    newest = Some((i, info))
+                }
+                (Some(info), None) => newest = Some((i, info)),
+                _ => {}
+            }
+        }
+        if let Some((i, info)) = newest {
+            fs.truncate(log.segments[i], info.len)?;
+            log.current = Some(i);
+            log.current_len = info.len;
+            log.next_seq = info.last_seq.wrapping_add(1);
+        }
+        Ok(log)
+    }
+
+    /// Appends a record, overwriting the oldest segment if the newest one is
+    /// full.
+    pub fn append<T: ReadWriteSeek>(
+        &mut self,
+        fs: &mut FileSystem<T>,
+        record: &[u8],
+    ) -> io::Result<()> {
+        let frame_len = (FRAME_HEADER_SIZE + record.len()) as u64;
+        if record.len() > MAX_RECORD_SIZE || frame_len > self.segment_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record too large",
+            ));
+        }
+        let fd = match self.current {
+            Some(i) if self.current_len + frame_len <= self.segment_size => {
+                fs.open_append(self.segments[i])?
+            }
+            current => {
+                let next = current.map_or(0, |i| (i + 1) % self.count);
+                let fd = fs.create(self.segments[next])?;
+                self.current = Some(next);
+                self.current_len = 0;
+                fd
+            }
+        };
+        let seq = self.next_seq.to_le_bytes();
+        let result = fs
+            .get_writer(&fd)
+            .and_then(|writer| RecordWriter::new(writer).write_record_parts(&[&seq, record]));
+        fs.close(fd)?;
+        match result {
+            Ok(()) => {
+                self.current_len += frame_len;
+                self.next_seq = self.next_seq.wrapping_add(1);
+                Ok(())
+            }
+            Err(e) => {
+                // nothing appended after a torn frame would ever be read, so
+                // start over in the next segment
+                self.current_len = self.segment_size;
+                Err(e)
+            }
+        }
+    }
+
+    /// Calls `f` with the sequence number and contents of every record,
+    /// oldest first.
+    pub fn read<T, F>(&self, fs: &mut FileSystem<T>, mut f: F) -> io::Result<()>
+    where
+        T: Read + Seek,
+        F: FnMut(u32, &[u8]),
+    {
+        let mut order = [(0, 0); MAX_SEGMENTS];
+        let mut found = 0;
+        for i in 0..self.count {
+            if let Some(info) = read_segment(fs, self.segments[i], |_, _| ())? {
+                // distance back from the next record, which allows for
+                // sequence numbers that wrap around
+                order[found] = (info.first_seq.wrapping_sub(self.next_seq), i);
+                found += 1;
+            }
+        }
+        order[..found].sort_unstable();
+        for &(_, i) in &order[..found] {
+            read_segment(fs, self.segments[i], &mut f)?;
+        }
+        Ok(())
+    }
+
+    /// Sequence number the next record will get.
+    pub fn next_seq(&self) -> u32 {
+        self.next_seq
+    }
+}
+
+/// Reads the valid records at the start of a segment, calling `f` with each
+/// of them. Returns `None` if the segment is missing or holds no records.
+fn read_segment<T, F>(
+    fs: &mut FileSystem<T>,
+    path: Path,
+    mut f: F,
+) -> io::Result<Option<SegmentInfo>>
+where
+    T: Read + Seek,
+    F: FnMut(u32, &[u8]),
+{
+    let fd = match fs.open_read(path) {
+        Ok(fd) => fd,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut info: Option<SegmentInfo> = None;
+    let result = fs.get_reader(&fd).and_then(|reader| {
+        let mut reader = RecordReader::with_max_len(reader, 4 + MAX_RECORD_SIZE);
+        let mut payload = [0; 4 + MAX_RECORD_SIZE];
+        while let Some(len) = reader.read_record(&mut payload)? {
+            if len < 4 {
+                return Ok(());
+            }
+            let seq = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]);
+            info = match info {
+                None => Some(SegmentInfo {
+                    first_seq: seq,
+                    last_seq: seq,
+                    len: 0,
+                }),
+                Some(info) if info.last_seq.wrapping_add(1) == seq => Some(SegmentInfo {
+                    last_seq: seq,
+                    ..info
+                }),
+                // left over from before the segment was recreated
+                Some(_) => return Ok(()),
+            };
+            if let Some(ref mut info) = info {
+                info.len = reader.valid_len();
+            }
+            f(seq, &payload[4..len]);
+        }
+        Ok(())
+    });
+    fs.close_read(fd)?;
+    result.map(|()| info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::{Cursor, Write};
+    use std::vec::Vec;
+    use FS_SIZE;
+
+    fn records<T: ReadWriteSeek>(log: &RingLog, fs: &mut FileSystem<T>) -> Vec<(u32, Vec<u8>)> {
+        let mut records = Vec::new();
+        log.read(fs, |seq, data| records.push((seq, data.to_vec())))
+            .expect("failed to read");
+        records
+    }
+
+    fn record(i: u32) -> Vec<u8> {
+        format!("record number {}", i).into_bytes()
+    }
+
+    #[test]
+    fn overwrites_oldest() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let mut log = RingLog::open(&mut fs, b"app.log", 3, 100).expect("failed to open");
+        assert!(records(&log, &mut fs).is_empty());
+        for i in 0..40 {
+            log.append(&mut fs, &record(i)).expect("failed to append");
+        }
+        let kept = records(&log, &mut fs);
+        // three records fit in a segment, two full ones and the newest
+        assert!(kept.len() > 6 && kept.len() <= 9, "kept {}", kept.len());
+        let first = 40 - kept.len() as u32;
+        for (i, &(seq, ref data)) in kept.iter().enumerate() {
+            assert_eq!(seq, first + i as u32);
+            assert_eq!(*data, record(seq));
+        }
+        assert_eq!(fs.list_files().count(), 3);
+    }
+
+    #[test]
+    fn continues_after_remount() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            let mut log = RingLog::open(&mut fs, b"app.log", 2, 1000).expect("failed to open");
+            for i in 0..5 {
+                log.append(&mut fs, &record(i)).expect("failed to append");
+            }
+            fs.unmount().expect("failed to unmount");
+        }
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let mut log = RingLog::open(&mut fs, b"app.log", 2, 1000).expect("failed to open");
+        assert_eq!(log.next_seq(), 5);
+        log.append(&mut fs, &record(5)).expect("failed to append");
+        let kept = records(&log, &mut fs);
+        assert_eq!(kept.len(), 6);
+        assert_eq!(
+            fs.list_files().count(),
+            1,
+            "should append to the same segment"
+        );
+    }
+
+    #[test]
+    fn torn_record_is_cut_off() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let mut log = RingLog::open(&mut fs, b"app.log", 2, 1000).expect("failed to open");
+        for i in 0..3 {
+            log.append(&mut fs, &record(i)).expect("failed to append");
+        }
+        let path = Path::from_ascii_str(b"app.log.0").unwrap();
+        let fd = fs.open_append(path).unwrap();
+        let mut frame = Vec::new();
+        RecordWriter::new(&mut frame)
+            .write_record_parts(&[&3u32.to_le_bytes(), &record(3)])
+            .unwrap();
+        fs.get_writer(&fd).unwrap().write_all(&frame[..7]).unwrap();
+        fs.close(fd).unwrap();
+
+        let mut log = RingLog::open(&mut fs, b"app.log", 2, 1000).expect("failed to open");
+        assert_eq!(log.next_seq(), 3);
+        log.append(&mut fs, &record(3)).expect("failed to append");
+        let seqs = records(&log, &mut fs)
+            .into_iter()
+            .map(|(seq, _)| seq)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn recreated_segment_hides_leftovers() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let mut log = RingLog::open(&mut fs, b"app.log", 2, 110).expect("failed to open");
+        // four records fit in a segment
+        for i in 0..8 {
+            log.append(&mut fs, &record(i)).expect("failed to append");
+        }
+        fs.flush_to_storage().unwrap();
+        // as if power was lost while recreating the oldest segment: new data
+        // over the start of it, and the header still saying it is full
+        log.append(&mut fs, &record(8)).expect("failed to append");
+        let mut fs = FileSystem::new(fs.inner_mut()).expect("failed to mount");
+        let log = RingLog::open(&mut fs, b"app.log", 2, 110).expect("failed to open");
+        let seqs = records(&log, &mut fs)
+            .into_iter()
+            .map(|(seq, _)| seq)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, [4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let mut log = RingLog::open(&mut fs, b"app.log", 3, 100).expect("failed to open");
+        log.next_seq = u32::MAX - 5;
+        for i in 0..12 {
+            log.append(&mut fs, &record(i)).expect("failed to append");
+        }
+        let log = RingLog::open(&mut fs, b"app.log", 3, 100).expect("failed to open");
+        assert_eq!(log.next_seq(), 6);
+        let seqs = records(&log, &mut fs)
+            .into_iter()
+            .map(|(seq, _)| seq)
+            .collect::<Vec<_>>();
+        let first = seqs[0];
+        assert!(first >= u32::MAX - 5, "oldest is {}", first);
+        for (i, &seq) in seqs.iter().enumerate() {
+            assert_eq!(seq, first.wrapping_add(i as u32));
+        }
+    }
+
+    #[test]
+    fn open_fails_on_locked_segment() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        let mut log = RingLog::open(&mut fs, b"app.log", 2, 1000).expect("failed to open");
+        log.append(&mut fs, &record(0)).expect("failed to append");
+        let path = Path::from_ascii_str(b"app.log.0").unwrap();
+        let fd = fs.open_append(path).unwrap();
+        assert!(RingLog::open(&mut fs, b"app.log", 2, 1000).is_err());
+        fs.close(fd).unwrap();
+        let log = RingLog::open(&mut fs, b"app.log", 2, 1000).expect("failed to open");
+        assert_eq!(records(&log, &mut fs), [(0, record(0))]);
+    }
+}
@@ -0,0 +1,453 @@
+//! Sharing one `FileSystem` between tasks and interrupt handlers.
+//!
+//! `SharedFileSystem` puts the file system behind a mutex and runs every
+//! operation with it locked, so that it only needs `&self`. The mutex is
+//! anything implementing `Mutex`: `std::sync::Mutex` with the `std` feature,
+//! the `SpinMutex` in this module for tasks, or, with the `critical-section`
+//! feature, the `CriticalSectionMutex` in this module for code that also runs
+//! in interrupt handlers:
+//!
+//! ```ignore
+//! let shared = SharedFileSystem::new(SpinMutex::new(FileSystem::new(&mut flash)?));
+//! // in each task
+//! let fs = shared.handle();
+//! let mut file = fs.open_read(path)?;
+//! let read = file.read(&mut buf)?;
+//! file.close()?;
+//! ```
+//!
+//! A `SharedFile` dropped without `close` is closed then, ignoring errors.
+//!
+//! Files opened through a handle keep their own position, so any number of
+//! tasks can read the same file at once. Opening a file for writing still
+//! fails while it is open elsewhere.
+
+#[cfg(any(test, feature = "critical-section"))]
+use core::cell::RefCell;
+use core::cell::UnsafeCell;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(any(test, feature = "critical-section"))]
+use critical_section;
+use fs::{CreateOptions, Fd, FileSystem, Metadata};
+use io::{self, Read, ReadWriteSeek, Seek, Write};
+use path::Path;
+
+/// Mutual exclusion around some data, given to `SharedFileSystem`.
+pub trait Mutex {
+    type Data;
+
+    /// Runs `f` with exclusive access to the data. Must not be called again
+    /// from within `f`.
+    fn lock<R, F: FnOnce(&mut Self::Data) -> R>(&self, f: F) -> R;
+}
+
+#[cfg(any(test, feature = "std"))]
+impl<T> Mutex for ::std::sync::Mutex<T> {
+    type Data = T;
+
+    fn lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        // a task that panicked with the lock held at worst leaves a file
+        // open, which is no reason to stop every other task
+        let mut guard = match ::std::sync::Mutex::lock(self) {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        f(&mut guard)
+    }
+}
+
+/// Mutex that busy-waits, for targets without an operating system. Not
+/// usable from interrupt handlers, which would spin forever on a lock held
+/// by the code they interrupted.
+pub struct SpinMutex<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinMutex<T> {}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(data: T) -> Self {
+        SpinMutex {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+/// Releases a `SpinMutex`, also when the closure holding it panics.
+struct SpinGuard<'m> {
+    locked: &'m AtomicBool,
+}
+
+impl<'m> Drop for SpinGuard<'m> {
+    fn drop(&mut self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<T> Mutex for SpinMutex<T> {
+    type Data = T;
+
+    fn lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            ::core::hint::spin_loop();
+        }
+        let _guard = SpinGuard {
+            locked: &self.locked,
+        };
+        // the flag gives this closure the only access to the data
+        f(unsafe { &mut *self.data.get() })
+    }
+}
+
+/// Mutex that runs the closure in a critical section, which on single core
+/// targets disables interrupts, so that interrupt handlers can share the data
+/// with tasks.
+#[cfg(any(test, feature = "critical-section"))]
+pub struct CriticalSectionMutex<T> {
+    data: critical_section::Mutex<RefCell<T>>,
+}
+
+#[cfg(any(test, feature = "critical-section"))]
+impl<T> CriticalSectionMutex<T> {
+    pub const fn new(data: T) -> Self {
+        CriticalSectionMutex {
+            data: critical_section::Mutex::new(RefCell::new(data)),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner().into_inner()
+    }
+}
+
+#[cfg(any(test, feature = "critical-section"))]
+impl<T> Mutex for CriticalSectionMutex<T> {
+    type Data = T;
+
+    fn lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        // panics if called again from within `f`, rather than handing out the
+        // data twice
+        critical_section::with(|cs| f(&mut self.data.borrow_ref_mut(cs)))
+    }
+}
+
+/// File system data a `SharedFile` can be closed with, when it is dropped.
+pub trait CloseFile {
+    fn close_file(&mut self, fd: Fd) -> io::Result<()>;
+}
+
+impl<'a, T: ReadWriteSeek + 'a> CloseFile for FileSystem<'a, T> {
+    fn close_file(&mut self, fd: Fd) -> io::Result<()> {
+        self.close(fd)
+    }
+}
+
+/// `FileSystem` that can be used through shared references, see the module
+/// documentation.
+pub struct SharedFileSystem<M> {
+    mutex: M,
+}
+
+impl<M: Mutex> SharedFileSystem<M> {
+    pub fn new(mutex: M) -> Self {
+        SharedFileSystem { mutex }
+    }
+
+    pub fn into_inner(self) -> M {
+        self.mutex
+    }
+
+    /// Returns a handle for one task to use.
+    pub fn handle(&self) -> Handle<'_, M> {
+        Handle { shared: self }
+    }
+
+    /// Runs `f` with the file system locked, for operations without a
+    /// shorthand on `Handle`.
+    pub fn lock<R, F: FnOnce(&mut M::Data) -> R>(&self, f: F) -> R {
+        self.mutex.lock(f)
+    }
+}
+
+/// Access to a `SharedFileSystem` from one task.
+pub struct Handle<'s, M: 's> {
+    shared: &'s SharedFileSystem<M>,
+}
+
+impl<'s, M> Clone for Handle<'s, M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'s, M> Copy for Handle<'s, M> {}
+
+impl<'s, 'a, T, M> Handle<'s, M>
+where
+    T: Read + Seek + 'a,
+    M: Mutex<Data = FileSystem<'a, T>>,
+{
+    pub fn metadata(&self, path: Path) -> io::Result<Metadata> {
+        self.shared.lock(|fs| fs.metadata(path))
+    }
+
+    pub fn lock<R, F: FnOnce(&mut FileSystem<'a, T>) -> R>(&self, f: F) -> R {
+        self.shared.lock(f)
+    }
+}
+
+impl<'s, 'a, T, M> Handle<'s, M>
+where
+    T: ReadWriteSeek + 'a,
+    M: Mutex<Data = FileSystem<'a, T>>,
+{
+    pub fn open_read(&self, path: Path) -> io::Result<SharedFile<'s, M>> {
+        let fd = self.shared.lock(|fs| fs.open_read(path))?;
+        Ok(SharedFile {
+            shared: self.shared,
+            fd,
+        })
+    }
+
+    pub fn create(&self, path: Path) -> io::Result<SharedFile<'s, M>> {
+        self.create_with(path, &CreateOptions::new())
+    }
+
+    pub fn create_with(
+        &self,
+        path: Path,
+        options: &CreateOptions,
+    ) -> io::Result<SharedFile<'s, M>> {
+        let fd = self.shared.lock(|fs| fs.create_with(path, options))?;
+        Ok(SharedFile {
+            shared: self.shared,
+            fd,
+        })
+    }
+
+    pub fn remove(&self, path: Path) -> io::Result<()> {
+        self.shared.lock(|fs| fs.remove(path))
+    }
+
+    pub fn flush_to_storage(&self) -> io::Result<()> {
+        self.shared.lock(|fs| fs.flush_to_storage())
+    }
+}
+
+/// File opened through a `Handle`. Reads and writes lock the file system for
+/// their duration only.
+///
+/// Dropping it closes the file and ignores errors, `close` returns them.
+pub struct SharedFile<'s, M: Mutex + 's>
+where
+    M::Data: CloseFile,
+{
+    shared: &'s SharedFileSystem<M>,
+    fd: Fd,
+}
+
+impl<'s, M: Mutex + 's> SharedFile<'s, M>
+where
+    M::Data: CloseFile,
+{
+    pub fn close(self) -> io::Result<()> {
+        let (shared, fd) = (self.shared, self.fd);
+        // holds nothing else to release
+        mem::forget(self);
+        shared.lock(|fs| fs.close_file(fd))
+    }
+}
+
+impl<'s, M: Mutex + 's> Drop for SharedFile<'s, M>
+where
+    M::Data: CloseFile,
+{
+    fn drop(&mut self) {
+        let fd = self.fd;
+        let _ = self.shared.lock(|fs| fs.close_file(fd));
+    }
+}
+
+impl<'s, 'a, T, M> SharedFile<'s, M>
+where
+    T: ReadWriteSeek + 'a,
+    M: Mutex<Data = FileSystem<'a, T>>,
+{
+    /// See `FileSystem::sync`.
+    pub fn sync(&self) -> io::Result<()> {
+        let fd = &self.fd;
+        self.shared.lock(|fs| fs.sync(fd))
+    }
+}
+
+impl<'s, 'a, T, M> Read for SharedFile<'s, M>
+where
+    T: ReadWriteSeek + 'a,
+    M: Mutex<Data = FileSystem<'a, T>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let fd = &self.fd;
+        self.shared.lock(|fs| fs.get_reader(fd)?.read(buf))
+    }
+}
+
+impl<'s, 'a, T, M> Write for SharedFile<'s, M>
+where
+    T: ReadWriteSeek + 'a,
+    M: Mutex<Data = FileSystem<'a, T>>,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let fd = &self.fd;
+        self.shared.lock(|fs| fs.get_writer(fd)?.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let fd = &self.fd;
+        self.shared.lock(|fs| fs.get_writer(fd)?.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::{Cursor, ErrorKind};
+    use std::sync;
+    use std::thread;
+    use std::vec::Vec;
+    use FS_SIZE;
+
+    fn path(name: &str) -> Path {
+        Path::from_ascii_str(name.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn concurrent_readers() {
+        let data = (0..5000).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let fs = FileSystem::new(&mut storage).unwrap();
+        let shared = SharedFileSystem::new(sync::Mutex::new(fs));
+        {
+            let mut file = shared.handle().create(path("shared")).unwrap();
+            file.write_all(&data).unwrap();
+            file.close().unwrap();
+        }
+        let barrier = sync::Barrier::new(4);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let fs = shared.handle();
+                    let mut file = fs.open_read(path("shared")).unwrap();
+                    // all four files are open at the same time
+                    barrier.wait();
+                    let mut buf = [0; 100];
+                    let mut read = Vec::new();
+                    loop {
+                        match file.read(&mut buf).unwrap() {
+                            0 => break,
+                            n => read.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    assert_eq!(read, data);
+                    barrier.wait();
+                    file.close().unwrap();
+                });
+            }
+        });
+        shared.into_inner().into_inner().unwrap().unmount().unwrap();
+    }
+
+    #[test]
+    fn writer_excludes_readers() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let shared = SharedFileSystem::new(SpinMutex::new(FileSystem::new(&mut storage).unwrap()));
+        let fs = shared.handle();
+        let mut writer = fs.create(path("file")).unwrap();
+        writer.write_all(b"contents").unwrap();
+        let err = fs.open_read(path("file")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        writer.close().unwrap();
+
+        let mut reader = fs.open_read(path("file")).unwrap();
+        assert!(fs.create(path("file")).is_err());
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"contents");
+        reader.close().unwrap();
+        assert_eq!(fs.metadata(path("file")).unwrap().len(), 8);
+        assert_eq!(fs.lock(|fs| fs.list_files().count()), 1);
+    }
+
+    #[test]
+    fn write_to_reader_is_denied() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let shared = SharedFileSystem::new(SpinMutex::new(FileSystem::new(&mut storage).unwrap()));
+        let fs = shared.handle();
+        fs.create(path("file")).unwrap().close().unwrap();
+        let mut reader = fs.open_read(path("file")).unwrap();
+        let err = reader.write(b"data").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(reader.flush().err().unwrap().kind(), ErrorKind::PermissionDenied);
+        reader.close().unwrap();
+        assert_eq!(fs.metadata(path("file")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn drop_closes_file() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let shared = SharedFileSystem::new(SpinMutex::new(FileSystem::new(&mut storage).unwrap()));
+        let fs = shared.handle();
+        {
+            let mut writer = fs.create(path("file")).unwrap();
+            writer.write_all(b"contents").unwrap();
+        }
+        let reader = fs.open_read(path("file")).unwrap();
+        drop(reader);
+        fs.create(path("file")).unwrap().close().unwrap();
+        shared.into_inner().into_inner().unmount().unwrap();
+    }
+
+    #[test]
+    fn critical_section_mutex() {
+        let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+        let fs = FileSystem::new(&mut storage).unwrap();
+        let shared = SharedFileSystem::new(CriticalSectionMutex::new(fs));
+        thread::scope(|scope| {
+            for i in 0..4 {
+                let fs = shared.handle();
+                scope.spawn(move || {
+                    let name = [b'f', b'0' + i];
+                    let mut file = fs.create(Path::from_ascii_str(&name).unwrap()).unwrap();
+                    file.write_all(&[i; 100]).unwrap();
+                });
+            }
+        });
+        assert_eq!(shared.handle().lock(|fs| fs.list_files().count()), 4);
+        shared.into_inner().into_inner().unmount().unwrap();
+    }
+
+    #[test]
+    fn spin_mutex_threads() {
+        let mutex = SpinMutex::new(0u32);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        mutex.lock(|count| *count += 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(mutex.into_inner(), 4000);
+    }
+}
@@ -0,0 +1,717 @@
+//! Copy-on-write snapshots of a whole filesystem.
+//!
+//! `SnapshotStorage` exposes the first `FS_SIZE` bytes of the storage to
+//! `FileSystem` and keeps snapshots in the area after them. Taking a snapshot
+//! only writes a small record. Afterwards, the first write to each block of
+//! `BLOCK_SIZE` bytes copies its old contents to the snapshot area, so a
+//! snapshot costs only the blocks that change later:
+//!
+//! ```ignore
+//! let mut storage = SnapshotStorage::open(flash)?;
+//! let mut fs = FileSystem::new(&mut storage)?;
+//! fs.snapshot(name)?;
+//! // update files, and if that goes wrong
+//! fs.rollback(name)?;
+//! ```
+//!
+//! The storage must hold at least `SNAPSHOT_STORAGE_SIZE` bytes. Records and
+//! block entries count as used only if their used byte is 1, so erased flash
+//! opens without snapshots; `format_snapshots` clears the tables of storage
+//! holding anything else. A block copy is recorded only after
+//! it is fully written, and rollback and deletion record what they are doing
+//! first, so that `open` finishes them if power is lost halfway.
+
+use core::cmp;
+use fs::FS_SIZE;
+use io::{self, Read, ReadWriteSeek, Seek, SeekFrom, Write};
+use path::{self, Path, MAX_PATH_LENGTH};
+
+/// Granularity of copy-on-write.
+pub const BLOCK_SIZE: usize = 4096;
+pub const MAX_SNAPSHOTS: usize = 4;
+/// Number of blocks all snapshots together can hold copies of.
+pub const MAX_SAVED_BLOCKS: usize = 512;
+
+// sequence number (4), operation (1), reserved
+const STATE_SIZE: u64 = 8;
+// name, sequence number (4), used (1)
+const RECORD_SIZE: u64 = MAX_PATH_LENGTH as u64 + 5;
+// block (4), sequence number (4), used (1)
+const ENTRY_SIZE: u64 = 9;
+const RECORDS_START: u64 = FS_SIZE + STATE_SIZE;
+const ENTRIES_START: u64 = RECORDS_START + MAX_SNAPSHOTS as u64 * RECORD_SIZE;
+const DATA_START: u64 = ENTRIES_START + MAX_SAVED_BLOCKS as u64 * ENTRY_SIZE;
+/// Size of the storage needed by `SnapshotStorage`.
+pub const SNAPSHOT_STORAGE_SIZE: u64 = DATA_START + (MAX_SAVED_BLOCKS * BLOCK_SIZE) as u64;
+
+const OP_NONE: u8 = 0;
+const OP_ROLLBACK: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+/// Clears the snapshot tables of `storage`, dropping all snapshots. Needed
+/// before the first `SnapshotStorage::open` unless the area after `FS_SIZE`
+/// is zeroed or erased.
+pub fn format_snapshots<T: ReadWriteSeek>(storage: &mut T) -> io::Result<()> {
+    storage.seek(SeekFrom::Start(FS_SIZE))?;
+    let zeros = [0; 256];
+    let mut left = DATA_START - FS_SIZE;
+    while left > 0 {
+        let len = cmp::min(left, zeros.len() as u64) as usize;
+        storage.write_all(&zeros[..len])?;
+        left -= len as u64;
+    }
+    storage.flush()
+}
+
+/// A snapshot, ordered by a sequence number that grows with every snapshot
+/// taken.
+#[derive(Debug, Copy, Clone)]
+struct Record {
+    used: bool,
+    seq: u32,
+    name: Path,
+}
+
+const UNUSED_RECORD: Record = Record {
+    used: false,
+    seq: 0,
+    name: path::EMPTY,
+};
+
+/// Old contents of `block` as of snapshot `seq`, kept in the data block with
+/// the same index as the entry.
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    used: bool,
+    block: u32,
+    seq: u32,
+}
+
+const UNUSED_ENTRY: Entry = Entry {
+    used: false,
+    block: 0,
+    seq: 0,
+};
+
+pub struct SnapshotStorage<T> {
+    inner: T,
+    pos: u64,
+    records: [Record; MAX_SNAPSHOTS],
+    entries: [Entry; MAX_SAVED_BLOCKS],
+}
+
+impl<T> SnapshotStorage<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Returns names of all snapshots, oldest first.
+    pub fn snapshots<'b>(&'b self) -> impl Iterator<Item = Path> + 'b {
+        SnapshotIterator {
+            records: &self.records,
+            last: 0,
+        }
+    }
+
+    fn find(&self, name: Path) -> Option<u32> {
+        self.records
+            .iter()
+            .find(|r| r.used && r.name == name)
+            .map(|r| r.seq)
+    }
+
+    fn is_live(&self, seq: u32) -> bool {
+        self.records.iter().any(|r| r.used && r.seq == seq)
+    }
+
+    fn latest(&self) -> Option<u32> {
+        self.records.iter().filter(|r| r.used).map(|r| r.seq).max()
+    }
+
+    fn previous(&self, seq: u32) -> Option<u32> {
+        self.records
+            .iter()
+            .filter(|r| r.used && r.seq < seq)
+            .map(|r| r.seq)
+            .max()
+    }
+
+    fn has_entry(&self, seq: u32, block: u32) -> bool {
+        self.entries
+            .iter()
+            .any(|e| e.used && e.seq == seq && e.block == block)
+    }
+}
+
+impl<T: Read + Write + Seek> SnapshotStorage<T> {
+    /// Loads the snapshot tables, finishing a rollback or deletion that was
+    /// interrupted by power loss.
+    pub fn open(mut inner: T) -> io::Result<Self> {
+        if inner.seek(SeekFrom::End(0))? < SNAPSHOT_STORAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "storage too small for snapshots",
+            ));
+        }
+        let mut storage = SnapshotStorage {
+            inner,
+            pos: 0,
+            records: [UNUSED_RECORD; MAX_SNAPSHOTS],
+            entries: [UNUSED_ENTRY; MAX_SAVED_BLOCKS],
+        };
+        let mut state = [0; 5];
+        storage.read_at(FS_SIZE, &mut state)?;
+        for i in 0..MAX_SNAPSHOTS {
+            let mut buf = [0; RECORD_SIZE as usize];
+            storage.read_at(RECORDS_START + i as u64 * RECORD_SIZE, &mut buf)?;
+            if buf[MAX_PATH_LENGTH + 4] == 1 {
+                let name = Path::from_ascii_zero_padded(&buf[..MAX_PATH_LENGTH]).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "bad snapshot name")
+                })?;
+                storage.records[i] = Record {
+                    used: true,
+                    seq: to_u32(&buf[MAX_PATH_LENGTH..(MAX_PATH_LENGTH + 4)]),
+                    name,
+                };
+            }
+        }
+        for i in 0..MAX_SAVED_BLOCKS {
+            let mut buf = [0; ENTRY_SIZE as usize];
+            storage.read_at(ENTRIES_START + i as u64 * ENTRY_SIZE, &mut buf)?;
+            storage.entries[i] = Entry {
+                used: buf[8] == 1,
+                block: to_u32(&buf[..4]),
+                seq: to_u32(&buf[4..8]),
+            };
+        }
+        let seq = to_u32(&state[..4]);
+        match state[4] {
+            OP_ROLLBACK => storage.finish_rollback(seq)?,
+            OP_DELETE => {
+                // entries whose sequence number is no snapshot's were being
+                // moved away from the deleted one when power was lost
+                for i in 0..MAX_SAVED_BLOCKS {
+                    if storage.entries[i].used && !storage.is_live(storage.entries[i].seq) {
+                        storage.entries[i].seq = seq;
+                    }
+                }
+                storage.finish_delete(seq)?;
+            }
+            _ => {}
+        }
+        Ok(storage)
+    }
+
+    /// Records the current contents of the storage as snapshot `name`.
+    pub fn snapshot(&mut self, name: Path) -> io::Result<()> {
+        if self.find(name).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "snapshot exists",
+            ));
+        }
+        let slot = match self.records.iter().position(|r| !r.used) {
+            Some(slot) => slot,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "too many snapshots")),
+        };
+        let seq = match self.latest() {
+            Some(latest) => latest.checked_add(1).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "snapshot sequence exhausted")
+            })?,
+            None => 1,
+        };
+        let record = Record {
+            used: true,
+            seq,
+            name,
+        };
+        self.write_record(slot, &record)?;
+        self.inner.flush()?;
+        self.records[slot] = record;
+        Ok(())
+    }
+
+    /// Brings the storage back to how it was when snapshot `name` was taken.
+    /// The snapshot is kept, every later one is deleted.
+    pub fn rollback(&mut self, name: Path) -> io::Result<()> {
+        let seq = match self.find(name) {
+            Some(seq) => seq,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "no snapshot")),
+        };
+        self.write_state(OP_ROLLBACK, seq)?;
+        self.finish_rollback(seq)
+    }
+
+    pub fn delete_snapshot(&mut self, name: Path) -> io::Result<()> {
+        let seq = match self.find(name) {
+            Some(seq) => seq,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "no snapshot")),
+        };
+        self.write_state(OP_DELETE, seq)?;
+        self.finish_delete(seq)
+    }
+
+    fn finish_rollback(&mut self, seq: u32) -> io::Result<()> {
+        // the old contents of a block are in the oldest copy made since the
+        // snapshot
+        for i in 0..MAX_SAVED_BLOCKS {
+            let entry = self.entries[i];
+            if !entry.used || entry.seq < seq || !self.is_live(entry.seq) {
+                continue;
+            }
+            let older = self
+                .entries
+                .iter()
+                .any(|e| e.used && e.block == entry.block && e.seq >= seq && e.seq < entry.seq);
+            if !older {
+                let start = u64::from(entry.block) * BLOCK_SIZE as u64;
+                self.copy(data_position(i), start, block_len(entry.block))?;
+            }
+        }
+        self.inner.flush()?;
+        // newest first, so that if this is cut short, the copies left are
+        // still the oldest ones of each block
+        while let Some(latest) = self.latest().filter(|&latest| latest >= seq) {
+            for i in 0..MAX_SAVED_BLOCKS {
+                if self.entries[i].used && self.entries[i].seq == latest {
+                    self.free_entry(i)?;
+                }
+            }
+            self.inner.flush()?;
+            if latest == seq {
+                break;
+            }
+            self.clear_record(latest)?;
+        }
+        self.inner.flush()?;
+        self.clear_state()
+    }
+
+    fn finish_delete(&mut self, seq: u32) -> io::Result<()> {
+        // copies of the deleted snapshot hold blocks as of the previous one,
+        // unless that one has a copy of its own
+        let previous = self.previous(seq);
+        for i in 0..MAX_SAVED_BLOCKS {
+            let entry = self.entries[i];
+            if !entry.used || entry.seq != seq {
+                continue;
+            }
+            match previous {
+                Some(previous) if !self.has_entry(previous, entry.block) => {
+                    let pos = ENTRIES_START + i as u64 * ENTRY_SIZE + 4;
+                    self.write_at(pos, &previous.to_le_bytes())?;
+                    self.entries[i].seq = previous;
+                }
+                _ => self.free_entry(i)?,
+            }
+        }
+        self.inner.flush()?;
+        self.clear_record(seq)?;
+        self.inner.flush()?;
+        self.clear_state()
+    }
+
+    /// Copies blocks touched by a write of `len` bytes at the current
+    /// position into the newest snapshot, unless they are copied already.
+    fn save_blocks(&mut self, len: usize) -> io::Result<()> {
+        let seq = match self.latest() {
+            Some(seq) => seq,
+            None => return Ok(()),
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        let first = self.pos / BLOCK_SIZE as u64;
+        let last = (self.pos + len as u64 - 1) / BLOCK_SIZE as u64;
+        for block in first..=last {
+            let block = block as u32;
+            if self.has_entry(seq, block) {
+                continue;
+            }
+            let index = match self.entries.iter().position(|e| !e.used) {
+                Some(index) => index,
+                None => return Err(io::Error::new(io::ErrorKind::Other, "snapshot area full")),
+            };
+            let start = u64::from(block) * BLOCK_SIZE as u64;
+            self.copy(start, data_position(index), block_len(block))?;
+            self.inner.flush()?;
+            // the used flag is written last, so a torn entry is never used
+            let mut buf = [0; ENTRY_SIZE as usize];
+            buf[..4].copy_from_slice(&block.to_le_bytes());
+            buf[4..8].copy_from_slice(&seq.to_le_bytes());
+            buf[8] = 1;
+            self.write_at(ENTRIES_START + index as u64 * ENTRY_SIZE, &buf)?;
+            self.inner.flush()?;
+            self.entries[index] = Entry {
+                used: true,
+                block,
+                seq,
+            };
+        }
+        Ok(())
+    }
+
+    fn free_entry(&mut self, index: usize) -> io::Result<()> {
+        self.write_at(ENTRIES_START + index as u64 * ENTRY_SIZE + 8, &[0])?;
+        self.entries[index].used = false;
+        Ok(())
+    }
+
+    fn write_record(&mut self, slot: usize, record: &Record) -> io::Result<()> {
+        // the used flag is written last, so a torn record is never used
+        let mut buf = [0; RECORD_SIZE as usize];
+        let name = record.name.as_slice();
+        buf[..name.len()].copy_from_slice(name);
+        buf[MAX_PATH_LENGTH..(MAX_PATH_LENGTH + 4)].copy_from_slice(&record.seq.to_le_bytes());
+        buf[MAX_PATH_LENGTH + 4] = record.used as u8;
+        self.write_at(RECORDS_START + slot as u64 * RECORD_SIZE, &buf)
+    }
+
+    fn clear_record(&mut self, seq: u32) -> io::Result<()> {
+        if let Some(slot) = self.records.iter().position(|r| r.used && r.seq == seq) {
+            let pos = RECORDS_START + slot as u64 * RECORD_SIZE + MAX_PATH_LENGTH as u64 + 4;
+            self.write_at(pos, &[0])?;
+            self.records[slot] = UNUSED_RECORD;
+        }
+        Ok(())
+    }
+
+    fn write_state(&mut self, op: u8, seq: u32) -> io::Result<()> {
+        let mut buf = [0; 5];
+        buf[..4].copy_from_slice(&seq.to_le_bytes());
+        buf[4] = op;
+        self.write_at(FS_SIZE, &buf)?;
+        self.inner.flush()
+    }
+
+    fn clear_state(&mut self) -> io::Result<()> {
+        self.write_at(FS_SIZE + 4, &[OP_NONE])?;
+        self.inner.flush()
+    }
+
+    fn copy(&mut self, from: u64, to: u64, len: usize) -> io::Result<()> {
+        let mut buf = [0; 256];
+        let mut done = 0;
+        while done < len {
+            let n = cmp::min(buf.len(), len - done);
+            self.read_at(from + done as u64, &mut buf[..n])?;
+            self.write_at(to + done as u64, &buf[..n])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(pos))?;
+        self.inner.read_exact(buf)
+    }
+
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(pos))?;
+        self.inner.write_all(buf)
+    }
+}
+
+impl<T: Read + Seek> Read for SnapshotStorage<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // the snapshot area is not part of the filesystem
+        let len = cmp::min(buf.len() as u64, FS_SIZE.saturating_sub(self.pos)) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        let read = self.inner.read(&mut buf[..len])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Read + Write + Seek> Write for SnapshotStorage<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len() as u64, FS_SIZE.saturating_sub(self.pos)) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        self.save_blocks(len)?;
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        let written = self.inner.write(&buf[..len])?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T> Seek for SnapshotStorage<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (FS_SIZE, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        let new_pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+struct SnapshotIterator<'a> {
+    records: &'a [Record],
+    last: u32,
+}
+
+impl<'a> Iterator for SnapshotIterator<'a> {
+    type Item = Path;
+
+    fn next(&mut self) -> Option<Path> {
+        let last = self.last;
+        let next = self
+            .records
+            .iter()
+            .filter(|r| r.used && r.seq > last)
+            .min_by_key(|r| r.seq)?;
+        self.last = next.seq;
+        Some(next.name)
+    }
+}
+
+fn data_position(index: usize) -> u64 {
+    DATA_START + (index * BLOCK_SIZE) as u64
+}
+
+/// Bytes of the filesystem in `block`, less than `BLOCK_SIZE` only for the
+/// last block.
+fn block_len(block: u32) -> usize {
+    let start = u64::from(block) * BLOCK_SIZE as u64;
+    cmp::min(BLOCK_SIZE as u64, FS_SIZE - start) as usize
+}
+
+fn to_u32(buf: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(buf);
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::Cursor;
+    use std::vec::Vec;
+    use testing::fault::{Fault, FaultyStorage};
+    use FileSystem;
+
+    fn path(name: &[u8]) -> Path {
+        Path::from_ascii_str(name).unwrap()
+    }
+
+    fn write_file<T: io::ReadWriteSeek>(fs: &mut FileSystem<T>, name: &[u8], data: &[u8]) {
+        let fd = fs.create(path(name)).expect("failed to create");
+        fs.get_writer(&fd)
+            .expect("failed to get writer")
+            .write_all(data)
+            .expect("failed to write");
+        fs.close(fd).expect("failed to close");
+    }
+
+    fn read_file<T: io::ReadWriteSeek>(fs: &mut FileSystem<T>, name: &[u8]) -> Option<Vec<u8>> {
+        let fd = fs.open_read(path(name)).ok()?;
+        let mut data = Vec::new();
+        fs.get_reader(&fd)
+            .expect("failed to get reader")
+            .read_to_end(&mut data)
+            .expect("failed to read");
+        fs.close(fd).expect("failed to close");
+        Some(data)
+    }
+
+    fn empty_storage() -> SnapshotStorage<Cursor<Vec<u8>>> {
+        SnapshotStorage::open(Cursor::new(vec![0; SNAPSHOT_STORAGE_SIZE as usize]))
+            .expect("failed to open")
+    }
+
+    #[test]
+    fn rollback() {
+        let mut storage = empty_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        write_file(&mut fs, b"fw.bin", b"version 1");
+        fs.snapshot(path(b"v1")).expect("failed to snapshot");
+        write_file(&mut fs, b"fw.bin", b"version 2, longer");
+        write_file(&mut fs, b"new.cfg", b"config");
+        fs.rollback(path(b"v1")).expect("failed to roll back");
+        assert_eq!(read_file(&mut fs, b"fw.bin").unwrap(), b"version 1");
+        assert_eq!(read_file(&mut fs, b"new.cfg"), None);
+        assert_eq!(fs.list_snapshots().collect::<Vec<_>>(), [path(b"v1")]);
+
+        // the snapshot is kept and can be rolled back to again
+        write_file(&mut fs, b"fw.bin", b"version 3");
+        fs.rollback(path(b"v1")).expect("failed to roll back");
+        assert_eq!(read_file(&mut fs, b"fw.bin").unwrap(), b"version 1");
+    }
+
+    #[test]
+    fn snapshot_costs_changed_blocks() {
+        let mut storage = empty_storage();
+        {
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            write_file(&mut fs, b"big", &[7; 3 * BLOCK_SIZE]);
+            fs.snapshot(path(b"before")).expect("failed to snapshot");
+            write_file(&mut fs, b"small", b"x");
+            fs.flush_to_storage().expect("failed to flush");
+        }
+        // a header and the superblock, both in the first block
+        assert_eq!(storage.entries.iter().filter(|e| e.used).count(), 1);
+    }
+
+    #[test]
+    fn delete_keeps_older_snapshots() {
+        let mut storage = empty_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        write_file(&mut fs, b"a", b"1");
+        fs.snapshot(path(b"s1")).expect("failed to snapshot");
+        write_file(&mut fs, b"a", b"2");
+        fs.snapshot(path(b"s2")).expect("failed to snapshot");
+        write_file(&mut fs, b"a", b"3");
+        fs.snapshot(path(b"s3")).expect("failed to snapshot");
+        write_file(&mut fs, b"a", b"4");
+        assert!(fs.snapshot(path(b"s3")).is_err(), "names should be unique");
+
+        fs.delete_snapshot(path(b"s2")).expect("failed to delete");
+        assert_eq!(
+            fs.list_snapshots().collect::<Vec<_>>(),
+            [path(b"s1"), path(b"s3")]
+        );
+        fs.rollback(path(b"s3")).expect("failed to roll back");
+        assert_eq!(read_file(&mut fs, b"a").unwrap(), b"3");
+        fs.delete_snapshot(path(b"s3")).expect("failed to delete");
+        fs.rollback(path(b"s1")).expect("failed to roll back");
+        assert_eq!(read_file(&mut fs, b"a").unwrap(), b"1");
+        assert!(fs.rollback(path(b"s2")).is_err());
+    }
+
+    #[test]
+    fn rollback_with_open_files_fails() {
+        let mut storage = empty_storage();
+        let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+        fs.snapshot(path(b"empty")).expect("failed to snapshot");
+        let fd = fs.create(path(b"a")).expect("failed to create");
+        assert!(fs.rollback(path(b"empty")).is_err());
+        fs.close(fd).expect("failed to close");
+        fs.rollback(path(b"empty")).expect("failed to roll back");
+        assert_eq!(fs.list_files().count(), 0);
+    }
+
+    fn interrupted(
+        image: &[u8],
+        fault: Option<Fault>,
+        op: fn(&mut FileSystem<SnapshotStorage<&mut FaultyStorage>>),
+    ) -> (u64, Vec<u8>) {
+        let mut faulty = FaultyStorage::new(image.to_vec());
+        if let Some(fault) = fault {
+            faulty = faulty.with_fault(fault);
+        }
+        {
+            let mut storage = SnapshotStorage::open(&mut faulty).expect("failed to open");
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            op(&mut fs);
+        }
+        (faulty.bytes_written(), faulty.into_image().into_inner())
+    }
+
+    fn check_after_power_loss(
+        op: fn(&mut FileSystem<SnapshotStorage<&mut FaultyStorage>>),
+        expected: &[u8],
+    ) {
+        let image = {
+            let mut storage = empty_storage();
+            {
+                let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+                write_file(&mut fs, b"a", b"old");
+                fs.snapshot(path(b"s1")).expect("failed to snapshot");
+                write_file(&mut fs, b"a", b"middle");
+                fs.snapshot(path(b"s2")).expect("failed to snapshot");
+                write_file(&mut fs, b"a", b"newest");
+                fs.flush_to_storage().expect("failed to flush");
+            }
+            storage.into_inner().into_inner()
+        };
+        let (total, _) = interrupted(&image, None, op);
+        // every byte of the bookkeeping at the end, a sample of the block copies
+        for cut in (0..total).filter(|cut| cut % 97 == 0 || total - cut < 64) {
+            let fault = Fault::PowerLoss { after_bytes: cut };
+            let (_, after) = interrupted(&image, Some(fault), op);
+            let mut storage = SnapshotStorage::open(Cursor::new(after)).expect("failed to open");
+            let mut fs = FileSystem::new(&mut storage).expect("failed to mount");
+            if fs.list_snapshots().any(|name| name == path(b"s1")) {
+                fs.rollback(path(b"s1")).expect("failed to roll back");
+            }
+            let data = read_file(&mut fs, b"a");
+            assert_eq!(
+                data.as_ref().map(|d| &d[..]),
+                Some(expected),
+                "cut at {}",
+                cut
+            );
+        }
+    }
+
+    #[test]
+    fn rollback_survives_power_loss() {
+        check_after_power_loss(
+            |fs| fs.rollback(path(b"s1")).expect("failed to roll back"),
+            b"old",
+        );
+    }
+
+    #[test]
+    fn delete_survives_power_loss() {
+        check_after_power_loss(
+            |fs| fs.delete_snapshot(path(b"s2")).expect("failed to delete"),
+            b"old",
+        );
+    }
+
+    #[test]
+    fn erased_tables() {
+        let mut image = vec![0xff; SNAPSHOT_STORAGE_SIZE as usize];
+        {
+            let storage =
+                SnapshotStorage::open(Cursor::new(&mut image[..])).expect("failed to open");
+            assert!(storage.records.iter().all(|r| !r.used));
+            assert!(storage.entries.iter().all(|e| !e.used));
+        }
+
+        // leftover data that happens to look like a snapshot
+        let used = (RECORDS_START + MAX_PATH_LENGTH as u64 + 4) as usize;
+        image[used] = 1;
+        {
+            let storage =
+                SnapshotStorage::open(Cursor::new(&mut image[..])).expect("failed to open");
+            assert!(storage.records[0].used);
+        }
+
+        format_snapshots(&mut Cursor::new(&mut image[..])).expect("failed to format");
+        let storage = SnapshotStorage::open(Cursor::new(&mut image[..])).expect("failed to open");
+        assert!(storage.records.iter().all(|r| !r.used));
+    }
+}
@@ -0,0 +1,92 @@
+//! Running async code in tests.
+
+use core::future::Future;
+use core::pin::pin;
+use core::task::{ready, Context, Poll, Waker};
+use io::{self, AsyncRead, AsyncSeek, AsyncWrite, Read, Seek, SeekFrom, Write};
+
+/// Polls `future` until it completes. Everything in this crate wakes the task
+/// before returning `Poll::Pending`, so busy polling is all it takes.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// Turns blocking storage into async storage whose every operation is
+/// pending on the first poll, and which transfers at most 7 bytes at a time,
+/// so that callers have to get resuming right.
+pub struct Stuttering<T> {
+    inner: T,
+    ready: bool,
+    polls: usize,
+}
+
+const MAX_TRANSFER: usize = 7;
+
+impl<T> Stuttering<T> {
+    pub fn new(inner: T) -> Self {
+        Stuttering {
+            inner,
+            ready: false,
+            polls: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Number of times an operation was polled.
+    pub fn polls(&self) -> usize {
+        self.polls
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<()> {
+        self.polls += 1;
+        if self.ready {
+            self.ready = false;
+            Poll::Ready(())
+        } else {
+            self.ready = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+impl<T: Read> AsyncRead for Stuttering<T> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_ready(cx));
+        let len = buf.len().min(MAX_TRANSFER);
+        Poll::Ready(self.inner.read(&mut buf[..len]))
+    }
+}
+
+impl<T: Write> AsyncWrite for Stuttering<T> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_ready(cx));
+        let len = buf.len().min(MAX_TRANSFER);
+        Poll::Ready(self.inner.write(&buf[..len]))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.poll_ready(cx));
+        Poll::Ready(self.inner.flush())
+    }
+}
+
+impl<T: Seek> AsyncSeek for Stuttering<T> {
+    fn poll_seek(&mut self, cx: &mut Context, pos: SeekFrom) -> Poll<io::Result<u64>> {
+        ready!(self.poll_ready(cx));
+        Poll::Ready(self.inner.seek(pos))
+    }
+}
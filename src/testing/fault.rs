@@ -0,0 +1,199 @@
+//! Fault-injecting storage for crash safety tests.
+//!
+//! `FaultyStorage` wraps an in-memory image and misbehaves at scripted
+//! points, so tests can check what the filesystem leaves behind when the
+//! device under it fails.
+
+use io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::vec::Vec;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Power is cut once `after_bytes` bytes have been written in total. The
+    /// write crossing that point lands only partially and every later write
+    /// is silently lost, while still being reported as successful.
+    PowerLoss { after_bytes: u64 },
+    /// The write crossing `after_bytes` written bytes only writes up to that
+    /// point and reports a short write. Later writes go through.
+    ShortWrite { after_bytes: u64 },
+    /// The `write`-th call to `write` (counting from 0) fails.
+    WriteError { write: usize },
+    /// The `read`-th call to `read` (counting from 0) fails.
+    ReadError { read: usize },
+    /// Bit `bit` of the byte at `offset` reads back flipped.
+    FlipBit { offset: u64, bit: u8 },
+}
+
+pub struct FaultyStorage {
+    inner: Cursor<Vec<u8>>,
+    faults: Vec<Fault>,
+    bytes_written: u64,
+    writes: usize,
+    reads: usize,
+}
+
+impl FaultyStorage {
+    pub fn new(image: Vec<u8>) -> Self {
+        FaultyStorage {
+            inner: Cursor::new(image),
+            faults: Vec::new(),
+            bytes_written: 0,
+            writes: 0,
+            reads: 0,
+        }
+    }
+
+    pub fn with_fault(mut self, fault: Fault) -> Self {
+        self.faults.push(fault);
+        self
+    }
+
+    /// Total number of bytes the user attempted to write, including the ones
+    /// that were lost.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Number of calls to `read` so far.
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    /// Returns the image as it would be seen after a reboot.
+    pub fn into_image(self) -> Cursor<Vec<u8>> {
+        let mut image = self.inner;
+        image.set_position(0);
+        image
+    }
+
+    fn power_lost_at(&self) -> Option<u64> {
+        self.faults
+            .iter()
+            .filter_map(|fault| match *fault {
+                Fault::PowerLoss { after_bytes } => Some(after_bytes),
+                _ => None,
+            })
+            .min()
+    }
+
+    fn short_write_at(&self, len: usize) -> Option<u64> {
+        let start = self.bytes_written;
+        let end = start + len as u64;
+        self.faults
+            .iter()
+            .filter_map(|fault| match *fault {
+                Fault::ShortWrite { after_bytes } if start < after_bytes && after_bytes < end => {
+                    Some(after_bytes)
+                }
+                _ => None,
+            })
+            .min()
+    }
+}
+
+impl Read for FaultyStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let call = self.reads;
+        self.reads += 1;
+        if self.faults.contains(&Fault::ReadError { read: call }) {
+            return Err(io::Error::new(io::ErrorKind::Other, "injected read error"));
+        }
+        let start = self.inner.position();
+        let read = self.inner.read(buf)?;
+        for fault in &self.faults {
+            if let Fault::FlipBit { offset, bit } = *fault {
+                if start <= offset && offset < start + read as u64 {
+                    buf[(offset - start) as usize] ^= 1 << bit;
+                }
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Write for FaultyStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let call = self.writes;
+        self.writes += 1;
+        if self.faults.contains(&Fault::WriteError { write: call }) {
+            return Err(io::Error::new(io::ErrorKind::Other, "injected write error"));
+        }
+        let len = match self.short_write_at(buf.len()) {
+            Some(at) => (at - self.bytes_written) as usize,
+            None => buf.len(),
+        };
+        let persisted = match self.power_lost_at() {
+            Some(at) => at.saturating_sub(self.bytes_written).min(len as u64) as usize,
+            None => len,
+        };
+        let start = self.inner.position();
+        self.inner.write_all(&buf[..persisted])?;
+        self.inner.set_position(start + len as u64);
+        self.bytes_written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FaultyStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> FaultyStorage {
+        FaultyStorage::new(vec![0; 16])
+    }
+
+    #[test]
+    fn power_loss_drops_later_writes() {
+        let mut storage = storage().with_fault(Fault::PowerLoss { after_bytes: 3 });
+        storage.write_all(&[1, 2]).unwrap();
+        storage.write_all(&[3, 4]).unwrap();
+        storage.write_all(&[5]).unwrap();
+        assert_eq!(storage.bytes_written(), 5);
+        let image = storage.into_image().into_inner();
+        assert_eq!(&image[..6], &[1, 2, 3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn short_write_cuts_one_write() {
+        let mut storage = storage().with_fault(Fault::ShortWrite { after_bytes: 3 });
+        assert_eq!(storage.write(&[1, 2]).unwrap(), 2);
+        assert_eq!(storage.write(&[3, 4]).unwrap(), 1);
+        assert_eq!(storage.write(&[5]).unwrap(), 1);
+        let image = storage.into_image().into_inner();
+        assert_eq!(&image[..5], &[1, 2, 3, 5, 0]);
+    }
+
+    #[test]
+    fn scripted_errors() {
+        let mut storage = storage()
+            .with_fault(Fault::WriteError { write: 1 })
+            .with_fault(Fault::ReadError { read: 0 });
+        assert!(storage.write(&[1]).is_ok());
+        assert!(storage.write(&[2]).is_err());
+        storage.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0; 2];
+        assert!(storage.read(&mut buf).is_err());
+        assert_eq!(storage.read(&mut buf).unwrap(), 2);
+        assert_eq!(buf, [1, 0]);
+    }
+
+    #[test]
+    fn flipped_bit() {
+        let mut storage = storage().with_fault(Fault::FlipBit { offset: 2, bit: 7 });
+        storage.write_all(&[1, 2, 3, 4]).unwrap();
+        storage.seek(SeekFrom::Start(1)).unwrap();
+        let mut buf = [0; 3];
+        storage.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [2, 0x83, 4]);
+    }
+}
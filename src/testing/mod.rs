@@ -0,0 +1,6 @@
+//! Helpers shared by tests that exercise the whole filesystem.
+
+pub mod asynch;
+pub mod fault;
+mod model;
+mod power_loss;
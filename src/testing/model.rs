@@ -0,0 +1,378 @@
+//! Model-based tests: random sequences of operations are run against the
+//! real filesystem and against a trivial in-memory model, and every result
+//! is compared.
+
+use cache::{BlockCache, CachePolicy};
+use fs::{FS_SIZE, MAX_DESCRIPTORS};
+use io::{Cursor, Read, ReadWriteSeek, Write};
+use std::collections::HashMap;
+use std::vec::Vec;
+use {format_storage, Fd, FileSystem, Path};
+
+const NAMES: &[&[u8]] = &[b"a", b"b", b"config.json", b"log.txt", b""];
+const MAX_OPEN: usize = 20;
+
+/// A small xorshift generator, so that failures can be reproduced from the
+/// seed alone.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Create(Path),
+    OpenRead(Path),
+    /// Operations on descriptors pick one of the open ones by index.
+    Write(usize, Vec<u8>),
+    Read(usize, usize),
+    Close(usize),
+    Remove(Path),
+    Flush,
+    Remount,
+    Format,
+}
+
+fn gen_op(rng: &mut Rng) -> Op {
+    let name = Path::from_ascii_str(NAMES[rng.below(NAMES.len())]).unwrap();
+    match rng.below(21) {
+        0..=3 => Op::Create(name),
+        4..=7 => Op::OpenRead(name),
+        8..=11 => {
+            let len = rng.below(40);
+            let data = (0..len).map(|_| rng.next() as u8).collect();
+            Op::Write(rng.below(MAX_OPEN), data)
+        }
+        12..=14 => Op::Read(rng.below(MAX_OPEN), rng.below(40)),
+        15..=16 => Op::Close(rng.below(MAX_OPEN)),
+        17 => Op::Flush,
+        18 => Op::Remount,
+        19 => Op::Remove(name),
+        _ => Op::Format,
+    }
+}
+
+struct ModelFile {
+    /// Bytes currently in the file's data area, possibly longer than the file.
+    /// `None` for bytes that can't be known, see `Model::ghosts`.
+    data: Vec<Option<u8>>,
+    len: usize,
+    /// Length stored in the on-disk header, if it was ever flushed.
+    flushed: Option<usize>,
+    readers: usize,
+    writing: bool,
+}
+
+struct ModelFd {
+    name: Path,
+    pos: usize,
+    writing: bool,
+}
+
+#[derive(Default)]
+struct Model {
+    files: HashMap<Vec<u8>, ModelFile>,
+    open: Vec<ModelFd>,
+    /// Lengths of files removed since the last flush that were on storage
+    /// before. They come back on remount, but their data may have been
+    /// overwritten by files created since.
+    ghosts: HashMap<Vec<u8>, usize>,
+}
+
+impl Model {
+    fn create(&mut self, name: Path) -> bool {
+        if self.open.len() >= MAX_DESCRIPTORS {
+            return false;
+        }
+        let file = self
+            .files
+            .entry(name.as_slice().to_vec())
+            .or_insert(ModelFile {
+                data: Vec::new(),
+                len: 0,
+                flushed: None,
+                readers: 0,
+                writing: false,
+            });
+        if file.writing || file.readers > 0 {
+            return false;
+        }
+        file.writing = true;
+        file.len = 0;
+        self.open.push(ModelFd {
+            name,
+            pos: 0,
+            writing: true,
+        });
+        true
+    }
+
+    fn open_read(&mut self, name: Path) -> bool {
+        if self.open.len() >= MAX_DESCRIPTORS {
+            return false;
+        }
+        match self.files.get_mut(name.as_slice()) {
+            Some(ref mut file) if !file.writing => {
+                file.readers += 1;
+                self.open.push(ModelFd {
+                    name,
+                    pos: 0,
+                    writing: false,
+                });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn remove(&mut self, name: Path) -> bool {
+        match self.files.get(name.as_slice()) {
+            Some(file) if !file.writing && file.readers == 0 => {}
+            _ => return false,
+        }
+        let file = self.files.remove(name.as_slice()).unwrap();
+        if let Some(len) = file.flushed {
+            self.ghosts.insert(name.as_slice().to_vec(), len);
+        }
+        true
+    }
+
+    fn write(&mut self, fd: usize, buf: &[u8]) {
+        let desc = &mut self.open[fd];
+        let file = self.files.get_mut(desc.name.as_slice()).unwrap();
+        for &byte in buf {
+            if desc.pos < file.data.len() {
+                file.data[desc.pos] = Some(byte);
+            } else {
+                file.data.push(Some(byte));
+            }
+            desc.pos += 1;
+        }
+        file.len = desc.pos;
+    }
+
+    /// Reads like the filesystem would, returning `None` for bytes that
+    /// can't be known.
+    fn read(&mut self, fd: usize, len: usize) -> Vec<Option<u8>> {
+        let desc = &mut self.open[fd];
+        let file = &self.files[desc.name.as_slice()];
+        let end = ::core::cmp::min(desc.pos + len, file.len);
+        let data = file.data[desc.pos..end].to_vec();
+        desc.pos = end;
+        data
+    }
+
+    fn close(&mut self, fd: usize) {
+        let desc = self.open.remove(fd);
+        let file = self.files.get_mut(desc.name.as_slice()).unwrap();
+        if desc.writing {
+            file.writing = false;
+        } else {
+            file.readers -= 1;
+        }
+    }
+
+    fn flush(&mut self) {
+        for file in self.files.values_mut() {
+            file.flushed = Some(file.len);
+        }
+        self.ghosts.clear();
+    }
+
+    fn remount(&mut self) {
+        self.open.clear();
+        self.files.retain(|_, file| file.flushed.is_some());
+        for file in self.files.values_mut() {
+            file.len = file.flushed.unwrap();
+            file.readers = 0;
+            file.writing = false;
+        }
+        for (name, len) in self.ghosts.drain() {
+            self.files.insert(
+                name,
+                ModelFile {
+                    data: vec![None; len],
+                    len,
+                    flushed: Some(len),
+                    readers: 0,
+                    writing: false,
+                },
+            );
+        }
+    }
+
+    fn format(&mut self) {
+        self.open.clear();
+        self.files.clear();
+        self.ghosts.clear();
+    }
+
+    fn list(&self) -> Vec<Vec<u8>> {
+        let mut names = self.files.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+}
+
+/// Whether data read from the filesystem is what the model expects.
+fn same_data(expected: &[Option<u8>], actual: &[u8]) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .all(|(expected, actual)| expected.is_none_or(|byte| byte == *actual))
+}
+
+fn list<T: ::io::ReadWriteSeek>(fs: &mut FileSystem<T>) -> Vec<Vec<u8>> {
+    let mut names = fs
+        .list_files()
+        .map(|p| p.as_slice().to_vec())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+fn read<T: ::io::ReadWriteSeek>(fs: &mut FileSystem<T>, fd: &Fd, len: usize) -> Vec<u8> {
+    let mut reader = fs.get_reader(fd).expect("failed to get reader");
+    let mut buf = vec![0; len];
+    let mut total = 0;
+    while total < len {
+        let read = reader.read(&mut buf[total..]).expect("failed to read");
+        if read == 0 {
+            break;
+        }
+        total += read;
+    }
+    buf.truncate(total);
+    buf
+}
+
+fn run_case(seed: u64, steps: usize) {
+    let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+    run_case_on(seed, steps, &mut storage);
+}
+
+/// Same as `run_case`, through a small write-back cache that has to evict
+/// all the time.
+fn run_case_cached(seed: u64, steps: usize) {
+    let mut storage = Cursor::new(vec![0; FS_SIZE as usize]);
+    let mut buf = [0; 4 * 64];
+    let mut cache = BlockCache::new(&mut storage, &mut buf, 64, CachePolicy::WriteBack);
+    run_case_on(seed, steps, &mut cache);
+}
+
+fn run_case_on<T: ReadWriteSeek>(seed: u64, steps: usize, storage: &mut T) {
+    let mut rng = Rng(seed);
+    let all_ops = (0..steps).map(|_| gen_op(&mut rng)).collect::<Vec<_>>();
+    let mut model = Model::default();
+    let mut ops = all_ops.iter().enumerate();
+    let fail = |step: usize, what: &str| -> ! {
+        panic!("seed {}, step {} ({:?}): {}", seed, step, all_ops[step], what);
+    };
+    'mount: loop {
+        let mut fs = FileSystem::new(&mut *storage).expect("failed to mount");
+        let mut fds = Vec::new();
+        for (step, op) in ops.by_ref() {
+            match *op {
+                Op::Create(name) => {
+                    let expected = model.create(name);
+                    match fs.create(name) {
+                        Ok(fd) => fds.push(fd),
+                        Err(_) if !expected => {}
+                        Err(_) => fail(step, "create failed"),
+                    }
+                    if fds.len() != model.open.len() {
+                        fail(step, "create succeeded");
+                    }
+                }
+                Op::OpenRead(name) => {
+                    let expected = model.open_read(name);
+                    match fs.open_read(name) {
+                        Ok(fd) => fds.push(fd),
+                        Err(_) if !expected => {}
+                        Err(_) => fail(step, "open failed"),
+                    }
+                    if fds.len() != model.open.len() {
+                        fail(step, "open succeeded");
+                    }
+                }
+                Op::Write(fd, ref data) => {
+                    if fd >= fds.len() || !model.open[fd].writing {
+                        continue;
+                    }
+                    model.write(fd, data);
+                    let mut writer = fs.get_writer(&fds[fd]).expect("failed to get writer");
+                    writer.write_all(data).expect("failed to write");
+                }
+                Op::Read(fd, len) => {
+                    if fd >= fds.len() || model.open[fd].writing {
+                        continue;
+                    }
+                    if !same_data(&model.read(fd, len), &read(&mut fs, &fds[fd], len)) {
+                        fail(step, "read different data");
+                    }
+                }
+                Op::Close(fd) => {
+                    if fd >= fds.len() {
+                        continue;
+                    }
+                    model.close(fd);
+                    fs.close(fds.remove(fd)).expect("failed to close");
+                }
+                Op::Remove(name) => {
+                    if model.remove(name) != fs.remove(name).is_ok() {
+                        fail(step, "remove result differs");
+                    }
+                }
+                Op::Flush => {
+                    model.flush();
+                    fs.flush_to_storage().expect("failed to flush");
+                }
+                Op::Remount => {
+                    model.remount();
+                    continue 'mount;
+                }
+                Op::Format => {
+                    model.format();
+                    format_storage(storage, FS_SIZE).expect("failed to format");
+                    continue 'mount;
+                }
+            }
+            if list(&mut fs) != model.list() {
+                fail(step, "listed different files");
+            }
+        }
+        break;
+    }
+}
+
+#[test]
+fn random_operations() {
+    for seed in 1..200 {
+        run_case(seed, 200);
+    }
+}
+
+#[test]
+fn random_operations_cached() {
+    for seed in 1..200 {
+        run_case_cached(seed, 200);
+    }
+}
+
+#[test]
+fn long_random_operations() {
+    for seed in 1000..1005 {
+        run_case(seed, 5000);
+    }
+}